@@ -11,7 +11,8 @@ struct Opts {
     q: f32,
     bandwidth: f32,
     gain: f32,
-    shelf_slope: f32
+    shelf_slope: f32,
+    sample_rate: f32
 }
 
 fn parse_args() -> Opts {
@@ -53,6 +54,12 @@ fn parse_args() -> Opts {
              .takes_value(true)
              .default_value("1.0")
              .about("The shelf slope (in dB/octave)"))
+        .arg(Arg::new("sample-rate")
+             .short('r')
+             .long("sample-rate")
+             .takes_value(true)
+             .default_value("44100.0")
+             .about("The sample rate (in Hz)"))
         .get_matches();
 
     Opts {
@@ -61,7 +68,8 @@ fn parse_args() -> Opts {
         q: matches.value_of_t_or_exit("q"),
         bandwidth: matches.value_of_t_or_exit("bandwidth"),
         gain: matches.value_of_t_or_exit("gain"),
-        shelf_slope: matches.value_of_t_or_exit("shelf-slope")
+        shelf_slope: matches.value_of_t_or_exit("shelf-slope"),
+        sample_rate: matches.value_of_t_or_exit("sample-rate")
     }
 }
 
@@ -93,25 +101,25 @@ fn main() {
     let filter: Box<dyn TransferFunction> = match opts.filter.as_str() {
         // One-pole filters
         "onepole_bypass" => Box::new(OnePole::bypass()),
-        "highpass_6db" => Box::new(OnePole::high_pass(opts.frequency)),
-        "allpass_6db" => Box::new(OnePole::all_pass(opts.frequency)),
+        "highpass_6db" => Box::new(OnePole::high_pass(opts.frequency, opts.sample_rate)),
+        "allpass_6db" => Box::new(OnePole::all_pass(opts.frequency, opts.sample_rate)),
 
         // BiQuad filters
         "biquad_bypass" => Box::new(BiQuad::bypass()),
-        "lowpass_6db_butterworth" => Box::new(BiQuad::lowpass_6db_butterworth(opts.frequency)),
-        "highpass_6db_butterworth" => Box::new(BiQuad::highpass_6db_butterworth(opts.frequency)),
-        "lowpass_12db" => Box::new(BiQuad::lowpass_12db(opts.frequency, opts.q)),
-        "highpass_12db" => Box::new(BiQuad::highpass_12db(opts.frequency, opts.q)),
-        "bandpass_constant_skirt_gain" => Box::new(BiQuad::bandpass_constant_skirt_gain(opts.frequency, opts.bandwidth)),
-        "bandpass_constant_peak_gain" => Box::new(BiQuad::bandpass_constant_peak_gain(opts.frequency, opts.bandwidth)),
-        "notch" => Box::new(BiQuad::notch(opts.frequency, opts.bandwidth)),
-        "allpass" => Box::new(BiQuad::allpass(opts.frequency, opts.q)),
-        "peaking_eq" => Box::new(BiQuad::peaking_eq(opts.frequency, opts.gain, opts.bandwidth)),
-        "low_shelf" => Box::new(BiQuad::low_shelf(opts.frequency, opts.gain, opts.shelf_slope)),
-        "high_shelf" => Box::new(BiQuad::high_shelf(opts.frequency, opts.gain, opts.shelf_slope)),
-        "presence_moorer" => Box::new(BiQuad::presence_moorer(opts.frequency, opts.bandwidth, opts.gain)),
-        "low_shelving_moorer" => Box::new(BiQuad::shelving_moorer(opts.frequency, opts.gain, opts.shelf_slope, false)),
-        "high_shelving_moorer" => Box::new(BiQuad::shelving_moorer(opts.frequency, opts.gain, opts.shelf_slope, true)),
+        "lowpass_6db_butterworth" => Box::new(BiQuad::lowpass_6db_butterworth(opts.frequency, opts.sample_rate)),
+        "highpass_6db_butterworth" => Box::new(BiQuad::highpass_6db_butterworth(opts.frequency, opts.sample_rate)),
+        "lowpass_12db" => Box::new(BiQuad::lowpass_12db(opts.frequency, opts.q, opts.sample_rate)),
+        "highpass_12db" => Box::new(BiQuad::highpass_12db(opts.frequency, opts.q, opts.sample_rate)),
+        "bandpass_constant_skirt_gain" => Box::new(BiQuad::bandpass_constant_skirt_gain(opts.frequency, opts.bandwidth, opts.sample_rate)),
+        "bandpass_constant_peak_gain" => Box::new(BiQuad::bandpass_constant_peak_gain(opts.frequency, opts.bandwidth, opts.sample_rate)),
+        "notch" => Box::new(BiQuad::notch(opts.frequency, opts.bandwidth, opts.sample_rate)),
+        "allpass" => Box::new(BiQuad::allpass(opts.frequency, opts.q, opts.sample_rate)),
+        "peaking_eq" => Box::new(BiQuad::peaking_eq(opts.frequency, opts.gain, opts.bandwidth, opts.sample_rate)),
+        "low_shelf" => Box::new(BiQuad::low_shelf(opts.frequency, opts.gain, opts.shelf_slope, opts.sample_rate)),
+        "high_shelf" => Box::new(BiQuad::high_shelf(opts.frequency, opts.gain, opts.shelf_slope, opts.sample_rate)),
+        "presence_moorer" => Box::new(BiQuad::presence_moorer(opts.frequency, opts.bandwidth, opts.gain, opts.sample_rate)),
+        "low_shelving_moorer" => Box::new(BiQuad::shelving_moorer(opts.frequency, opts.gain, opts.shelf_slope, false, opts.sample_rate)),
+        "high_shelving_moorer" => Box::new(BiQuad::shelving_moorer(opts.frequency, opts.gain, opts.shelf_slope, true, opts.sample_rate)),
         _ => panic!("Invalid filter specified!")
     };
 
@@ -5,7 +5,8 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
 
-use syn::{parse_macro_input, Expr, Ident, ItemEnum, Lit, Token};
+use quote::ToTokens;
+use syn::{parse_macro_input, Attribute, Expr, Ident, ItemEnum, Lit, Token};
 use syn::parse::{Parse, ParseStream};
 use syn::punctuated::Punctuated;
 
@@ -26,7 +27,9 @@ impl Parse for KeyValuePair {
 }
 
 struct Args {
-    filename: String
+    filename: String,
+    encoder: Option<String>,
+    decoder: Option<String>
 }
 
 impl Parse for Args {
@@ -35,17 +38,25 @@ impl Parse for Args {
         let attributes: Vec<(String, Lit)> = pairs.into_iter().map(|pair| (pair.ident.to_string(), pair.value)).collect();
 
         let mut filename: Option<String> = None;
+        let mut encoder: Option<String> = None;
+        let mut decoder: Option<String> = None;
 
         for (key, lit) in attributes {
             match (key.as_str(), lit) {
                 ("filename", Lit::Str(value)) => filename = Some(value.value()),
                 ("filename", _) => panic!("filename must be a string"),
+                ("encoder", Lit::Str(value)) => encoder = Some(value.value()),
+                ("encoder", _) => panic!("encoder must be a string"),
+                ("decoder", Lit::Str(value)) => decoder = Some(value.value()),
+                ("decoder", _) => panic!("decoder must be a string"),
                 _ => panic!("unrecognized attribute '{}'", key)
             }
         }
 
         Ok(Args {
-            filename: filename.expect("no filename was provided")
+            filename: filename.expect("no filename was provided"),
+            encoder,
+            decoder
         })
     }
 }
@@ -57,40 +68,450 @@ fn extract_value(literal: &Lit) -> isize {
     }
 }
 
+// Returns the integer type from #[repr(...)], or isize for enums without one
+fn extract_repr(ast: &ItemEnum) -> String {
+    ast.attrs.iter()
+        .filter(|attribute| attribute.path.is_ident("repr"))
+        .find_map(|attribute| attribute.parse_args::<Ident>().ok())
+        .map(|ident| ident.to_string())
+        .unwrap_or_else(|| "isize".to_string())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum OperandType {
+    U8,
+    U32,
+    F32,
+    Blob // u32 byte count followed by the bytes
+}
+
+impl OperandType {
+    fn parse(ident: &Ident) -> Self {
+        match ident.to_string().as_str() {
+            "u8" => OperandType::U8,
+            "u32" => OperandType::U32,
+            "f32" => OperandType::F32,
+            "blob" => OperandType::Blob,
+            other => panic!("unrecognized operand type '{}'", other)
+        }
+    }
+
+    fn rust_type(&self) -> &'static str {
+        match self {
+            OperandType::U8 => "u8",
+            OperandType::U32 => "u32",
+            OperandType::F32 => "f32",
+            OperandType::Blob => "&[u8]"
+        }
+    }
+
+    fn typescript_type(&self) -> &'static str {
+        match self {
+            OperandType::Blob => "Uint8Array",
+            _ => "number"
+        }
+    }
+
+    // in bytes, blobs have a variable size
+    fn size(&self) -> Option<usize> {
+        match self {
+            OperandType::U8 => Some(1),
+            OperandType::U32 => Some(4),
+            OperandType::F32 => Some(4),
+            OperandType::Blob => None
+        }
+    }
+}
+
+struct Variant {
+    ident: String,
+    discriminant: isize,
+    operands: Vec<OperandType>,
+    is_sentinel: bool
+}
+
+impl Variant {
+    // Encoders and decoders take a single operand as operand and multiple operands as operands
+    fn operand_expressions(&self, prefix: &str) -> Vec<String> {
+        match self.operands.len() {
+            1 => vec![format!("{}operand", prefix)],
+            _ => (0..self.operands.len()).map(|i| format!("{}operands[{}]", prefix, i)).collect()
+        }
+    }
+}
+
+// Reads #[operands(...)] from a variant, variants without it have no operands
+fn extract_operands(attributes: &[Attribute]) -> Vec<OperandType> {
+    let operands = attributes.iter()
+        .filter(|attribute| attribute.path.is_ident("operands"))
+        .map(|attribute| attribute.parse_args_with(Punctuated::<Ident, Token![,]>::parse_terminated).expect("error parsing operands"))
+        .next()
+        .map(|idents| idents.iter().map(OperandType::parse).collect::<Vec<OperandType>>())
+        .unwrap_or_default();
+
+    if operands.contains(&OperandType::Blob) && operands.len() > 1 {
+        panic!("a blob must be the only operand");
+    }
+
+    operands
+}
+
+fn is_helper_attribute(attribute: &Attribute) -> bool {
+    attribute.path.is_ident("operands") || attribute.path.is_ident("sentinel")
+}
+
+fn snake_case(ident: &str) -> String {
+    let mut result = String::with_capacity(2 * ident.len());
+
+    for (i, c) in ident.chars().enumerate() {
+        if c.is_uppercase() && i > 0 {
+            result.push('_');
+        }
+
+        result.extend(c.to_lowercase());
+    }
+
+    result
+}
+
+// TypeScript instruction types and functions to encode and decode them
+fn generate_typescript_schema(name: &str, variants: &[&Variant]) -> String {
+    let mut output = String::with_capacity(65536);
+
+    // Instruction type
+    let types = variants.iter().map(|variant| {
+        let operand_types = variant.operands.iter().map(|operand| operand.typescript_type()).collect::<Vec<&str>>();
+
+        match operand_types.len() {
+            0 => format!("    {{ opcode: typeof {}.{} }}", name, variant.ident),
+            1 => format!("    {{ opcode: typeof {}.{}, operand: {} }}", name, variant.ident, operand_types[0]),
+            _ => format!("    {{ opcode: typeof {}.{}, operands: [{}] }}", name, variant.ident, operand_types.join(", "))
+        }
+    }).collect::<Vec<String>>().join(" |\n");
+
+    output.push_str(&format!("\nexport type Instruction =\n{};\n", types));
+
+    // Encoder
+    output.push_str("\n// Writes an instruction at ptr, returns the position after it\n");
+    output.push_str("export function encodeInstruction(view: DataView, ptr: number, instruction: Instruction): number {\n");
+    output.push_str("    view.setUint8(ptr++, instruction.opcode);\n\n");
+    output.push_str("    switch (instruction.opcode) {\n");
+
+    for variant in variants.iter().filter(|variant| !variant.operands.is_empty()) {
+        output.push_str(&format!("        case {}.{}:\n", name, variant.ident));
+
+        for (operand, expression) in variant.operands.iter().zip(variant.operand_expressions("instruction.")) {
+            let lines = match operand {
+                OperandType::U8 => vec![
+                    format!("view.setUint8(ptr, {});", expression),
+                    "ptr += 1;".to_string()
+                ],
+                OperandType::U32 => vec![
+                    format!("view.setUint32(ptr, {}, true);", expression),
+                    "ptr += 4;".to_string()
+                ],
+                OperandType::F32 => vec![
+                    format!("view.setFloat32(ptr, {}, true);", expression),
+                    "ptr += 4;".to_string()
+                ],
+                OperandType::Blob => vec![
+                    format!("view.setUint32(ptr, {}.length, true);", expression),
+                    "ptr += 4;".to_string(),
+                    format!("new Uint8Array(view.buffer, view.byteOffset + ptr, {0}.length).set({0});", expression),
+                    format!("ptr += {}.length;", expression)
+                ]
+            };
+
+            for line in lines {
+                output.push_str(&format!("            {}\n", line));
+            }
+        }
+
+        output.push_str("            break;\n");
+    }
+
+    output.push_str("    }\n\n    return ptr;\n}\n");
+
+    // Decoder
+    output.push_str("\n// Reads the instruction at ptr, returns it with the position after it. Unknown opcodes are\n");
+    output.push_str("// returned as undefined with the end of the view as position, since their size is unknown.\n");
+    output.push_str("export function decodeInstruction(view: DataView, ptr: number): [Instruction | undefined, number] {\n");
+    output.push_str("    switch (view.getUint8(ptr++)) {\n");
+
+    for variant in variants {
+        output.push_str(&format!("        case {}.{}: {{\n", name, variant.ident));
+
+        let mut offset = 0;
+        let mut reads = Vec::new();
+
+        for operand in &variant.operands {
+            reads.push(match operand {
+                OperandType::U8 => format!("view.getUint8(ptr + {})", offset),
+                OperandType::U32 => format!("view.getUint32(ptr + {}, true)", offset),
+                OperandType::F32 => format!("view.getFloat32(ptr + {}, true)", offset),
+                OperandType::Blob => {
+                    output.push_str("            const length = view.getUint32(ptr, true);\n");
+                    "new Uint8Array(view.buffer, view.byteOffset + ptr + 4, length).slice()".to_string()
+                }
+            });
+
+            offset += operand.size().unwrap_or(4);
+        }
+
+        let size = if variant.operands.contains(&OperandType::Blob) { "4 + length".to_string() } else { offset.to_string() };
+
+        let instruction = match reads.len() {
+            0 => format!("{{ opcode: {}.{} }}", name, variant.ident),
+            1 => format!("{{ opcode: {}.{}, operand: {} }}", name, variant.ident, reads[0]),
+            _ => format!("{{ opcode: {}.{}, operands: [{}] }}", name, variant.ident, reads.join(", "))
+        };
+
+        output.push_str(&format!("            return [{}, ptr + {}];\n", instruction, size));
+        output.push_str("        }\n");
+    }
+
+    output.push_str("        default:\n");
+    output.push_str("            return [undefined, view.byteLength];\n");
+    output.push_str("    }\n}\n");
+
+    output
+}
+
+// A Rust module with a function per variant that appends the encoded instruction to a program
+fn generate_rust_encoder(module: &str, name: &str, variants: &[&Variant]) -> String {
+    let functions = variants.iter().map(|variant| {
+        let names = match variant.operands.len() {
+            1 => vec!["operand".to_string()],
+            count => (0..count).map(|i| format!("operand{}", i)).collect()
+        };
+
+        let parameters = variant.operands.iter().zip(&names).map(|(operand, name)| {
+            format!(", {}: {}", name, operand.rust_type())
+        }).collect::<String>();
+
+        let body = variant.operands.iter().zip(&names).map(|(operand, name)| {
+            match operand {
+                OperandType::U8 => format!("program.push({});", name),
+                OperandType::Blob => format!("program.extend_from_slice(&({0}.len() as u32).to_le_bytes()); program.extend_from_slice({0});", name),
+                _ => format!("program.extend_from_slice(&{}.to_le_bytes());", name)
+            }
+        }).collect::<String>();
+
+        format!(
+            "pub fn {function}(program: &mut Vec<u8>{parameters}) {{
+                program.push({name}::{ident} as u8);
+                {body}
+            }}",
+            function = snake_case(&variant.ident),
+            parameters = parameters,
+            name = name,
+            ident = variant.ident,
+            body = body
+        )
+    }).collect::<String>();
+
+    format!(
+        "pub mod {module} {{
+            use super::{name};
+
+            {functions}
+        }}",
+        module = module,
+        name = name,
+        functions = functions
+    )
+}
+
+// A Rust module with a function per variant with operands that reads them from the operand bytes
+// of an instruction, plus the operand size that is needed to find the next instruction. The
+// operand bytes must have the size from the schema, blob operands are passed without their
+// length prefix.
+fn generate_rust_decoder(module: &str, name: &str, variants: &[&Variant]) -> String {
+    let read = |operand: &OperandType, offset: usize| {
+        match operand {
+            OperandType::U8 => format!("operands[{}]", offset),
+            OperandType::U32 | OperandType::F32 => format!(
+                "{}::from_le_bytes([operands[{}], operands[{}], operands[{}], operands[{}]])",
+                operand.rust_type(), offset, offset + 1, offset + 2, offset + 3
+            ),
+            OperandType::Blob => "operands".to_string()
+        }
+    };
+
+    let with_operands = variants.iter().filter(|variant| !variant.operands.is_empty()).collect::<Vec<&&Variant>>();
+
+    let functions = with_operands.iter().map(|variant| {
+        let mut offset = 0;
+        let mut reads = Vec::new();
+
+        for operand in &variant.operands {
+            reads.push(read(operand, offset));
+            offset += operand.size().unwrap_or(0);
+        }
+
+        let types = variant.operands.iter().map(|operand| operand.rust_type()).collect::<Vec<&str>>();
+
+        let (return_type, value) = match reads.len() {
+            1 => (types[0].to_string(), reads[0].clone()),
+            _ => (format!("({})", types.join(", ")), format!("({})", reads.join(", ")))
+        };
+
+        format!(
+            "pub fn {function}(operands: &[u8]) -> {return_type} {{
+                {value}
+            }}",
+            function = snake_case(&variant.ident),
+            return_type = return_type,
+            value = value
+        )
+    }).collect::<String>();
+
+    let sizes = variants.iter().map(|variant| {
+        let size = if variant.operands.contains(&OperandType::Blob) {
+            "None".to_string()
+        } else {
+            format!("Some({})", variant.operands.iter().filter_map(|operand| operand.size()).sum::<usize>())
+        };
+
+        format!("{}::{} => {},", name, variant.ident, size)
+    }).collect::<String>();
+
+    // Only variants with f32 operands need a check
+    let checks = with_operands.iter().filter(|variant| variant.operands.contains(&OperandType::F32)).map(|variant| {
+        let function = snake_case(&variant.ident);
+
+        let conditions = match variant.operands.len() {
+            1 => vec![format!("{}(operands).is_finite()", function)],
+            _ => variant.operands.iter().enumerate()
+                .filter(|(_, operand)| **operand == OperandType::F32)
+                .map(|(i, _)| format!("{}(operands).{}.is_finite()", function, i))
+                .collect()
+        };
+
+        format!("{}::{} => {},", name, variant.ident, conditions.join(" && "))
+    }).collect::<String>();
+
+    format!(
+        "pub mod {module} {{
+            use super::{name};
+
+            {functions}
+
+            // in bytes, None for blobs
+            pub fn operand_size(opcode: {name}) -> Option<usize> {{
+                #[allow(unreachable_patterns)]
+                match opcode {{
+                    {sizes}
+                    _ => None
+                }}
+            }}
+
+            // False when an f32 operand is NaN or infinite
+            pub fn is_finite(opcode: {name}, operands: &[u8]) -> bool {{
+                #[allow(unreachable_patterns)]
+                match opcode {{
+                    {checks}
+                    _ => true
+                }}
+            }}
+        }}",
+        module = module,
+        name = name,
+        functions = functions,
+        sizes = sizes,
+        checks = checks
+    )
+}
+
 #[proc_macro_attribute]
 pub fn enum_to_js(args: TokenStream, input: TokenStream) -> TokenStream {
     // Parse arguments
     let arguments = parse_macro_input!(args as Args);
 
     // Parse input
-    let cloned = input.clone();
-    let ast = parse_macro_input!(cloned as ItemEnum);
-
-    // Build output string
-    let mut output = String::with_capacity(16384);
-
-    output.push_str(&format!("const {} = {{\n", ast.ident));
+    let mut ast = parse_macro_input!(input as ItemEnum);
+    let name = ast.ident.to_string();
 
+    // Resolve discriminants and operands
     let mut discriminant = 0;
 
-    let lines = ast.variants.iter().map(|variant| {
+    let variants = ast.variants.iter().map(|variant| {
         discriminant = match &variant.discriminant {
             Some((_, Expr::Lit(literal))) => extract_value(&literal.lit),
             None => discriminant + 1,
             _ => panic!("enum discriminant must be a literal")
         };
 
-        format!("    {}: {}", variant.ident, discriminant)
+        Variant {
+            ident: variant.ident.to_string(),
+            discriminant,
+            operands: extract_operands(&variant.attrs),
+            is_sentinel: variant.attrs.iter().any(|attribute| attribute.path.is_ident("sentinel"))
+        }
+    }).collect::<Vec<Variant>>();
+
+    // Sentinels are not part of the schema
+    let schema = variants.iter().filter(|variant| !variant.is_sentinel).collect::<Vec<&Variant>>();
+
+    // Build output string
+    let mut output = String::with_capacity(16384);
+
+    output.push_str(&format!("const {} = {{\n", name));
+
+    let lines = variants.iter().map(|variant| {
+        format!("    {}: {}", variant.ident, variant.discriminant)
     }).collect::<Vec<String>>().join(",\n");
 
     output.push_str(&lines);
-    output.push_str(&format!("\n}} as const;\n\nexport default {};\n", ast.ident));
+    output.push_str(&format!("\n}} as const;\n\nexport default {};\n", name));
+
+    if arguments.encoder.is_some() {
+        output.push_str(&generate_typescript_schema(&name, &schema));
+    }
 
     // Write file to disk
     let path = Path::new(&arguments.filename);
-    let mut file = File::create(&path).expect("error opening output file");
+    let mut file = File::create(path).expect("error opening output file");
     file.write_all(output.as_bytes()).expect("error writing output file");
 
-    // Leave original enum untouched
-    input
+    // Leave original enum untouched apart from our own attributes, but add a conversion from its
+    // representation so that values coming from the frontend can be checked without transmuting
+    for variant in ast.variants.iter_mut() {
+        variant.attrs.retain(|attribute| !is_helper_attribute(attribute));
+    }
+
+    let repr = extract_repr(&ast);
+
+    let arms = variants.iter().map(|variant| {
+        format!("{} => Ok(Self::{}),", variant.discriminant, variant.ident)
+    }).collect::<Vec<String>>().join("\n");
+
+    let conversion = format!(
+        "impl std::convert::TryFrom<{repr}> for {ident} {{
+            type Error = {repr};
+
+            fn try_from(value: {repr}) -> Result<Self, Self::Error> {{
+                match value {{
+                    {arms}
+                    _ => Err(value)
+                }}
+            }}
+        }}",
+        repr = repr,
+        ident = name,
+        arms = arms
+    );
+
+    let mut result: TokenStream = ast.to_token_stream().into();
+    result.extend(conversion.parse::<TokenStream>().expect("error generating conversion"));
+
+    if let Some(module) = &arguments.encoder {
+        result.extend(generate_rust_encoder(module, &name, &schema).parse::<TokenStream>().expect("error generating encoder"));
+    }
+
+    if let Some(module) = &arguments.decoder {
+        result.extend(generate_rust_decoder(module, &name, &schema).parse::<TokenStream>().expect("error generating decoder"));
+    }
+
+    result
 }
@@ -0,0 +1,155 @@
+use clap::{Arg, App};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use kernel::kernel::{Kernel, DEFAULT_MAX_BLOCK_SIZE};
+use kernel::vm::{decode, Opcode, ProgramError, MAX_PROGRAM_SIZE};
+use std::fs;
+use std::process;
+
+#[derive(Debug)]
+struct Opts {
+    program: String,
+    output: String,
+    bars: u32,
+    format: String,
+    sample_rate: u32,
+    block_size: usize
+}
+
+fn parse_args() -> Opts {
+    let matches = App::new("render")
+        .version("1.0.0")
+        .author("Emil Loer <emil@koffietijd.net>")
+        .about("Render a kernel program to a WAV file")
+        .arg(Arg::new("program")
+             .about("The file containing the serialized VM program")
+             .required(true))
+        .arg(Arg::new("output")
+             .short('o')
+             .long("output")
+             .takes_value(true)
+             .default_value("output.wav")
+             .about("The WAV file to write"))
+        .arg(Arg::new("bars")
+             .short('b')
+             .long("bars")
+             .takes_value(true)
+             .default_value("4")
+             .about("The number of bars to render"))
+        .arg(Arg::new("format")
+             .short('f')
+             .long("format")
+             .takes_value(true)
+             .possible_values(&["float", "pcm16"])
+             .default_value("float")
+             .about("The sample format of the WAV file"))
+        .arg(Arg::new("sample-rate")
+             .short('r')
+             .long("sample-rate")
+             .takes_value(true)
+             .default_value("44100")
+             .about("The sample rate (in Hz)"))
+        .arg(Arg::new("block-size")
+             .short('s')
+             .long("block-size")
+             .takes_value(true)
+             .default_value(&DEFAULT_MAX_BLOCK_SIZE.to_string())
+             .about("The number of frames to render per block"))
+        .get_matches();
+
+    Opts {
+        program: matches.value_of_t_or_exit("program"),
+        output: matches.value_of_t_or_exit("output"),
+        bars: matches.value_of_t_or_exit("bars"),
+        format: matches.value_of_t_or_exit("format"),
+        sample_rate: matches.value_of_t_or_exit("sample-rate"),
+        block_size: matches.value_of_t_or_exit("block-size")
+    }
+}
+
+fn main() {
+    let opts = parse_args();
+
+    let program = fs::read(&opts.program).unwrap_or_else(|err| {
+        eprintln!("Error reading program {}: {}", opts.program, err);
+        process::exit(1);
+    });
+
+    if program.len() > MAX_PROGRAM_SIZE {
+        eprintln!("Program is too large ({} bytes, maximum is {})", program.len(), MAX_PROGRAM_SIZE);
+        process::exit(1);
+    }
+
+    let mut kernel = Kernel::new();
+    kernel.initialize(opts.sample_rate as f32);
+    kernel.set_max_block_size(opts.block_size);
+
+    // Execute the program without rendering so that the first block already uses its settings
+    let program_size = kernel.vm.borrow_mut().load(&program);
+    kernel.process(program_size as u32, 0);
+
+    // Instructions that could not be executed are reported back in the program buffer
+    for instruction in kernel.vm.borrow().outbound_instructions().flatten() {
+        if let (Opcode::DecodeError, Some(error)) = (instruction.opcode, ProgramError::decode(decode::decode_error(instruction.operands()))) {
+            eprintln!("Skipped instruction {} at offset {}: {:?}", error.opcode, error.offset, error.reason);
+        }
+    }
+
+    // A bar consists of four beats
+    let frames_per_bar = opts.sample_rate as f32 * 60.0 / kernel.tempo() * 4.0;
+    let total_frames = (opts.bars as f32 * frames_per_bar).round() as usize;
+
+    let spec = match opts.format.as_str() {
+        "pcm16" => WavSpec {
+            channels: 2,
+            sample_rate: opts.sample_rate,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int
+        },
+        _ => WavSpec {
+            channels: 2,
+            sample_rate: opts.sample_rate,
+            bits_per_sample: 32,
+            sample_format: SampleFormat::Float
+        }
+    };
+
+    let mut writer = WavWriter::create(&opts.output, spec).unwrap_or_else(|err| {
+        eprintln!("Error creating {}: {}", opts.output, err);
+        process::exit(1);
+    });
+
+    let mut frames_written = 0;
+
+    while frames_written < total_frames {
+        let frames = opts.block_size.min(total_frames - frames_written);
+        kernel.process(0, frames);
+
+        for i in 0..frames {
+            let left = kernel.left_buffer[i];
+            let right = kernel.right_buffer[i];
+
+            let result = match spec.sample_format {
+                SampleFormat::Int => writer.write_sample(to_pcm16(left)).and_then(|_| writer.write_sample(to_pcm16(right))),
+                SampleFormat::Float => writer.write_sample(left).and_then(|_| writer.write_sample(right))
+            };
+
+            if let Err(err) = result {
+                eprintln!("Error writing {}: {}", opts.output, err);
+                process::exit(1);
+            }
+        }
+
+        frames_written += frames;
+    }
+
+    writer.finalize().unwrap_or_else(|err| {
+        eprintln!("Error finalizing {}: {}", opts.output, err);
+        process::exit(1);
+    });
+
+    println!("Rendered {} frames ({} bars at {} bpm) to {}", total_frames, opts.bars, kernel.tempo(), opts.output);
+}
+
+fn to_pcm16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
@@ -0,0 +1,276 @@
+use crate::groove::Groove;
+use crate::parameters::ParameterId;
+use crate::vm::ErrorReason;
+
+use enumtojs::enum_to_js;
+
+use std::convert::TryFrom;
+
+// Sequencers step on multiples of these pulses, which fit every step resolution
+pub const PULSES_PER_QUARTER_NOTE: u32 = 24;
+
+// Pulses in a sixteenth note, the length of a step of the clock itself
+pub const PULSES_PER_STEP: u32 = PULSES_PER_QUARTER_NOTE / 4;
+
+// External clocks that are further off than this are followed by jumping to their position,
+// smaller differences are caught up with by adjusting the tempo
+const MAX_DRIFT: f64 = PULSES_PER_STEP as f64;
+
+// Largest tempo change used to catch up with an external clock, as a fraction of its tempo
+const MAX_TEMPO_CORRECTION: f64 = 0.1;
+
+// Smoothing of the time between MIDI clock pulses, the fraction of each new measurement
+const PULSE_INTERVAL_SMOOTHING: f32 = 0.1;
+
+// Where the pulses of the clock come from
+#[repr(u8)]
+#[enum_to_js(filename="../src/ClockSource.ts")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ClockSource {
+    Internal = 0, // timed by the tempo, the kernel is the master
+    Midi, // a pulse for every MIDI clock byte, the tempo follows the bytes
+    Host // timed by the tempo, kept in line with the transport of the host
+}
+
+impl ClockSource {
+    pub fn decode(value: u8) -> Result<Self, ErrorReason> {
+        Self::try_from(value).map_err(|_| ErrorReason::OutOfRange)
+    }
+}
+
+// The clock is shared by all sequencers in the kernel so that they stay in sync
+pub struct Clock {
+    is_running: bool,
+    restart: bool, // the next pulse starts a step
+    pub sample_position: u32, // in the current sixteenth note
+    pub step_started: bool,
+    pub pulse_started: bool,
+    pub pulse: u32, // counted from the first step, wraps around
+    pub groove: Groove,
+    source: ClockSource,
+    pending_pulses: u32, // MIDI clock pulses that were received but not started yet
+    samples_since_pulse: u32, // since the last MIDI clock pulse
+    pulse_interval: Option<f32>, // smoothed time between MIDI clock pulses, in samples
+    pulse_in_step: u32,
+    step_length: u32,
+    sample_rate: f32,
+    tempo: f32
+}
+
+impl Clock {
+    pub fn new(sample_rate: f32) -> Self {
+        let mut clock = Clock {
+            is_running: true,
+            restart: true,
+            sample_position: 0,
+            step_started: false,
+            pulse_started: false,
+            pulse: u32::MAX,
+            groove: Groove::new(),
+            source: ClockSource::Internal,
+            pending_pulses: 0,
+            samples_since_pulse: 0,
+            pulse_interval: None,
+            pulse_in_step: 0,
+            step_length: 0,
+            sample_rate,
+            tempo: 120.0
+        };
+
+        clock.update_step_length();
+
+        clock
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.is_running
+    }
+
+    // Runs from the start of a step
+    pub fn start(&mut self) {
+        self.is_running = true;
+        self.reset();
+    }
+
+    pub fn stop(&mut self) {
+        self.is_running = false;
+        self.pending_pulses = 0;
+    }
+
+    // Runs from where the clock was stopped
+    pub fn resume(&mut self) {
+        self.is_running = true;
+    }
+
+    // The next pulse starts a step, pulses are counted from there
+    pub fn reset(&mut self) {
+        self.restart = true;
+    }
+
+    // The step that is due when the clock continues, in sixteenth notes like MIDI song position
+    // pointers
+    pub fn song_position(&self) -> u32 {
+        if self.restart {
+            0
+        } else {
+            self.pulse.wrapping_add(1).div_ceil(PULSES_PER_STEP)
+        }
+    }
+
+    pub fn source(&self) -> ClockSource {
+        self.source
+    }
+
+    pub fn set_source(&mut self, source: ClockSource) {
+        self.source = source;
+        self.pending_pulses = 0;
+        self.pulse_interval = None;
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.update_step_length();
+    }
+
+    pub fn set_tempo(&mut self, tempo: f32) {
+        self.tempo = tempo;
+        self.update_step_length();
+    }
+
+    pub fn tempo(&self) -> f32 {
+        self.tempo
+    }
+
+    // External clocks are followed within the range of the tempo parameter, so that snapshots
+    // taken while following them can be restored
+    fn set_followed_tempo(&mut self, tempo: f32) {
+        let parameter = ParameterId::Tempo.parameter();
+        self.set_tempo(tempo.clamp(parameter.min, parameter.max));
+    }
+
+    // Length of a number of pulses in samples, rounded down
+    pub fn step_length(&self, pulses: u32) -> u32 {
+        self.step_length * pulses / PULSES_PER_STEP
+    }
+
+    fn update_step_length(&mut self) {
+        self.step_length = (self.sample_rate * 60.0 / self.tempo / 4.0) as u32;
+    }
+
+    // Called for every MIDI clock byte. The pulse starts on the next sample, and the tempo
+    // follows the time between pulses so that grooves and envelopes keep their timing.
+    pub fn external_pulse(&mut self) {
+        if self.source != ClockSource::Midi {
+            return;
+        }
+
+        // Pulses after a long pause, like the first one, do not say anything about the tempo
+        let interval = self.samples_since_pulse as f32;
+        self.samples_since_pulse = 0;
+
+        if interval > 0.0 && interval < self.sample_rate {
+            let smoothed = match self.pulse_interval {
+                Some(pulse_interval) => pulse_interval + PULSE_INTERVAL_SMOOTHING * (interval - pulse_interval),
+                None => interval
+            };
+
+            self.pulse_interval = Some(smoothed);
+            self.set_followed_tempo(self.sample_rate * 60.0 / (smoothed * PULSES_PER_QUARTER_NOTE as f32));
+        }
+
+        if self.is_running {
+            self.pending_pulses += 1;
+        }
+    }
+
+    // The position in pulses since the first step, including the part of the current pulse
+    pub fn position(&self) -> f64 {
+        let step_start = self.pulse.wrapping_sub(self.pulse_in_step) as f64;
+        step_start + self.sample_position as f64 * PULSES_PER_STEP as f64 / self.step_length.max(1) as f64
+    }
+
+    // Jumps to a position in pulses. A pulse that lies exactly on the position starts with the
+    // next sample.
+    pub fn set_position(&mut self, position: f64) {
+        let previous_pulse = position.ceil() as i64 - 1;
+
+        if previous_pulse < 0 {
+            self.reset();
+            return;
+        }
+
+        self.restart = false;
+        self.pulse = previous_pulse as u32;
+        self.pulse_in_step = self.pulse % PULSES_PER_STEP;
+
+        let step_start = (self.pulse - self.pulse_in_step) as f64;
+        self.sample_position = ((position - step_start) / PULSES_PER_STEP as f64 * self.step_length as f64) as u32;
+    }
+
+    // Follows the transport of the host, with its tempo in BPM and its position in pulses.
+    // Returns false when the clock is too far off to catch up, it then has to jump to the
+    // position instead.
+    pub fn follow(&mut self, tempo: f32, position: f64) -> bool {
+        let drift = position - self.position();
+
+        if self.restart || drift.abs() > MAX_DRIFT {
+            self.set_followed_tempo(tempo);
+            return false;
+        }
+
+        // Catches up within about a beat
+        let correction = (drift / PULSES_PER_QUARTER_NOTE as f64).clamp(-MAX_TEMPO_CORRECTION, MAX_TEMPO_CORRECTION);
+        self.set_followed_tempo(tempo * (1.0 + correction) as f32);
+
+        true
+    }
+
+    fn is_pulse_due(&mut self) -> bool {
+        match self.source {
+            ClockSource::Midi if self.pending_pulses > 0 => {
+                self.pending_pulses -= 1;
+                true
+            },
+
+            ClockSource::Midi => false,
+
+            // Pulses divide every step evenly, rounded down to whole samples
+            _ => self.restart || self.sample_position >= (self.pulse_in_step + 1) * self.step_length / PULSES_PER_STEP
+        }
+    }
+
+    // Advances the clock by one sample, returns true if a new step was started. Pulses that
+    // are late start one per sample until the clock has caught up.
+    pub fn tick(&mut self) -> bool {
+        self.step_started = false;
+        self.pulse_started = false;
+        self.samples_since_pulse = self.samples_since_pulse.saturating_add(1);
+
+        if !self.is_running {
+            return false;
+        }
+
+        self.sample_position += 1;
+
+        if !self.is_pulse_due() {
+            return false;
+        }
+
+        if self.restart {
+            self.restart = false;
+            self.pulse = u32::MAX;
+            self.pulse_in_step = PULSES_PER_STEP - 1;
+        }
+
+        self.pulse = self.pulse.wrapping_add(1);
+        self.pulse_in_step = (self.pulse_in_step + 1) % PULSES_PER_STEP;
+        self.pulse_started = true;
+
+        if self.pulse_in_step == 0 {
+            self.sample_position = 0;
+            self.step_started = true;
+        }
+
+        self.step_started
+    }
+}
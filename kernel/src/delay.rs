@@ -1,39 +1,125 @@
-use crate::kernel::{ANTI_DENORMAL,SAMPLE_RATE};
+use crate::kernel::ANTI_DENORMAL;
 
-const DELAY_BUFFER_SIZE: usize = 2 * SAMPLE_RATE as usize;
+// Maximum delay time in milliseconds
+pub const MAX_DELAY_TIME: u32 = 2000;
 
 pub struct Delay {
-    buffer: Vec<f32>,
+    left_buffer: Vec<f32>,
+    right_buffer: Vec<f32>,
 
-    pub send: f32, // 0..1
     pub feedback: f32, // 0..1
-    pub length: usize, // in samples
+    pub ping_pong: f32, // 0..1
+    time: u32, // in milliseconds
+    length: usize, // in samples, follows from the time and the sample rate
 
-    position: usize
+    position: usize,
+    sample_rate: f32
 }
 
 impl Delay {
-    pub fn new() -> Self {
-        Self {
-            buffer: vec![0.0; DELAY_BUFFER_SIZE],
+    pub fn new(sample_rate: f32) -> Self {
+        let mut delay = Self {
+            left_buffer: Vec::new(),
+            right_buffer: Vec::new(),
 
-            send: 0.2,
             feedback: 0.5,
-            length: 20000,
+            ping_pong: 0.0,
+            time: 450,
+            length: 0,
 
-            position: 0
+            position: 0,
+            sample_rate
+        };
+
+        delay.set_sample_rate(sample_rate);
+        delay
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        let size = Self::samples(MAX_DELAY_TIME, sample_rate);
+
+        self.left_buffer = vec![0.0; size];
+        self.right_buffer = vec![0.0; size];
+        self.sample_rate = sample_rate;
+        self.position = 0;
+
+        self.set_time(self.time);
+    }
+
+    // in milliseconds
+    pub fn time(&self) -> u32 {
+        self.time
+    }
+
+    pub fn set_time(&mut self, time: u32) {
+        self.time = time.min(MAX_DELAY_TIME);
+        self.length = Self::samples(self.time, self.sample_rate).min(self.left_buffer.len()).max(1);
+
+        if self.position >= self.length {
+            self.position = 0;
         }
     }
 
-    pub fn render(&mut self, sample: f32) -> f32 {
-        let previous = self.buffer[self.position];
-        self.buffer[self.position] = self.send * sample + self.feedback * previous + ANTI_DENORMAL;
+    fn samples(time: u32, sample_rate: f32) -> usize {
+        (time as f32 * 0.001 * sample_rate).round() as usize
+    }
+
+    // Takes the send bus as input and returns the wet signal only
+    pub fn render(&mut self, left: f32, right: f32) -> (f32, f32) {
+        let previous_left = self.left_buffer[self.position];
+        let previous_right = self.right_buffer[self.position];
+
+        // With ping pong enabled the input is summed into the left line and the repeats cross
+        // over to the opposite channel
+        let straight = 1.0 - self.ping_pong;
+
+        let input_left = straight * left + self.ping_pong * (left + right);
+        let input_right = straight * right;
+
+        let feedback_left = straight * previous_left + self.ping_pong * previous_right;
+        let feedback_right = straight * previous_right + self.ping_pong * previous_left;
+
+        self.left_buffer[self.position] = input_left + self.feedback * feedback_left + ANTI_DENORMAL;
+        self.right_buffer[self.position] = input_right + self.feedback * feedback_right + ANTI_DENORMAL;
 
         self.position += 1;
         if self.position >= self.length {
             self.position = 0;
         }
 
-        sample + previous
+        (previous_left, previous_right)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ping_pong() {
+        let mut delay = Delay::new(44100.0);
+        delay.feedback = 1.0;
+        delay.ping_pong = 1.0;
+        delay.length = 10;
+
+        delay.render(1.0, 0.0);
+
+        // Repeats alternate between the left and right channel
+        let output: Vec<(f32, f32)> = (0..30).map(|_| delay.render(0.0, 0.0)).collect();
+        assert!(output[9].0 > 0.5 && output[9].1 < 0.5);
+        assert!(output[19].0 < 0.5 && output[19].1 > 0.5);
+        assert!(output[29].0 > 0.5 && output[29].1 < 0.5);
+    }
+
+    #[test]
+    fn test_time() {
+        let mut delay = Delay::new(44100.0);
+        delay.set_time(100);
+        assert_eq!(delay.length, 4410);
+
+        // The delay time does not depend on the sample rate
+        delay.set_sample_rate(48000.0);
+        assert_eq!(delay.time(), 100);
+        assert_eq!(delay.length, 4800);
     }
 }
@@ -1,5 +1,6 @@
 pub struct FoldbackDistortion {
     pub shape: f32, // 0..1
+    threshold: f32, // 0..1
 
     gain: f32,
     effective_threshold: f32
@@ -9,6 +10,7 @@ impl FoldbackDistortion {
     pub fn new() -> Self {
         Self {
             shape: 0.5,
+            threshold: 0.5 / 0.9,
 
             gain: 2.0,
             effective_threshold: 0.5
@@ -17,10 +19,19 @@ impl FoldbackDistortion {
 
     // 0.1..1
     pub fn set_threshold(&mut self, threshold: f32) {
+        self.threshold = threshold;
         self.effective_threshold = 1.0 - 0.9 * threshold;
         self.gain = 1.0 / self.effective_threshold;
     }
 
+    pub fn threshold(&self) -> f32 {
+        self.threshold
+    }
+
+    pub fn render_stereo(&self, left: f32, right: f32) -> (f32, f32) {
+        (self.render(left), self.render(right))
+    }
+
     pub fn render(&self, sample: f32) -> f32 {
         if sample.abs() > self.effective_threshold {
             let clipped = sample.signum() * self.effective_threshold;
@@ -0,0 +1,449 @@
+use crate::clock::Clock;
+use crate::filters::{OnePole,BiQuad};
+use crate::kernel::{ANTI_DENORMAL, DRUM_CHANNEL};
+use crate::parameters::ParameterId;
+use crate::sequencer::{PatternStep, Sequencer};
+use crate::vm::{decode, encode, ErrorReason, Instruction, Opcode, VM};
+
+use std::cell::RefCell;
+use std::f32::consts::PI;
+use std::rc::Rc;
+
+// Instrument bits in a drum step
+pub const KICK: u8 = 1 << 0;
+pub const SNARE: u8 = 1 << 1;
+pub const CLOSED_HAT: u8 = 1 << 2;
+pub const OPEN_HAT: u8 = 1 << 3;
+pub const CLAP: u8 = 1 << 4;
+
+// Velocity of steps without accent
+const NORMAL_VELOCITY: f32 = 0.6;
+
+fn decay_multiplier(decay: f32, sample_rate: f32) -> f32 {
+    // Reduction of 1/e in decay ms
+    (-1.0 / (0.001 * decay * sample_rate)).exp()
+}
+
+#[derive(Clone, Copy, Default)]
+pub struct DrumStep {
+    pub instruments: u8,
+    pub has_accent: bool
+}
+
+impl PatternStep for DrumStep {
+    // Decodes a step from the instruments and flags bytes, every combination is valid
+    fn decode(instruments: u8, flags: u8) -> Result<Self, ErrorReason> {
+        Ok(DrumStep {
+            instruments,
+            has_accent: (flags & (1 << 0)) != 0
+        })
+    }
+
+    fn encode(&self) -> [u8; 2] {
+        [self.instruments, self.has_accent as u8]
+    }
+}
+
+struct Kick {
+    tune: f32, // Hz
+    decay: f32, // in ms
+
+    phase: f32,
+    amplitude: f32,
+    amplitude_multiplier: f32,
+    pitch_envelope: f32,
+    pitch_multiplier: f32
+}
+
+impl Kick {
+    fn new() -> Self {
+        Kick {
+            tune: 50.0,
+            decay: 300.0,
+
+            phase: 0.0,
+            amplitude: 0.0,
+            amplitude_multiplier: 0.0,
+            pitch_envelope: 0.0,
+            pitch_multiplier: 0.0
+        }
+    }
+
+    fn trigger(&mut self, velocity: f32, sample_rate: f32) {
+        self.phase = 0.0;
+        self.amplitude = velocity;
+        self.amplitude_multiplier = decay_multiplier(self.decay, sample_rate);
+        self.pitch_envelope = 1.0;
+        self.pitch_multiplier = decay_multiplier(20.0, sample_rate);
+    }
+
+    fn render(&mut self, sample_rate: f32) -> f32 {
+        let sample = (2.0 * PI * self.phase).sin() * self.amplitude;
+
+        // The pitch sweeps down from a few octaves above the tuning
+        let frequency = self.tune * (1.0 + 3.0 * self.pitch_envelope);
+        self.phase += frequency / sample_rate;
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+        }
+
+        self.amplitude = self.amplitude * self.amplitude_multiplier + ANTI_DENORMAL;
+        self.pitch_envelope = self.pitch_envelope * self.pitch_multiplier + ANTI_DENORMAL;
+
+        sample
+    }
+}
+
+struct Snare {
+    snappy: f32, // 0..1
+
+    phase: f32,
+    tone_amplitude: f32,
+    tone_multiplier: f32,
+    noise_amplitude: f32,
+    noise_multiplier: f32,
+
+    highpass: OnePole
+}
+
+impl Snare {
+    fn new(sample_rate: f32) -> Self {
+        Snare {
+            snappy: 0.7,
+
+            phase: 0.0,
+            tone_amplitude: 0.0,
+            tone_multiplier: 0.0,
+            noise_amplitude: 0.0,
+            noise_multiplier: 0.0,
+
+            highpass: OnePole::high_pass(1500.0, sample_rate)
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.highpass = OnePole::high_pass(1500.0, sample_rate);
+    }
+
+    fn trigger(&mut self, velocity: f32, sample_rate: f32) {
+        self.phase = 0.0;
+        self.tone_amplitude = velocity;
+        self.tone_multiplier = decay_multiplier(80.0, sample_rate);
+        self.noise_amplitude = velocity * self.snappy;
+        self.noise_multiplier = decay_multiplier(180.0, sample_rate);
+    }
+
+    fn render(&mut self, noise: f32, sample_rate: f32) -> f32 {
+        let tone = (2.0 * PI * self.phase).sin() * self.tone_amplitude;
+
+        self.phase += 185.0 / sample_rate;
+        if self.phase >= 1.0 {
+            self.phase -= 1.0;
+        }
+
+        let noise = self.highpass.render(noise) * self.noise_amplitude;
+
+        self.tone_amplitude = self.tone_amplitude * self.tone_multiplier + ANTI_DENORMAL;
+        self.noise_amplitude = self.noise_amplitude * self.noise_multiplier + ANTI_DENORMAL;
+
+        0.6 * tone + noise
+    }
+}
+
+struct HiHats {
+    decay: f32, // in ms, open hat only
+
+    closed_amplitude: f32,
+    closed_multiplier: f32,
+    open_amplitude: f32,
+    open_multiplier: f32,
+
+    highpass: BiQuad
+}
+
+impl HiHats {
+    fn new(sample_rate: f32) -> Self {
+        HiHats {
+            decay: 300.0,
+
+            closed_amplitude: 0.0,
+            closed_multiplier: 0.0,
+            open_amplitude: 0.0,
+            open_multiplier: 0.0,
+
+            highpass: BiQuad::highpass_12db(7000.0, 0.7, sample_rate)
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.highpass = BiQuad::highpass_12db(7000.0, 0.7, sample_rate);
+    }
+
+    fn trigger_closed(&mut self, velocity: f32, sample_rate: f32) {
+        // A closed hat chokes the open hat
+        self.open_amplitude = 0.0;
+
+        self.closed_amplitude = velocity;
+        self.closed_multiplier = decay_multiplier(40.0, sample_rate);
+    }
+
+    fn trigger_open(&mut self, velocity: f32, sample_rate: f32) {
+        self.open_amplitude = velocity;
+        self.open_multiplier = decay_multiplier(self.decay, sample_rate);
+    }
+
+    fn render(&mut self, noise: f32) -> f32 {
+        let sample = self.highpass.render(noise) * (self.closed_amplitude + self.open_amplitude);
+
+        self.closed_amplitude = self.closed_amplitude * self.closed_multiplier + ANTI_DENORMAL;
+        self.open_amplitude = self.open_amplitude * self.open_multiplier + ANTI_DENORMAL;
+
+        sample
+    }
+}
+
+struct Clap {
+    velocity: f32,
+    position: u32, // samples since trigger
+
+    amplitude: f32,
+    multiplier: f32,
+
+    bandpass: BiQuad
+}
+
+impl Clap {
+    fn new(sample_rate: f32) -> Self {
+        Clap {
+            velocity: 0.0,
+            position: u32::MAX,
+
+            amplitude: 0.0,
+            multiplier: 0.0,
+
+            bandpass: BiQuad::bandpass_constant_peak_gain(1100.0, 1.5, sample_rate)
+        }
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.bandpass = BiQuad::bandpass_constant_peak_gain(1100.0, 1.5, sample_rate);
+    }
+
+    fn trigger(&mut self, velocity: f32) {
+        self.velocity = velocity;
+        self.position = 0;
+    }
+
+    fn render(&mut self, noise: f32, sample_rate: f32) -> f32 {
+        // A clap consists of three short noise bursts followed by a longer tail
+        let burst_length = (0.010 * sample_rate) as u32;
+
+        if self.position < 3 * burst_length && self.position.is_multiple_of(burst_length) {
+            self.amplitude = self.velocity;
+            self.multiplier = decay_multiplier(3.0, sample_rate);
+        } else if self.position == 3 * burst_length {
+            self.amplitude = self.velocity;
+            self.multiplier = decay_multiplier(150.0, sample_rate);
+        }
+
+        self.position = self.position.saturating_add(1);
+
+        let sample = self.bandpass.render(noise) * self.amplitude;
+        self.amplitude = self.amplitude * self.multiplier + ANTI_DENORMAL;
+
+        sample
+    }
+}
+
+pub struct DrumMachine {
+    pub volume: f32, // 0..1
+
+    sample_rate: f32,
+
+    sequencer: Sequencer<DrumStep>, // transpose does not apply to drums
+
+    vm: Rc<RefCell<VM>>,
+
+    noise_state: u32,
+
+    kick: Kick,
+    snare: Snare,
+    hihats: HiHats,
+    clap: Clap
+}
+
+impl DrumMachine {
+    pub fn new(vm: Rc<RefCell<VM>>, sample_rate: f32) -> Self {
+        DrumMachine {
+            volume: 0.8,
+
+            sample_rate,
+
+            sequencer: Sequencer::new(),
+
+            vm,
+
+            noise_state: 0x1234_5678,
+
+            kick: Kick::new(),
+            snare: Snare::new(sample_rate),
+            hihats: HiHats::new(sample_rate),
+            clap: Clap::new(sample_rate)
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+
+        self.snare.set_sample_rate(sample_rate);
+        self.hihats.set_sample_rate(sample_rate);
+        self.clap.set_sample_rate(sample_rate);
+    }
+
+    // The step that plays when the clock starts a step next, 0 starts a new pattern
+    pub fn restart(&mut self, position: usize) {
+        self.sequencer.restart(position);
+    }
+
+    // Plays the step that is due when the clock continues from a position in pulses
+    pub fn relocate(&mut self, pulse: u32) {
+        self.sequencer.relocate(pulse);
+    }
+
+    // Starts the song from its first entry when the next pattern starts
+    pub fn rewind_song(&mut self) {
+        self.sequencer.song.rewind();
+    }
+
+    // Xorshift white noise, deterministic so that renders are reproducible
+    fn noise(&mut self) -> f32 {
+        self.noise_state ^= self.noise_state << 13;
+        self.noise_state ^= self.noise_state >> 17;
+        self.noise_state ^= self.noise_state << 5;
+
+        self.noise_state as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+
+    fn trigger(&mut self, step: DrumStep) {
+        let velocity = (if step.has_accent { 1.0 } else { NORMAL_VELOCITY }) * self.sequencer.step_delay.velocity;
+
+        if step.instruments & KICK != 0 {
+            self.kick.trigger(velocity, self.sample_rate);
+        }
+
+        if step.instruments & SNARE != 0 {
+            self.snare.trigger(velocity, self.sample_rate);
+        }
+
+        if step.instruments & CLOSED_HAT != 0 {
+            self.hihats.trigger_closed(velocity, self.sample_rate);
+        }
+
+        if step.instruments & OPEN_HAT != 0 {
+            self.hihats.trigger_open(velocity, self.sample_rate);
+        }
+
+        if step.instruments & CLAP != 0 {
+            self.clap.trigger(velocity);
+        }
+    }
+
+    pub fn render(&mut self, clock: &Clock) -> f32 {
+        if self.sequencer.update(clock) {
+            if self.sequencer.pattern_position == 0 {
+                self.sequencer.song.report(&mut self.vm.borrow_mut(), self.sequencer.current_pattern(), DRUM_CHANNEL);
+            }
+
+            self.trigger(*self.sequencer.step());
+        }
+
+        let noise = self.noise();
+
+        let mut sample = self.kick.render(self.sample_rate);
+        sample += 0.8 * self.snare.render(noise, self.sample_rate);
+        sample += 0.4 * self.hihats.render(noise);
+        sample += 0.7 * self.clap.render(noise, self.sample_rate);
+
+        sample * self.volume
+    }
+
+    // Current value of a continuous parameter, None for parameters of other subsystems
+    pub fn parameter(&self, id: ParameterId) -> Option<f32> {
+        use ParameterId::*;
+
+        match id {
+            DrumVolume => Some(self.volume),
+            KickTune => Some(self.kick.tune),
+            KickDecay => Some(self.kick.decay),
+            SnareSnappy => Some(self.snare.snappy),
+            HatDecay => Some(self.hihats.decay),
+
+            _ => None
+        }
+    }
+
+    // The value has already been checked against the parameter table
+    pub fn set_parameter(&mut self, id: ParameterId, value: f32) {
+        use ParameterId::*;
+
+        match id {
+            DrumVolume => self.volume = value,
+            KickTune => self.kick.tune = value,
+            KickDecay => self.kick.decay = value,
+            SnareSnappy => self.snare.snappy = value,
+            HatDecay => self.hihats.decay = value,
+
+            _ => ()
+        }
+    }
+
+    // Appends the instructions that restore the state that is not in the parameter table, they
+    // must be sent on the drum channel
+    pub fn snapshot(&self, program: &mut Vec<u8>) {
+        self.sequencer.snapshot(program, encode::set_drum_pattern);
+    }
+
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), ErrorReason> {
+        use Opcode::*;
+
+        match instruction.opcode {
+            SetDrumPatternData => {
+                let (pattern_index, step_index, instruments, flags) = decode::set_drum_pattern_data(instruction.operands());
+                self.sequencer.set_step(pattern_index, step_index, instruments, flags)?;
+            },
+
+            // Pattern index and step count, followed by the instruments and flags of every step
+            SetDrumPattern => self.sequencer.set_pattern(&mut instruction.reader())?,
+
+            // Sent on the drum channel
+            _ => return self.sequencer.execute(&instruction)
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render_peak(drums: &mut DrumMachine, clock: &mut Clock, frames: usize) -> f32 {
+        (0..frames).map(|_| {
+            clock.tick();
+            drums.render(clock).abs()
+        }).fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn test_pattern_triggers_instruments() {
+        let mut clock = Clock::new(44100.0);
+        let mut drums = DrumMachine::new(Rc::new(RefCell::new(VM::new())), 44100.0);
+
+        // The default patterns are empty
+        assert!(render_peak(&mut drums, &mut clock, 1000) < 1.0e-6);
+
+        // At 120 bpm the second step starts after 5512 samples
+        drums.sequencer.set_pattern_data(0, 1, DrumStep { instruments: KICK | CLAP, has_accent: true });
+        assert!(render_peak(&mut drums, &mut clock, 6000) > 0.1);
+    }
+}
@@ -37,6 +37,7 @@ pub struct TBFilter {
     k: f32,
 
     resonance_skewed: f32,
+    sample_rate: f32,
 
     feedback_highpass: OnePole
 }
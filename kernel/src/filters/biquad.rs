@@ -16,6 +16,15 @@ impl BiQuad {
         }
     }
 
+    // Takes over the response of another filter, without resetting the state of this one
+    pub fn set_coefficients(&mut self, other: &BiQuad) {
+        self.b0 = other.b0;
+        self.b1 = other.b1;
+        self.b2 = other.b2;
+        self.a1 = other.a1;
+        self.a2 = other.a2;
+    }
+
     pub fn render(&mut self, x0: f32) -> f32 {
         let y1 = self.b0 * x0 + self.b1 * self.x1 + self.b2 * self.x2 - self.a1 * self.y1 - self.a2 * self.y2 + ANTI_DENORMAL;
 
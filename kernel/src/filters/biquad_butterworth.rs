@@ -1,11 +1,10 @@
 use std::f32::consts::PI;
 
-use crate::kernel::SAMPLE_RATE;
 use super::BiQuad;
 
 impl BiQuad {
-    pub fn lowpass_6db_butterworth(frequency: f32) -> Self {
-        let omega = (2.0 * PI * frequency / SAMPLE_RATE) / 2.0;
+    pub fn lowpass_6db_butterworth(frequency: f32, sample_rate: f32) -> Self {
+        let omega = (2.0 * PI * frequency / sample_rate) / 2.0;
 
         let tan_omega = omega.tan();
         let one_over_a0 = 1.0 / (2.0 + 2.0 * tan_omega);
@@ -23,8 +22,8 @@ impl BiQuad {
         }
     }
 
-    pub fn highpass_6db_butterworth(frequency: f32) -> Self {
-        let omega = (2.0 * PI * frequency / SAMPLE_RATE) / 2.0;
+    pub fn highpass_6db_butterworth(frequency: f32, sample_rate: f32) -> Self {
+        let omega = (2.0 * PI * frequency / sample_rate) / 2.0;
 
         let tan_omega = omega.tan();
         let one_over_a0 = 1.0 / (2.0 + 2.0 * tan_omega);
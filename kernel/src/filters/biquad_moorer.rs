@@ -1,6 +1,5 @@
 use std::f32::consts::{PI, SQRT_2};
 
-use crate::kernel::SAMPLE_RATE;
 use super::BiQuad;
 
 // This file contains BiQuad filters modeled after Moorer's paper "The Manifold Joys of Conformal
@@ -34,13 +33,14 @@ fn bandwidth_to_bandedge_angle(a: f32, bandwidth: f32) -> f32 {
     }
 }
 
+#[allow(clippy::needless_late_init)]
 impl BiQuad {
-    pub fn presence_moorer(frequency: f32, bandwidth: f32, gain: f32) -> Self {
+    pub fn presence_moorer(frequency: f32, bandwidth: f32, gain: f32, sample_rate: f32) -> Self {
         // Presence filter, after Moorer's "The Manifold Joys of Conformal Mapping"
         // Seems to behave like a peaking EQ filter.
         // Note: frequency and bandwidth in Hz, gain in dB
-        let normalized_frequency = frequency / SAMPLE_RATE;
-        let normalized_bandwidth = bandwidth / SAMPLE_RATE;
+        let normalized_frequency = frequency / sample_rate;
+        let normalized_bandwidth = bandwidth / sample_rate;
 
         // Compute warp factor
         let a = (PI * (normalized_frequency - 0.25)).tan();
@@ -88,13 +88,13 @@ impl BiQuad {
         }
     }
 
-    pub fn shelving_moorer(frequency: f32, gain: f32, slope: f32, high_shelf: bool) -> Self {
+    pub fn shelving_moorer(frequency: f32, gain: f32, slope: f32, high_shelf: bool, sample_rate: f32) -> Self {
         // Shelving filter, after Moorer's "The Manifold Joys of Conformal Mapping"
         // Note: frequency in Hz, gain in dB. Set high_shelf to true to get a high shelving filter,
         // or false to get a low shelving one. Shelf slope is specified in decibels per octave. Set
         // to 0.5 * sqrt(2) to have sharpest slope that is still monotonic. Smaller vallues will
         // generate a peak in the response. Larger values will make the slope more gradual.
-        let normalized_frequency = frequency / SAMPLE_RATE;
+        let normalized_frequency = frequency / sample_rate;
 
         // Compute warp factor
         let a = (PI * (normalized_frequency - 0.25)).tan();
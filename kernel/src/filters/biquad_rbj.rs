@@ -1,13 +1,12 @@
 use std::f32::consts::{LN_2, PI};
 
-use crate::kernel::SAMPLE_RATE;
 use super::BiQuad;
 
 // This file contains BiQuad filters modeled after Robert Bristow-Johnson's filter cookbook.
 
 impl BiQuad {
-    pub fn lowpass_12db(frequency: f32, q: f32) -> Self {
-        let omega = 2.0 * PI * frequency / SAMPLE_RATE;
+    pub fn lowpass_12db(frequency: f32, q: f32, sample_rate: f32) -> Self {
+        let omega = 2.0 * PI * frequency / sample_rate;
         let sin_omega = omega.sin();
         let cos_omega = omega.cos();
         let alpha = sin_omega / (2.0 * q);
@@ -27,8 +26,8 @@ impl BiQuad {
         }
     }
 
-    pub fn highpass_12db(frequency: f32, q: f32) -> Self {
-        let omega = 2.0 * PI * frequency / SAMPLE_RATE;
+    pub fn highpass_12db(frequency: f32, q: f32, sample_rate: f32) -> Self {
+        let omega = 2.0 * PI * frequency / sample_rate;
         let sin_omega = omega.sin();
         let cos_omega = omega.cos();
         let alpha = sin_omega / (2.0 * q);
@@ -48,10 +47,10 @@ impl BiQuad {
         }
     }
 
-    pub fn bandpass_constant_skirt_gain(frequency: f32, bandwidth: f32) -> Self {
+    pub fn bandpass_constant_skirt_gain(frequency: f32, bandwidth: f32, sample_rate: f32) -> Self {
         // Constant skirt gain
         // Note: q = peak gain
-        let omega = 2.0 * PI * frequency / SAMPLE_RATE;
+        let omega = 2.0 * PI * frequency / sample_rate;
         let sin_omega = omega.sin();
         let cos_omega = omega.cos();
         let alpha = sin_omega * (0.5 * LN_2 * bandwidth * (omega / sin_omega)).sinh();
@@ -71,9 +70,9 @@ impl BiQuad {
         }
     }
 
-    pub fn bandpass_constant_peak_gain(frequency: f32, bandwidth: f32) -> Self {
+    pub fn bandpass_constant_peak_gain(frequency: f32, bandwidth: f32, sample_rate: f32) -> Self {
         // Constant 0 dB peak gain
-        let omega = 2.0 * PI * frequency / SAMPLE_RATE;
+        let omega = 2.0 * PI * frequency / sample_rate;
         let sin_omega = omega.sin();
         let cos_omega = omega.cos();
         let alpha = sin_omega * (0.5 * LN_2 * bandwidth * (omega / sin_omega)).sinh();
@@ -93,9 +92,9 @@ impl BiQuad {
         }
     }
 
-    pub fn notch(frequency: f32, bandwidth: f32) -> Self {
+    pub fn notch(frequency: f32, bandwidth: f32, sample_rate: f32) -> Self {
         // TODO: Verify correctness
-        let omega = 2.0 * PI * frequency / SAMPLE_RATE;
+        let omega = 2.0 * PI * frequency / sample_rate;
         let sin_omega = omega.sin();
         let cos_omega = omega.cos();
         let alpha = sin_omega * (0.5 * LN_2 * bandwidth * (omega / sin_omega)).sinh();
@@ -115,8 +114,8 @@ impl BiQuad {
         }
     }
 
-    pub fn allpass(frequency: f32, q: f32) -> Self {
-        let omega = 2.0 * PI * frequency / SAMPLE_RATE;
+    pub fn allpass(frequency: f32, q: f32, sample_rate: f32) -> Self {
+        let omega = 2.0 * PI * frequency / sample_rate;
         let sin_omega = omega.sin();
         let cos_omega = omega.cos();
         let alpha = sin_omega / (2.0 * q);
@@ -136,10 +135,10 @@ impl BiQuad {
         }
     }
 
-    pub fn peaking_eq(frequency: f32, gain: f32, bandwidth: f32) -> Self {
+    pub fn peaking_eq(frequency: f32, gain: f32, bandwidth: f32, sample_rate: f32) -> Self {
         // Note: gain is specified in decibels, can be positive or negative
         let a = 10.0_f32.powf(gain / 40.0);
-        let omega = 2.0 * PI * frequency / SAMPLE_RATE;
+        let omega = 2.0 * PI * frequency / sample_rate;
         let sin_omega = omega.sin();
         let cos_omega = omega.cos();
         let alpha = sin_omega * (0.5 * LN_2 * bandwidth * (omega / sin_omega)).sinh();
@@ -159,13 +158,13 @@ impl BiQuad {
         }
     }
 
-    pub fn low_shelf(frequency: f32, gain: f32, shelf_slope: f32) -> Self {
+    pub fn low_shelf(frequency: f32, gain: f32, shelf_slope: f32, sample_rate: f32) -> Self {
         // Note: gain is specified in decibels, can be positive or negative
         // Note: shelf slope is specified in decibels per octave. Set to 0.5 * sqrt(2) to have
         // sharpest slope that is still monotonic. Smaller vallues will generate a peak in the
         // response. Larger values will make the slope more gradual.
         let a = 10.0_f32.powf(gain / 40.0);
-        let omega = 2.0 * PI * frequency / SAMPLE_RATE;
+        let omega = 2.0 * PI * frequency / sample_rate;
         let sin_omega = omega.sin();
         let cos_omega = omega.cos();
         let alpha = 0.5 * sin_omega * ((a + 1.0 / a) * (1.0 / shelf_slope - 1.0) + 2.0).sqrt();
@@ -186,13 +185,13 @@ impl BiQuad {
         }
     }
 
-    pub fn high_shelf(frequency: f32, gain: f32, shelf_slope: f32) -> Self {
+    pub fn high_shelf(frequency: f32, gain: f32, shelf_slope: f32, sample_rate: f32) -> Self {
         // Note: gain is specified in decibels, can be positive or negative
         // Note: shelf slope is specified in decibels per octave. Set to 0.5 * sqrt(2) to have
         // sharpest slope that is still monotonic. Smaller vallues will generate a peak in the
         // response. Larger values will make the slope more gradual.
         let a = 10.0_f32.powf(gain / 40.0);
-        let omega = 2.0 * PI * frequency / SAMPLE_RATE;
+        let omega = 2.0 * PI * frequency / sample_rate;
         let sin_omega = omega.sin();
         let cos_omega = omega.cos();
         let alpha = 0.5 * sin_omega * ((a + 1.0 / a) * (1.0 / shelf_slope - 1.0) + 2.0).sqrt();
@@ -1,11 +1,11 @@
-use crate::kernel::{ANTI_DENORMAL, SAMPLE_RATE};
+use crate::kernel::ANTI_DENORMAL;
 use super::LeakyIntegrator;
 
 impl LeakyIntegrator {
-    pub fn new(decay_time: f32) -> Self {
+    pub fn new(decay_time: f32, sample_rate: f32) -> Self {
         Self {
             // Leaky integrator coefficient for reduction of 1/e in decay_time ms
-            coefficient: (-1.0 / (0.001 * decay_time * SAMPLE_RATE)).exp(),
+            coefficient: (-1.0 / (0.001 * decay_time * sample_rate)).exp(),
             y1: 0.0
         }
     }
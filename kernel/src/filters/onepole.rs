@@ -1,6 +1,6 @@
 use std::f32::consts::PI;
 
-use crate::kernel::{ANTI_DENORMAL, SAMPLE_RATE};
+use crate::kernel::ANTI_DENORMAL;
 use super::OnePole;
 
 impl OnePole {
@@ -14,8 +14,8 @@ impl OnePole {
         }
     }
 
-    pub fn high_pass(cutoff: f32) -> Self {
-        let alpha = (-2.0 * PI * cutoff * (1.0 / SAMPLE_RATE)).exp();
+    pub fn high_pass(cutoff: f32, sample_rate: f32) -> Self {
+        let alpha = (-2.0 * PI * cutoff * (1.0 / sample_rate)).exp();
 
         OnePole {
             x1: 0.0,
@@ -26,8 +26,8 @@ impl OnePole {
         }
     }
 
-    pub fn all_pass(cutoff: f32) -> Self {
-        let tau = (PI * cutoff * (1.0 / SAMPLE_RATE)).tan();
+    pub fn all_pass(cutoff: f32, sample_rate: f32) -> Self {
+        let tau = (PI * cutoff * (1.0 / sample_rate)).tan();
         let alpha = (1.0 - tau) / (1.0 + tau);
 
         OnePole {
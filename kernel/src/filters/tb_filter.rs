@@ -1,10 +1,9 @@
 use std::f32::consts::PI;
 
-use crate::kernel::SAMPLE_RATE;
 use super::{OnePole, TBFilter};
 
 impl TBFilter {
-    pub fn new() -> Self {
+    pub fn new(sample_rate: f32) -> Self {
         TBFilter {
             y0: 0.0,
             y1: 0.0,
@@ -15,10 +14,16 @@ impl TBFilter {
             g: 0.0,
             k: 0.0,
             resonance_skewed: 0.0,
-            feedback_highpass: OnePole::high_pass(150.0)
+            sample_rate,
+            feedback_highpass: OnePole::high_pass(150.0, sample_rate)
         }
     }
 
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.feedback_highpass = OnePole::high_pass(150.0, sample_rate);
+    }
+
     pub fn set_resonance(&mut self, resonance: f32) {
         self.resonance_skewed = (1.0 - (-3.0 * resonance).exp()) / (1.0 - (-3.0_f32).exp());
     }
@@ -27,7 +32,7 @@ impl TBFilter {
     pub fn update_coefficients(&mut self, cutoff: f32) {
         // Recalculate main filter coefficients
         // TODO: optimize into lookup table
-        let wc = ((2.0 * PI) / SAMPLE_RATE) * cutoff;
+        let wc = ((2.0 * PI) / self.sample_rate) * cutoff;
         let fx = wc * 0.11253953951963826; // (1.0 / sqrt(2)) / (2.0 * PI)
 
         self.b0 = (0.00045522346 + 6.1922189 * fx) / (1.0 + 12.358354 * fx + 4.4156345 * (fx * fx));
@@ -0,0 +1,157 @@
+use crate::clock::Clock;
+use crate::vm::{ErrorReason, OperandReader};
+
+pub const MAX_GROOVE_LENGTH: usize = 64;
+
+// Velocity scale of a step in a groove blob that leaves the step unchanged
+const UNITY_VELOCITY: u8 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct GrooveStep {
+    timing: u8, // delay in 1/256 of a step
+    velocity: u8 // in percent
+}
+
+// Timing and velocity variations that all sequencers apply to their steps. Grooves repeat every
+// few steps, counted from the start of the clock so that patterns of any length line up.
+pub struct Groove {
+    pub swing: f32, // delay of every other step, as a fraction of a step
+    steps: Vec<GrooveStep>
+}
+
+impl Groove {
+    pub fn new() -> Self {
+        Groove {
+            swing: 0.0,
+            steps: Vec::with_capacity(MAX_GROOVE_LENGTH)
+        }
+    }
+
+    // Delay of a step in samples. Steps are never delayed into the next step.
+    pub fn delay(&self, step_index: u32, step_length: u32) -> u32 {
+        let mut fraction = if step_index % 2 == 1 { self.swing } else { 0.0 };
+
+        if let Some(step) = self.step(step_index) {
+            fraction += step.timing as f32 / 256.0;
+        }
+
+        ((fraction * step_length as f32) as u32).min(step_length.saturating_sub(1))
+    }
+
+    // Scales the velocity of drums and the accent of voices
+    pub fn velocity(&self, step_index: u32) -> f32 {
+        self.step(step_index).map_or(1.0, |step| step.velocity as f32 / UNITY_VELOCITY as f32)
+    }
+
+    fn step(&self, step_index: u32) -> Option<&GrooveStep> {
+        match self.steps.len() {
+            0 => None,
+            length => Some(&self.steps[step_index as usize % length])
+        }
+    }
+
+    // The delay in 1/256 of a step and the velocity in percent of every step. A groove without
+    // steps turns the groove off, swing is set separately.
+    pub fn decode(&mut self, reader: &mut OperandReader) -> Result<(), ErrorReason> {
+        let data = reader.read_bytes(reader.remaining())?;
+
+        if !data.len().is_multiple_of(2) || data.len() / 2 > MAX_GROOVE_LENGTH {
+            return Err(ErrorReason::OutOfRange);
+        }
+
+        if data.chunks_exact(2).any(|step| step[1] > 2 * UNITY_VELOCITY) {
+            return Err(ErrorReason::OutOfRange);
+        }
+
+        self.steps.clear();
+        self.steps.extend(data.chunks_exact(2).map(|step| GrooveStep { timing: step[0], velocity: step[1] }));
+
+        Ok(())
+    }
+
+    // The inverse of decode
+    pub fn encode(&self) -> Vec<u8> {
+        self.steps.iter().flat_map(|step| [step.timing, step.velocity]).collect()
+    }
+}
+
+impl Default for Groove {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Holds back a step of a sequencer until its groove delay has passed
+pub struct StepDelay {
+    remaining: Option<u32>, // in samples, None when no step is waiting
+    pub velocity: f32 // of the last step
+}
+
+impl StepDelay {
+    pub fn new() -> Self {
+        StepDelay {
+            remaining: None,
+            velocity: 1.0
+        }
+    }
+
+    // Called when a step starts on the clock, pulses is the length of the step
+    pub fn schedule(&mut self, clock: &Clock, pulses: u32) {
+        let step_index = clock.pulse / pulses;
+
+        self.remaining = Some(clock.groove.delay(step_index, clock.step_length(pulses)));
+        self.velocity = clock.groove.velocity(step_index);
+    }
+
+    // Called every sample after schedule, returns true when the step should play
+    pub fn tick(&mut self) -> bool {
+        match self.remaining {
+            Some(0) => {
+                self.remaining = None;
+                true
+            },
+            Some(remaining) => {
+                self.remaining = Some(remaining - 1);
+                false
+            },
+            None => false
+        }
+    }
+}
+
+impl Default for StepDelay {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_groove() {
+        let mut groove = Groove::new();
+        assert_eq!(groove.delay(1, 1000), 0);
+        assert_eq!(groove.velocity(1), 1.0);
+
+        groove.swing = 0.25;
+        assert_eq!((0..4).map(|step| groove.delay(step, 1000)).collect::<Vec<_>>(), vec![0, 250, 0, 250]);
+
+        // A quarter step late on the third of every three steps, with the first one louder
+        let blob = [0, 150, 0, 100, 64, 100];
+        groove.decode(&mut OperandReader::new(&blob)).unwrap();
+        assert_eq!(groove.encode(), blob);
+
+        assert_eq!((0..6).map(|step| groove.delay(step, 1000)).collect::<Vec<_>>(), vec![0, 250, 250, 250, 0, 500]);
+        assert_eq!(groove.velocity(3), 1.5);
+
+        // Steps stay within their own step
+        groove.swing = 0.5;
+        groove.decode(&mut OperandReader::new(&[255, 100])).unwrap();
+        assert_eq!(groove.delay(1, 1000), 999);
+
+        assert_eq!(groove.decode(&mut OperandReader::new(&[0, 201])), Err(ErrorReason::OutOfRange));
+        assert_eq!(groove.decode(&mut OperandReader::new(&[0])), Err(ErrorReason::OutOfRange));
+    }
+}
@@ -0,0 +1,186 @@
+use std::cell::RefCell;
+use std::panic;
+use std::rc::Rc;
+
+// The host is the environment the kernel is running in. In the browser this is the JS side of
+// the AudioWorklet, natively it can be anything from stderr to a buffer that tests inspect.
+pub trait Host {
+    fn log(&self, message: &str);
+    fn error(&self, message: &str);
+}
+
+thread_local! {
+    static HOST: RefCell<Box<dyn Host>> = RefCell::new(default_host());
+}
+
+#[cfg(target_arch = "wasm32")]
+fn default_host() -> Box<dyn Host> {
+    Box::new(WasmHost)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn default_host() -> Box<dyn Host> {
+    Box::new(StderrHost)
+}
+
+pub fn set_host(host: Box<dyn Host>) {
+    HOST.with(|h| *h.borrow_mut() = host);
+}
+
+pub fn log(message: &str) {
+    HOST.with(|h| h.borrow().log(message));
+}
+
+pub fn error(message: &str) {
+    HOST.with(|h| h.borrow().error(message));
+}
+
+// The panic can happen while the host is borrowed, for example when set_host drops the previous
+// host, in which case the message goes to stderr
+fn panic_handler(info: &panic::PanicHookInfo) {
+    let message = info.to_string();
+
+    let _ = HOST.try_with(|h| match h.try_borrow() {
+        Ok(host) => host.error(&message),
+        Err(_) => StderrHost.error(&message)
+    });
+}
+
+pub fn install_panic_hook() {
+    panic::set_hook(Box::new(panic_handler));
+}
+
+#[cfg(target_arch = "wasm32")]
+pub struct WasmHost;
+
+#[cfg(target_arch = "wasm32")]
+extern "C" {
+    #[link_name="console_log"]
+    fn _console_log(a_ptr: *const u8, a_len: usize);
+
+    #[link_name="console_error"]
+    fn _console_error(a_ptr: *const u8, a_len: usize);
+}
+
+#[cfg(target_arch = "wasm32")]
+fn wrap(s: &str, f: unsafe extern "C" fn(*const u8, usize)) {
+    let ptr = s.as_ptr();
+    let len = s.len();
+
+    unsafe {
+        f(ptr, len);
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+impl Host for WasmHost {
+    fn log(&self, message: &str) {
+        wrap(message, _console_log);
+    }
+
+    fn error(&self, message: &str) {
+        wrap(message, _console_error);
+    }
+}
+
+pub struct StderrHost;
+
+impl Host for StderrHost {
+    fn log(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+
+    fn error(&self, message: &str) {
+        eprintln!("error: {}", message);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Level {
+    Log,
+    Error
+}
+
+// Collects all messages so they can be inspected afterwards. Clones share the same buffer, so
+// keep a clone around after passing one to set_host.
+#[derive(Clone, Default)]
+pub struct CaptureHost {
+    messages: Rc<RefCell<Vec<(Level, String)>>>
+}
+
+impl CaptureHost {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn messages(&self) -> Vec<(Level, String)> {
+        self.messages.borrow().clone()
+    }
+
+    pub fn clear(&self) {
+        self.messages.borrow_mut().clear();
+    }
+}
+
+impl Host for CaptureHost {
+    fn log(&self, message: &str) {
+        self.messages.borrow_mut().push((Level::Log, message.to_string()));
+    }
+
+    fn error(&self, message: &str) {
+        self.messages.borrow_mut().push((Level::Error, message.to_string()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_capture_host() {
+        let capture = CaptureHost::new();
+        set_host(Box::new(capture.clone()));
+
+        log("hello");
+        error("world");
+
+        assert_eq!(capture.messages(), vec![
+            (Level::Log, "hello".to_string()),
+            (Level::Error, "world".to_string())
+        ]);
+    }
+
+    struct PanickingHost;
+
+    impl Host for PanickingHost {
+        fn log(&self, _message: &str) {}
+        fn error(&self, _message: &str) {}
+    }
+
+    impl Drop for PanickingHost {
+        fn drop(&mut self) {
+            panic!("dropped");
+        }
+    }
+
+    #[test]
+    fn test_panic_hook() {
+        install_panic_hook();
+
+        let capture = CaptureHost::new();
+        set_host(Box::new(capture.clone()));
+
+        assert!(panic::catch_unwind(|| panic!("boom")).is_err());
+
+        let messages = capture.messages();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].0, Level::Error);
+        assert!(messages[0].1.contains("boom"));
+
+        // A panic while the host is borrowed must not panic again in the hook
+        set_host(Box::new(PanickingHost));
+        assert!(panic::catch_unwind(|| set_host(Box::new(StderrHost))).is_err());
+
+        let _ = panic::take_hook();
+    }
+}
@@ -1,23 +1,71 @@
+use crate::clock::{Clock, ClockSource, PULSES_PER_QUARTER_NOTE, PULSES_PER_STEP};
+use crate::drums::DrumMachine;
+use crate::host;
+use crate::midi::{self, MidiDecoder, MidiEvent, MidiMapping, MidiOutput, ALL_NOTES_OFF, CONTROLLER_COUNT, NO_MAPPING};
+use crate::mixer::Mixer;
+use crate::parameters::{self, ParameterId, PARAMETERS};
 use crate::r303::R303;
-use crate::vm::VM;
+use crate::ramp::{Curve, Ramps, RAMP_INTERVAL};
+use crate::telemetry::{Telemetry, MAX_TELEMETRY_RATE};
+use crate::vm::{check_range, decode, encode, ErrorReason, Instruction, OperandReader, Opcode, ProgramError, ProgramIterator, MAX_PROGRAM_SIZE, VM};
 
 use std::rc::Rc;
 use std::cell::RefCell;
 
-const BUFFER_SIZE: usize = 128;
+// Note: Web Audio API nodes use a fixed 128-sample buffer size per channel
+pub const DEFAULT_MAX_BLOCK_SIZE: usize = 128;
 
 pub const ANTI_DENORMAL: f32 = 1.0e-20;
-pub const SAMPLE_RATE: f32 = 44100.0;
+pub const DEFAULT_SAMPLE_RATE: f32 = 44100.0;
+
+pub const MAX_VOICE_COUNT: usize = 8;
+
+// The drum machine has its own mixer channel after the voices
+pub const DRUM_CHANNEL: usize = MAX_VOICE_COUNT;
+
+// Must be increased whenever the instructions in a snapshot or their meaning change, which
+// includes adding state to snapshots. test_snapshot_version lists the instructions of the
+// current version.
+pub const SNAPSHOT_VERSION: u8 = 8;
 
 pub struct Kernel {
     pub current_sample: u32,
+    pub sample_rate: f32,
+    max_block_size: usize,
 
     pub left_buffer: Vec::<f32>,
     pub right_buffer: Vec::<f32>,
 
     pub vm: Rc<RefCell<VM>>,
 
-    r303: R303
+    // Copy of the incoming program, so that the VM can be borrowed for outgoing messages while it runs
+    program: Vec<u8>,
+
+    clock: Clock,
+
+    // Voice instructions are sent to the channel selected by the last SetChannel instruction
+    channel: usize,
+    voices: Vec<R303>,
+
+    drums: DrumMachine,
+
+    mixer: Mixer,
+
+    ramps: Ramps,
+    smoothing_time: f32, // in ms
+
+    // MIDI channels play the voice with the same index
+    midi_decoder: MidiDecoder,
+    midi_mapping: MidiMapping,
+    midi_output: MidiOutput,
+
+    // The frame in the block that the instructions being executed take effect at
+    frame: usize,
+
+    telemetry: Telemetry,
+
+    // Snapshots are validated on this kernel before they are restored, see restore
+    staging: Option<Box<Kernel>>
 }
 
 impl Kernel {
@@ -26,43 +74,1476 @@ impl Kernel {
 
         Kernel {
             current_sample: 0,
+            sample_rate: DEFAULT_SAMPLE_RATE,
+            max_block_size: DEFAULT_MAX_BLOCK_SIZE,
+
+            left_buffer: vec![0.0; DEFAULT_MAX_BLOCK_SIZE],
+            right_buffer: vec![0.0; DEFAULT_MAX_BLOCK_SIZE],
+
+            program: Vec::with_capacity(MAX_PROGRAM_SIZE),
+
+            clock: Clock::new(DEFAULT_SAMPLE_RATE),
 
-            left_buffer: vec![0.0; BUFFER_SIZE],
-            right_buffer: vec![0.0; BUFFER_SIZE],
+            channel: 0,
+            voices: vec![R303::new(Rc::clone(&vm), 0, DEFAULT_SAMPLE_RATE)],
+
+            drums: DrumMachine::new(Rc::clone(&vm), DEFAULT_SAMPLE_RATE),
+
+            mixer: Mixer::new(DEFAULT_SAMPLE_RATE),
+
+            ramps: Ramps::new(),
+            smoothing_time: 0.0,
+
+            midi_decoder: MidiDecoder::new(),
+            midi_mapping: MidiMapping::new(),
+            midi_output: MidiOutput::new(),
+
+            frame: 0,
+
+            telemetry: Telemetry::new(DEFAULT_SAMPLE_RATE),
+
+            staging: None,
 
-            r303: R303::new(Rc::clone(&vm)),
             vm
         }
     }
 
-    pub fn initialize(&mut self) {
+    pub fn initialize(&mut self, sample_rate: f32) {
+        // Built first, so that the wavetable of the voices ends up cached at this sample rate
+        self.staging = Some(Box::new(Self::staging(sample_rate)));
+
         self.current_sample = 0;
+        self.set_sample_rate(sample_rate);
+    }
+
+    // A kernel with every voice, so that restoring a snapshot on it never allocates
+    fn staging(sample_rate: f32) -> Self {
+        let mut staging = Kernel::new();
+
+        staging.set_voice_count(MAX_VOICE_COUNT);
+        staging.set_sample_rate(sample_rate);
+
+        staging
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+
+        self.clock.set_sample_rate(sample_rate);
+
+        for voice in self.voices.iter_mut() {
+            voice.set_sample_rate(sample_rate);
+        }
+
+        self.drums.set_sample_rate(sample_rate);
+        self.mixer.set_sample_rate(sample_rate);
+        self.telemetry.set_sample_rate(sample_rate);
+    }
+
+    pub fn set_voice_count(&mut self, voice_count: usize) {
+        let voice_count = voice_count.clamp(1, MAX_VOICE_COUNT);
+
+        self.voices.truncate(voice_count);
+
+        while self.voices.len() < voice_count {
+            let channel = self.voices.len();
+            self.voices.push(R303::new(Rc::clone(&self.vm), channel, self.sample_rate));
+        }
+    }
+
+    pub fn voice_count(&self) -> usize {
+        self.voices.len()
+    }
+
+    // Note: this reallocates the output buffers, so pointers to them must be fetched again
+    pub fn set_max_block_size(&mut self, max_block_size: usize) {
+        self.max_block_size = max_block_size;
+
+        self.left_buffer = vec![0.0; max_block_size];
+        self.right_buffer = vec![0.0; max_block_size];
+    }
+
+    pub fn max_block_size(&self) -> usize {
+        self.max_block_size
+    }
+
+    pub fn tempo(&self) -> f32 {
+        self.clock.tempo()
+    }
+
+    // Current value of a parameter of a channel, None for voices that do not exist
+    fn parameter(&self, channel: usize, id: ParameterId) -> Option<f32> {
+        use ParameterId::*;
+
+        match id {
+            Tempo => Some(self.clock.tempo()),
+            Swing => Some(self.clock.groove.swing),
+
+            DrumVolume | KickTune | KickDecay | SnareSnappy | HatDecay => self.drums.parameter(id),
+
+            ChannelGain | ChannelPan | DelaySend |
+            DelayFeedback | DelayPingPong |
+            MasterWidth | MasterGain | MasterLowShelf | MasterHighShelf => self.mixer.parameter(channel, id),
+
+            _ => self.voices.get(channel).and_then(|voice| voice.parameter(id))
+        }
+    }
+
+    fn is_global_parameter(id: ParameterId) -> bool {
+        use ParameterId::*;
+
+        matches!(id,
+            Tempo | Swing | DrumVolume | KickTune | KickDecay | SnareSnappy | HatDecay |
+            DelayFeedback | DelayPingPong |
+            MasterWidth | MasterGain | MasterLowShelf | MasterHighShelf)
     }
 
-    pub fn process(&mut self, program_size: u32) -> u32 {
+    // Ramps of parameters that are not tied to a channel are all kept on channel 0, so that
+    // there is only ever one of them
+    fn ramp_channel(&self, id: ParameterId) -> usize {
+        if Self::is_global_parameter(id) { 0 } else { self.channel }
+    }
+
+    // Sets a parameter on the current channel right away. Every way of setting a parameter ends up
+    // here, so the range from the parameter table applies to all of them.
+    fn set_parameter(&mut self, id: ParameterId, value: f32) -> Result<(), ErrorReason> {
+        use ParameterId::*;
+
+        let value = id.parameter().check(value)?;
+
+        match id {
+            Tempo => self.clock.set_tempo(value),
+            Swing => self.clock.groove.swing = value,
+
+            DrumVolume | KickTune | KickDecay | SnareSnappy | HatDecay => self.drums.set_parameter(id, value),
+
+            ChannelGain | ChannelPan | DelaySend |
+            DelayFeedback | DelayPingPong |
+            MasterWidth | MasterGain | MasterLowShelf | MasterHighShelf => self.mixer.set_parameter(self.channel, id, value),
+
+            _ => {
+                // Parameters of voices that do not exist are ignored
+                if let Some(voice) = self.voices.get_mut(self.channel) {
+                    voice.set_parameter(id, value);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Plain changes to parameters are smoothed, or stop the ramp that was running otherwise
+    fn change_parameter(&mut self, id: ParameterId, value: f32) -> Result<(), ErrorReason> {
+        if self.smoothing_time > 0.0 {
+            return self.start_ramp(id, value, self.smoothing_time, Curve::Linear);
+        }
+
+        self.ramps.cancel(self.ramp_channel(id), id);
+        self.set_parameter(id, value)
+    }
+
+    fn start_ramp(&mut self, id: ParameterId, target: f32, duration: f32, curve: Curve) -> Result<(), ErrorReason> {
+        let target = id.parameter().check(target)?;
+
+        let value = match self.parameter(self.channel, id) {
+            Some(value) => value,
+
+            // Parameters of voices that do not exist are ignored
+            None => return Ok(())
+        };
+
+        let frame_count = (0.001 * duration * self.sample_rate) as usize;
+        self.ramps.start(self.ramp_channel(id), id, value, target, frame_count, curve);
+
+        Ok(())
+    }
+
+    // The parameter and value of SetParameter and its aliases, None for other instructions
+    fn parameter_change(instruction: &Instruction) -> Result<Option<(ParameterId, f32)>, ErrorReason> {
+        match instruction.opcode {
+            Opcode::SetParameter => {
+                let (id, value) = decode::set_parameter(instruction.operands());
+                Ok(Some((ParameterId::decode(id)?, value)))
+            },
+
+            opcode => match parameters::find_by_alias(opcode) {
+                Some(id) => Ok(Some((id, instruction.reader().read_f32()?))),
+                None => Ok(None)
+            }
+        }
+    }
+
+    // Advances all ramps, the parameters are set on the channel the ramp was started on
+    fn update_ramps(&mut self, frame_count: usize) {
+        let mut ramps = std::mem::take(&mut self.ramps);
+        let channel = self.channel;
+
+        ramps.advance(frame_count, |ramp_channel, id, value| {
+            self.channel = ramp_channel;
+
+            // The target was checked when the ramp started, so every value on the way is valid
+            let _ = self.set_parameter(id, value);
+        });
+
+        self.channel = channel;
+        self.ramps = ramps;
+    }
+
+    fn handle_midi(&mut self, event: MidiEvent) {
+        // The transport only follows MIDI when the clock does
+        let is_following = self.clock.source() == ClockSource::Midi;
+
+        match event {
+            MidiEvent::NoteOn { channel, note, velocity } => {
+                if let Some(voice) = self.voices.get_mut(channel as usize) {
+                    voice.note_on(note, velocity);
+                }
+            },
+
+            MidiEvent::NoteOff { channel, note } => {
+                if let Some(voice) = self.voices.get_mut(channel as usize) {
+                    voice.note_off(note);
+                }
+            },
+
+            MidiEvent::ControlChange { channel, controller: ALL_NOTES_OFF, .. } => {
+                if let Some(voice) = self.voices.get_mut(channel as usize) {
+                    voice.all_notes_off();
+                }
+            },
+
+            // Mapped controllers are smoothed like any other change to a parameter
+            MidiEvent::ControlChange { channel, controller, value } => {
+                let id = match self.midi_mapping.get(controller) {
+                    Some(id) if channel as usize <= DRUM_CHANNEL => id,
+                    _ => return
+                };
+
+                let value = id.parameter().value_at(value as f32 / 127.0);
+                let previous_channel = self.channel;
+
+                // Values in the range of the parameter are always valid
+                self.channel = channel as usize;
+                let _ = self.change_parameter(id, value);
+                self.channel = previous_channel;
+            },
+
+            MidiEvent::Clock => self.clock.external_pulse(),
+            MidiEvent::Start if is_following => self.start_transport(),
+            MidiEvent::Continue if is_following => self.continue_transport(),
+            MidiEvent::Stop if is_following => self.stop_transport(),
+            MidiEvent::SongPosition { position } if is_following => self.relocate(position as f64 * PULSES_PER_STEP as f64),
+            MidiEvent::Start | MidiEvent::Continue | MidiEvent::Stop | MidiEvent::SongPosition { .. } => ()
+        }
+    }
+
+    fn execute(&mut self, instruction: Instruction) -> Result<(), ErrorReason> {
+        use Opcode::*;
+
+        if let Some((id, value)) = Self::parameter_change(&instruction)? {
+            return self.change_parameter(id, value);
+        }
+
+        match instruction.opcode {
+            RequestSnapshot => {
+                let snapshot = self.snapshot();
+                self.vm.borrow_mut().push_blob(Snapshot, &snapshot);
+            },
+
+            RestoreSnapshot => {
+                let mut reader = instruction.reader();
+                return self.restore(reader.read_bytes(reader.remaining())?);
+            },
+
+            MidiInput => {
+                let mut reader = instruction.reader();
+
+                for &byte in reader.read_bytes(reader.remaining())? {
+                    if let Some(event) = self.midi_decoder.decode(byte) {
+                        self.handle_midi(event);
+                    }
+                }
+            },
+
+            // Mappings are configuration of the host, so they are not part of snapshots
+            SetMidiMapping => {
+                let mut reader = instruction.reader();
+
+                let controller = reader.read_u8()?;
+                let id = match reader.read_u8()? {
+                    NO_MAPPING => None,
+                    id => Some(ParameterId::decode(id)?)
+                };
+
+                if controller as usize >= CONTROLLER_COUNT {
+                    return Err(ErrorReason::OutOfRange);
+                }
+
+                self.midi_mapping.set(controller, id);
+            },
+
+            DescribeParameters => {
+                let mut vm = self.vm.borrow_mut();
+
+                for parameter in PARAMETERS.iter() {
+                    parameter.describe(&mut vm);
+                }
+            },
+
+            // The reply is preceded by the channel, just like telemetry
+            GetParameter => {
+                let id = ParameterId::decode(decode::get_parameter(instruction.operands()))?;
+
+                // Parameters of voices that do not exist are not reported
+                if let Some(value) = self.parameter(self.channel, id) {
+                    let mut operands = [id as u8, 0, 0, 0, 0];
+                    operands[1..].copy_from_slice(&value.to_le_bytes());
+
+                    let mut vm = self.vm.borrow_mut();
+                    vm.push_u32(SetChannel, self.channel as u32);
+                    vm.push_operands(ParameterValue, &operands);
+                }
+            },
+
+            RampParameter => {
+                let mut reader = instruction.reader();
+
+                let id = ParameterId::decode(reader.read_u8()?)?;
+                let curve = Curve::decode(reader.read_u8()?)?;
+                let target = reader.read_f32()?;
+                let duration = reader.read_f32()?;
+
+                if duration < 0.0 {
+                    return Err(ErrorReason::OutOfRange);
+                }
+
+                return self.start_ramp(id, target, duration, curve);
+            },
+
+            _ => return self.dispatch(instruction)
+        }
+
+        Ok(())
+    }
+
+    // The snapshot version and voice count, followed by a program that restores the state of the
+    // kernel. Ramps are captured at their current value.
+    fn snapshot(&self) -> Vec<u8> {
+        let mut program = vec![SNAPSHOT_VERSION, self.voices.len() as u8];
+
+        encode::set_smoothing_time(&mut program, self.smoothing_time);
+        encode::set_telemetry_rate(&mut program, self.telemetry.rate());
+        self.mixer.snapshot(&mut program);
+        encode::set_groove(&mut program, &self.clock.groove.encode());
+
+        for channel in 0..=DRUM_CHANNEL {
+            encode::set_channel(&mut program, channel as u32);
+
+            for parameter in PARAMETERS.iter() {
+                if Self::is_global_parameter(parameter.id) && channel != 0 {
+                    continue;
+                }
+
+                if let Some(value) = self.parameter(channel, parameter.id) {
+                    encode::set_parameter(&mut program, parameter.id as u8, value);
+                }
+            }
+
+            self.mixer.snapshot_channel(channel, &mut program);
+
+            if let Some(voice) = self.voices.get(channel) {
+                voice.snapshot(&mut program);
+            }
+
+            if channel == DRUM_CHANNEL {
+                self.drums.snapshot(&mut program);
+            }
+        }
+
+        program
+    }
+
+    // Restores a snapshot completely or not at all. The snapshot is applied to the staging kernel
+    // first, so that an instruction that fails leaves the state of this kernel untouched. The
+    // staging kernel keeps every voice, so instructions for voices that the snapshot does not
+    // have must be valid as well.
+    fn restore(&mut self, snapshot: &[u8]) -> Result<(), ErrorReason> {
+        let mut reader = OperandReader::new(snapshot);
+
+        if reader.read_u8()? != SNAPSHOT_VERSION {
+            return Err(ErrorReason::OutOfRange);
+        }
+
+        let voice_count = reader.read_u8()? as usize;
+
+        if !(1..=MAX_VOICE_COUNT).contains(&voice_count) {
+            return Err(ErrorReason::OutOfRange);
+        }
+
+        let program = reader.read_bytes(reader.remaining())?;
+
+        // Only kernels that were never initialized build one here
+        let sample_rate = self.sample_rate;
+        let staging = self.staging.get_or_insert_with(|| Box::new(Self::staging(sample_rate)));
+
+        let result = staging.apply_snapshot(program);
+        staging.vm.borrow_mut().drain();
+        result?;
+
+        // The snapshot sets all of the state it depends on, so it applies here as well
+        let channel = self.channel;
+
+        self.set_voice_count(voice_count);
+        let result = self.apply_snapshot(program);
+
+        self.channel = channel;
+        self.ramps = Ramps::new();
+
+        result
+    }
+
+    // Snapshots start out on channel 0, like a new kernel
+    fn apply_snapshot(&mut self, program: &[u8]) -> Result<(), ErrorReason> {
+        self.channel = 0;
+
+        for instruction in ProgramIterator::new(program) {
+            self.dispatch(instruction.map_err(|error| error.reason)?)?;
+        }
+
+        Ok(())
+    }
+
+    fn report_transport(&self) {
+        self.vm.borrow_mut().push_u32(Opcode::SetTransportState, self.clock.is_running() as u32);
+    }
+
+    // Sends MIDI at the current frame while the kernel is the clock master
+    fn send_midi(&mut self, bytes: &[u8]) {
+        if self.clock.source() == ClockSource::Internal {
+            self.midi_output.send(self.frame, bytes);
+        }
+    }
+
+    fn start_transport(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.rewind_song();
+            voice.restart(0);
+        }
+
+        self.drums.rewind_song();
+        self.drums.restart(0);
+
+        self.clock.start();
+        self.report_transport();
+        self.send_midi(&[midi::START]);
+    }
+
+    fn stop_transport(&mut self) {
+        for voice in self.voices.iter_mut() {
+            voice.stop();
+        }
+
+        self.clock.stop();
+        self.report_transport();
+        self.send_midi(&[midi::STOP]);
+    }
+
+    // Followers are told where the clock continues from first
+    fn continue_transport(&mut self) {
+        let position = self.clock.song_position() & 0x3fff;
+
+        self.clock.resume();
+        self.report_transport();
+        self.send_midi(&[midi::SONG_POSITION, (position & 0x7f) as u8, (position >> 7) as u8]);
+        self.send_midi(&[midi::CONTINUE]);
+    }
+
+    // Moves the clock and all sequencers to a position in pulses
+    fn relocate(&mut self, position: f64) {
+        self.clock.set_position(position);
+
+        let pulse = position.ceil() as u32;
+
+        for voice in self.voices.iter_mut() {
+            voice.relocate(pulse);
+        }
+
+        self.drums.relocate(pulse);
+    }
+
+    // Executes an instruction right away, without smoothing
+    fn dispatch(&mut self, instruction: Instruction) -> Result<(), ErrorReason> {
+        use Opcode::*;
+
+        if let Some((id, value)) = Self::parameter_change(&instruction)? {
+            return self.set_parameter(id, value);
+        }
+
+        match instruction.opcode {
+            StartTransport => self.start_transport(),
+            StopTransport => self.stop_transport(),
+            ContinueTransport => self.continue_transport(),
+
+            // Takes effect right away while running, or when the transport continues
+            ResetTransport => {
+                let position = decode::reset_transport(instruction.operands()) as usize;
+
+                for voice in self.voices.iter_mut() {
+                    voice.restart(position);
+                }
+
+                self.drums.restart(position);
+                self.clock.reset();
+            },
+
+            // The clock source and MIDI output are configuration of the host, so they are not
+            // part of snapshots
+            SetClockSource => self.clock.set_source(ClockSource::decode(check_range(decode::set_clock_source(instruction.operands()), 0..=u8::MAX as u32)? as u8)?),
+            SetMidiClockOutput => self.midi_output.is_enabled = check_range(decode::set_midi_clock_output(instruction.operands()), 0..=1_u32)? == 1,
+
+            // Small differences are caught up with smoothly, larger ones make the clock jump
+            SetHostTransport => {
+                let (tempo, position) = decode::set_host_transport(instruction.operands());
+
+                let tempo = check_range(tempo, 1.0..)?;
+                let position = check_range(position, 0.0..)? as f64 * PULSES_PER_QUARTER_NOTE as f64;
+
+                if self.clock.source() == ClockSource::Host && !self.clock.follow(tempo, position) {
+                    self.relocate(position);
+                }
+            },
+
+            SetGroove => self.clock.groove.decode(&mut instruction.reader())?,
+            SetChannel => self.channel = check_range(decode::set_channel(instruction.operands()), 0..=DRUM_CHANNEL as u32)? as usize,
+
+            // Scheduling is handled by process
+            SetFrameOffset => (),
+
+            SetSmoothingTime => self.smoothing_time = check_range(decode::set_smoothing_time(instruction.operands()), 0.0..)?,
+            SetTelemetryRate => self.telemetry.set_rate(check_range(decode::set_telemetry_rate(instruction.operands()), 0.0..=MAX_TELEMETRY_RATE)?),
+
+            SetDrumPatternData | SetDrumPattern => return self.drums.execute(instruction),
+            SetCurrentPattern | SetNextPattern | SetSong | QueueSongEntry | SetPatternLength | SetPatternResolution if self.channel == DRUM_CHANNEL => return self.drums.execute(instruction),
+
+            SetChannelMute | SetChannelSolo | SetDelayLength => return self.mixer.execute(self.channel, instruction),
+
+            _ => {
+                // Instructions for channels that do not exist are ignored
+                if let Some(voice) = self.voices.get_mut(self.channel) {
+                    return voice.execute(instruction);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    // Executes an instruction, instructions that could not be decoded or executed are reported
+    // back to the frontend and skipped
+    fn execute_or_report(&mut self, instruction: Result<Instruction, ProgramError>) {
+        let result = instruction.and_then(|instruction| {
+            let offset = instruction.offset;
+            let opcode = instruction.opcode as u8;
+
+            self.execute(instruction).map_err(|reason| ProgramError { offset, opcode, reason })
+        });
+
+        if let Err(error) = result {
+            self.vm.borrow_mut().push_u32(Opcode::DecodeError, error.encode());
+        }
+    }
+
+    // Renders frames start..end into the output buffers. While ramps are running, rendering is
+    // split up so that they are updated at the control rate.
+    fn render(&mut self, start: usize, end: usize) {
+        let mut frame = start;
+
+        while frame < end {
+            if self.ramps.is_empty() {
+                self.render_frames(frame, end);
+                break;
+            }
+
+            let chunk_end = (frame + RAMP_INTERVAL).min(end);
+
+            self.update_ramps(chunk_end - frame);
+            self.render_frames(frame, chunk_end);
+
+            frame = chunk_end;
+        }
+    }
+
+    fn render_frames(&mut self, start: usize, end: usize) {
+        let is_master = self.clock.source() == ClockSource::Internal;
+
+        for i in start..end {
+            self.clock.tick();
+
+            if self.clock.pulse_started && is_master {
+                self.midi_output.send(i, &[midi::CLOCK]);
+            }
+
+            for (channel, voice) in self.voices.iter_mut().enumerate() {
+                self.mixer.add(channel, voice.render(&self.clock));
+            }
+
+            let sample = self.drums.render(&self.clock);
+            self.mixer.add(DRUM_CHANNEL, (sample, sample));
+
+            let (left, right) = self.mixer.render();
+            self.telemetry.add(left, right);
+
+            self.left_buffer[i] = left;
+            self.right_buffer[i] = right;
+
+            self.current_sample += 1;
+        }
+    }
+
+    // Renders frame_count frames into the output buffers and returns the size of the outbound
+    // messages. Blocks larger than the maximum block size are rejected: nothing is executed or
+    // rendered and the output buffers are silenced. Instructions take effect at the frame set by
+    // the last SetFrameOffset instruction before them, or at the start of the block if there is
+    // none. Offsets must not decrease, offsets past the end of the block take effect after the
+    // last frame.
+    pub fn process(&mut self, program_size: u32, frame_count: usize) -> u32 {
+        if frame_count > self.max_block_size {
+            host::error(&format!("Block of {} frames exceeds the maximum block size of {}", frame_count, self.max_block_size));
+
+            self.left_buffer.fill(0.0);
+            self.right_buffer.fill(0.0);
+
+            return 0;
+        }
+
+        let mut program = std::mem::take(&mut self.program);
+
         {
             let mut vm = self.vm.borrow_mut();
 
-            // Execute VM opcodes
-            vm.set_position(program_size as usize);
+            vm.set_inbound_size(program_size as usize);
 
-            for instruction in vm.into_iter() {
-                self.r303.execute(instruction);
-            }
+            program.clear();
+            program.extend_from_slice(vm.inbound());
 
             vm.drain();
         }
 
-        // Fill audio buffer
-        for i in 0..BUFFER_SIZE {
-            let sample = self.r303.render();
+        let mut instructions = ProgramIterator::new(&program);
+        let mut frame = 0;
 
-            self.left_buffer[i] = sample;
-            self.right_buffer[i] = sample;
+        while frame < frame_count {
+            // Execute VM opcodes up to the next frame offset in this block
+            let mut end = frame_count;
+            self.frame = frame;
 
-            self.current_sample += 1;
+            for instruction in &mut instructions {
+                match instruction {
+                    Ok(instruction) if matches!(instruction.opcode, Opcode::SetFrameOffset) => {
+                        let offset = decode::set_frame_offset(instruction.operands()) as usize;
+
+                        if offset > frame {
+                            end = offset.min(frame_count);
+                            break;
+                        }
+                    }
+
+                    _ => self.execute_or_report(instruction)
+                }
+            }
+
+            // Fill audio buffer
+            self.render(frame, end);
+            frame = end;
+        }
+
+        // Execute VM opcodes scheduled after the end of the block
+        self.frame = frame_count;
+
+        for instruction in instructions {
+            self.execute_or_report(instruction);
+        }
+
+        self.program = program;
+
+        self.midi_output.flush(&mut self.vm.borrow_mut());
+        self.telemetry.report(&mut self.vm.borrow_mut(), &self.voices);
+
+        return self.vm.borrow().outbound().len() as u32;
+    }
+}
+
+impl Default for Kernel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::host::{self, CaptureHost, Level};
+    use crate::sequencer::{StepResolution, MAX_PATTERN_COUNT, MAX_PATTERN_LENGTH};
+    use crate::song::NO_ENTRY;
+    use crate::vm::{encode, MAX_OUTBOUND_SIZE};
+
+    #[test]
+    fn test_process_natively() {
+        let capture = CaptureHost::new();
+        host::set_host(Box::new(capture.clone()));
+
+        let mut kernel = Kernel::new();
+        kernel.initialize(DEFAULT_SAMPLE_RATE);
+
+        // The first block starts the first step, which is reported back to the host
+        assert!(kernel.process(0, DEFAULT_MAX_BLOCK_SIZE) > 0);
+        assert!(kernel.left_buffer.iter().any(|&sample| sample != 0.0));
+        assert!(capture.messages().is_empty());
+
+        // The exported log functions reach the native host
+        crate::log("hello");
+        crate::error("world");
+
+        assert_eq!(capture.messages(), vec![
+            (Level::Log, "hello".to_string()),
+            (Level::Error, "world".to_string())
+        ]);
+    }
+
+    #[test]
+    fn test_variable_block_size() {
+        let capture = CaptureHost::new();
+        host::set_host(Box::new(capture.clone()));
+
+        let mut kernel = Kernel::new();
+        kernel.set_max_block_size(512);
+
+        kernel.process(0, 300);
+        assert_eq!(kernel.current_sample, 300);
+        assert!(capture.messages().is_empty());
+
+        // Blocks larger than the maximum are rejected and leave silence in the buffers
+        assert_eq!(kernel.process(0, 1000), 0);
+        assert_eq!(kernel.current_sample, 300);
+        assert!(kernel.left_buffer.iter().chain(&kernel.right_buffer).all(|&sample| sample == 0.0));
+        assert_eq!(capture.messages().len(), 1);
+        assert_eq!(capture.messages()[0].0, Level::Error);
+    }
+
+    #[test]
+    fn test_voices_share_clock() {
+        let mut single = Kernel::new();
+        single.process(0, DEFAULT_MAX_BLOCK_SIZE);
+
+        let mut dual = Kernel::new();
+        dual.set_voice_count(2);
+
+        // Instructions for a channel that does not exist are ignored
+        let mut program = vec![Opcode::SetChannel as u8];
+        program.extend_from_slice(&7_u32.to_le_bytes());
+        program.push(Opcode::SetCutoff as u8);
+        program.extend_from_slice(&2000.0_f32.to_le_bytes());
+
+        let program_size = dual.vm.borrow_mut().load(&program);
+        dual.process(program_size as u32, DEFAULT_MAX_BLOCK_SIZE);
+
+        // Both voices play the same default pattern in lockstep
+        for i in 0..DEFAULT_MAX_BLOCK_SIZE {
+            assert_eq!(dual.left_buffer[i], 2.0 * single.left_buffer[i]);
+        }
+    }
+
+    #[test]
+    fn test_frame_offset() {
+        let mut reference = Kernel::new();
+        reference.process(0, DEFAULT_MAX_BLOCK_SIZE);
+
+        let mut kernel = Kernel::new();
+
+        // Mute the voice halfway through the first block, the second mute is scheduled past the end
+        let mut program = vec![Opcode::SetFrameOffset as u8];
+        program.extend_from_slice(&64_u32.to_le_bytes());
+        program.push(Opcode::SetChannelMute as u8);
+        program.extend_from_slice(&1_u32.to_le_bytes());
+        program.push(Opcode::SetFrameOffset as u8);
+        program.extend_from_slice(&1000_u32.to_le_bytes());
+        program.push(Opcode::SetChannelMute as u8);
+        program.extend_from_slice(&0_u32.to_le_bytes());
+
+        let program_size = kernel.vm.borrow_mut().load(&program);
+        kernel.process(program_size as u32, DEFAULT_MAX_BLOCK_SIZE);
+
+        assert_eq!(kernel.left_buffer[..64], reference.left_buffer[..64]);
+        assert!(kernel.left_buffer[64..].iter().all(|&sample| sample.abs() < 1.0e-6));
+
+        // The voice was unmuted after the last frame
+        kernel.process(0, DEFAULT_MAX_BLOCK_SIZE);
+        assert!(kernel.left_buffer.iter().any(|&sample| sample.abs() > 1.0e-6));
+    }
+
+    #[test]
+    fn test_telemetry() {
+        let mut kernel = Kernel::new();
+        kernel.set_voice_count(2);
+
+        let mut peak = None;
+        let mut cutoffs = Vec::new();
+
+        // One report is sent for every 735 frames at the default rate of 60 Hz
+        for _ in 0..8 {
+            kernel.process(0, DEFAULT_MAX_BLOCK_SIZE);
+
+            for instruction in kernel.vm.borrow().outbound_instructions().flatten() {
+                match instruction.opcode {
+                    Opcode::SetPeakLeft => peak = Some(decode::set_peak_left(instruction.operands())),
+                    Opcode::SetEffectiveCutoff => cutoffs.push(decode::set_effective_cutoff(instruction.operands())),
+                    _ => ()
+                }
+            }
+        }
+
+        assert!(peak.unwrap() > 0.0);
+        assert_eq!(cutoffs.len(), 2);
+        assert!(cutoffs[0] > 0.0);
+
+        // Reports are disabled with a rate of 0
+        let mut program = vec![Opcode::SetTelemetryRate as u8];
+        program.extend_from_slice(&0.0_f32.to_le_bytes());
+
+        let program_size = kernel.vm.borrow_mut().load(&program);
+        kernel.process(program_size as u32, 0);
+
+        for _ in 0..8 {
+            kernel.process(0, DEFAULT_MAX_BLOCK_SIZE);
+            assert!(!kernel.vm.borrow().outbound_instructions().flatten().any(|instruction| matches!(instruction.opcode, Opcode::SetPeakLeft)));
         }
+    }
+
+    #[test]
+    fn test_report_errors() {
+        let mut kernel = Kernel::new();
+
+        // A pattern index past the last pattern, followed by a valid instruction
+        let mut program = vec![Opcode::SetPatternData as u8];
+        program.extend_from_slice(&[200, 0, 36, 1]);
+        program.push(Opcode::SetCutoff as u8);
+        program.extend_from_slice(&1000.0_f32.to_le_bytes());
+
+        let program_size = kernel.vm.borrow_mut().load(&program);
+        kernel.process(program_size as u32, 0);
+
+        let errors: Vec<Option<ProgramError>> = kernel.vm.borrow().outbound_instructions().flatten()
+            .filter(|instruction| matches!(instruction.opcode, Opcode::DecodeError))
+            .map(|instruction| ProgramError::decode(decode::decode_error(instruction.operands())))
+            .collect();
+
+        assert_eq!(errors, vec![
+            Some(ProgramError { offset: 0, opcode: Opcode::SetPatternData as u8, reason: ErrorReason::OutOfRange })
+        ]);
+
+        // Continuous parameters outside of their range
+        let cases = [
+            (Opcode::SetCutoff, 10.0),
+            (Opcode::SetResonance, 1.5),
+            (Opcode::SetEnvMod, -0.1),
+            (Opcode::SetAccent, 2.0),
+            (Opcode::SetDelayFeedback, 1.2),
+            (Opcode::SetPan, -1.5),
+            (Opcode::SetDrumVolume, 1.1),
+            (Opcode::SetKickTune, 1000.0),
+            (Opcode::SetKickDecay, 0.0),
+            (Opcode::SetTelemetryRate, -1.0)
+        ];
+
+        for (opcode, value) in cases {
+            let mut program = vec![opcode as u8];
+            program.extend_from_slice(&f32::to_le_bytes(value));
+
+            let program_size = kernel.vm.borrow_mut().load(&program);
+            kernel.process(program_size as u32, 0);
+
+            let errors: Vec<Option<ProgramError>> = kernel.vm.borrow().outbound_instructions().flatten()
+                .filter(|instruction| matches!(instruction.opcode, Opcode::DecodeError))
+                .map(|instruction| ProgramError::decode(decode::decode_error(instruction.operands())))
+                .collect();
+
+            assert_eq!(errors, vec![Some(ProgramError { offset: 0, opcode: opcode as u8, reason: ErrorReason::OutOfRange })], "opcode {}", opcode as u8);
+        }
+    }
+
+    #[test]
+    fn test_pattern_blob() {
+        let steps: Vec<[u8; 2]> = (0..16).map(|i| [36 + i, if i % 3 == 0 { 0b11 } else { 0b01 }]).collect();
+
+        // The whole pattern in one instruction
+        let mut blob = vec![0, steps.len() as u8];
+        blob.extend(steps.iter().flatten());
+
+        let mut program = vec![Opcode::SetTempo as u8];
+        program.extend_from_slice(&1000.0_f32.to_le_bytes());
+        program.push(Opcode::SetPattern as u8);
+        program.extend_from_slice(&(blob.len() as u32).to_le_bytes());
+        program.extend_from_slice(&blob);
+
+        // One instruction per step
+        let mut reference_program = vec![Opcode::SetTempo as u8];
+        reference_program.extend_from_slice(&1000.0_f32.to_le_bytes());
+
+        for (i, step) in steps.iter().enumerate() {
+            reference_program.push(Opcode::SetPatternData as u8);
+            reference_program.extend_from_slice(&[0, i as u8, step[0], step[1]]);
+        }
+
+        let mut kernel = Kernel::new();
+        let program_size = kernel.vm.borrow_mut().load(&program);
+        kernel.process(program_size as u32, 0);
+
+        let mut reference = Kernel::new();
+        let program_size = reference.vm.borrow_mut().load(&reference_program);
+        reference.process(program_size as u32, 0);
+
+        for _ in 0..16 {
+            kernel.process(0, DEFAULT_MAX_BLOCK_SIZE);
+            reference.process(0, DEFAULT_MAX_BLOCK_SIZE);
+
+            assert_eq!(kernel.left_buffer, reference.left_buffer);
+        }
+    }
+
+    #[test]
+    fn test_ramps() {
+        let mut kernel = Kernel::new();
+
+        // 128 frames at 12.8 kHz, followed by a ramp with a target that is out of range
+        let mut program = Vec::new();
+        encode::set_tempo(&mut program, 60.0);
+        encode::ramp_parameter(&mut program, ParameterId::MasterGain as u8, 0, 0.0, 10.0);
+        encode::ramp_parameter(&mut program, ParameterId::Cutoff as u8, 1, 0.0, 10.0);
+        encode::ramp_parameter(&mut program, PARAMETERS.len() as u8, 0, 1.0, 10.0);
+
+        kernel.initialize(12800.0);
+        let program_size = kernel.vm.borrow_mut().load(&program);
+        kernel.process(program_size as u32, 64);
+
+        assert_eq!(kernel.parameter(0, ParameterId::MasterGain), Some(0.5));
+        assert_eq!(kernel.parameter(0, ParameterId::Tempo), Some(60.0));
+
+        let errors: Vec<u8> = kernel.vm.borrow().outbound_instructions().flatten()
+            .filter(|instruction| matches!(instruction.opcode, Opcode::DecodeError))
+            .filter_map(|instruction| ProgramError::decode(decode::decode_error(instruction.operands())))
+            .map(|error| error.opcode)
+            .collect();
+
+        assert_eq!(errors, vec![Opcode::RampParameter as u8, Opcode::RampParameter as u8]);
+
+        // Ramps end exactly at their target
+        kernel.process(0, 100);
+        assert_eq!(kernel.parameter(0, ParameterId::MasterGain), Some(0.0));
+        assert!(kernel.ramps.is_empty());
+
+        // With smoothing, plain changes are ramped too
+        let mut program = Vec::new();
+        encode::set_smoothing_time(&mut program, 10.0);
+        encode::set_master_gain(&mut program, 1.0);
+
+        let program_size = kernel.vm.borrow_mut().load(&program);
+        kernel.process(program_size as u32, 32);
+
+        assert_eq!(kernel.parameter(0, ParameterId::MasterGain), Some(0.25));
+    }
+
+    #[test]
+    fn test_parameters() {
+        let mut kernel = Kernel::new();
+
+        for parameter in PARAMETERS.iter() {
+            assert_eq!(kernel.parameter(0, parameter.id), Some(parameter.default), "{}", parameter.name);
+        }
+
+        let cutoff = ParameterId::Cutoff as u8;
+
+        let mut program = Vec::new();
+        encode::describe_parameters(&mut program);
+        encode::set_parameter(&mut program, cutoff, 1000.0);
+        encode::set_parameter(&mut program, cutoff, 20000.0);
+        encode::get_parameter(&mut program, cutoff);
+
+        let program_size = kernel.vm.borrow_mut().load(&program);
+        kernel.process(program_size as u32, 0);
+
+        let vm = kernel.vm.borrow();
+
+        let mut names = Vec::new();
+        let mut values = Vec::new();
+        let mut errors = 0;
+
+        for instruction in vm.outbound_instructions().flatten() {
+            let mut reader = instruction.reader();
+
+            match instruction.opcode {
+                Opcode::ParameterInfo => {
+                    reader.read_bytes(15).unwrap();
+                    names.push(reader.read_bytes(reader.remaining()).unwrap());
+                },
+                Opcode::ParameterValue => values.push((reader.read_u8().unwrap(), reader.read_f32().unwrap())),
+                Opcode::DecodeError => errors += 1,
+                _ => ()
+            }
+        }
+
+        assert_eq!(names.len(), PARAMETERS.len());
+        assert_eq!(names[cutoff as usize], b"Cutoff");
+
+        // The value past the maximum was rejected
+        assert_eq!(values, vec![(cutoff, 1000.0)]);
+        assert_eq!(errors, 1);
+    }
+
+    fn run(kernel: &mut Kernel, program: &[u8]) {
+        let program_size = kernel.vm.borrow_mut().load(program);
+        kernel.process(program_size as u32, 0);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut kernel = Kernel::new();
+        kernel.set_voice_count(2);
+
+        let mut program = Vec::new();
+        encode::set_tempo(&mut program, 170.0);
+        encode::set_swing(&mut program, 0.1);
+        encode::set_groove(&mut program, &[0, 120, 20, 80]);
+        encode::set_channel(&mut program, 1);
+        encode::set_cutoff(&mut program, 1200.0);
+        encode::set_waveform_index(&mut program, 1);
+        encode::set_pattern_data(&mut program, 3, 0, 48, 0b11);
+        encode::set_current_pattern(&mut program, 3);
+        encode::set_song(&mut program, &[NO_ENTRY, 0, 1, 0, 3, 2, 0, 5, 1, 12]);
+        encode::set_channel(&mut program, DRUM_CHANNEL as u32);
+        encode::set_channel_mute(&mut program, 1);
+        encode::set_smoothing_time(&mut program, 10.0);
+        encode::set_telemetry_rate(&mut program, 30.0);
+        encode::request_snapshot(&mut program);
+        run(&mut kernel, &program);
+
+        let snapshot = kernel.vm.borrow().outbound_instructions().flatten()
+            .find(|instruction| matches!(instruction.opcode, Opcode::Snapshot))
+            .map(|instruction| {
+                let mut reader = instruction.reader();
+                reader.read_bytes(reader.remaining()).unwrap().to_vec()
+            })
+            .unwrap();
+
+        // A restored kernel plays exactly the same
+        let mut restored = Kernel::new();
+        restored.initialize(DEFAULT_SAMPLE_RATE);
+        let mut program = Vec::new();
+        encode::restore_snapshot(&mut program, &snapshot);
+        run(&mut restored, &program);
+
+        assert_eq!(restored.voice_count(), 2);
+        assert_eq!(restored.staging.as_ref().unwrap().voice_count(), MAX_VOICE_COUNT);
+        assert_eq!(restored.smoothing_time, 10.0);
+        assert_eq!(restored.snapshot(), snapshot);
+
+        let mut program = Vec::new();
+        encode::start_transport(&mut program);
+        run(&mut kernel, &program);
+        run(&mut restored, &program);
+
+        for _ in 0..16 {
+            kernel.process(0, DEFAULT_MAX_BLOCK_SIZE);
+            restored.process(0, DEFAULT_MAX_BLOCK_SIZE);
+
+            assert_eq!(kernel.left_buffer, restored.left_buffer);
+        }
+
+        // A snapshot with an invalid instruction at the end is not applied at all, and the
+        // kernel keeps playing as if it was never sent
+        let mut invalid = Kernel::new().snapshot();
+        encode::set_tempo(&mut invalid, 0.0);
+
+        let mut program = Vec::new();
+        encode::restore_snapshot(&mut program, &invalid);
+        run(&mut restored, &program);
+
+        assert_eq!(restored.snapshot(), kernel.snapshot());
+        assert!(restored.vm.borrow().outbound_instructions().flatten().any(|instruction| matches!(instruction.opcode, Opcode::DecodeError)));
+
+        for _ in 0..16 {
+            kernel.process(0, DEFAULT_MAX_BLOCK_SIZE);
+            restored.process(0, DEFAULT_MAX_BLOCK_SIZE);
+
+            assert_eq!(kernel.left_buffer, restored.left_buffer);
+        }
+
+        assert!(kernel.left_buffer.iter().any(|&sample| sample != 0.0));
+    }
+
+    #[test]
+    fn test_snapshot_version() {
+        use Opcode::*;
+
+        let snapshot = Kernel::new().snapshot();
+
+        let mut opcodes: Vec<u8> = Vec::new();
+
+        for instruction in ProgramIterator::new(&snapshot[2..]) {
+            let opcode = instruction.unwrap().opcode as u8;
+
+            if !opcodes.contains(&opcode) {
+                opcodes.push(opcode);
+            }
+        }
+
+        // Snapshots that are made of other instructions need a new version
+        let expected = [
+            SetSmoothingTime, SetTelemetryRate, SetDelayLength, SetGroove, SetChannel, SetParameter,
+            SetChannelMute, SetChannelSolo, SetWaveformIndex, SetPattern, SetPatternLength,
+            SetPatternResolution, SetCurrentPattern, SetNextPattern, SetSong, SetDrumPattern
+        ];
+
+        assert_eq!((SNAPSHOT_VERSION, opcodes), (8, expected.iter().map(|&opcode| opcode as u8).collect()));
+    }
+
+    #[test]
+    fn test_snapshot_size() {
+        let mut kernel = Kernel::new();
+        kernel.set_voice_count(MAX_VOICE_COUNT);
+
+        let mut program = Vec::new();
+
+        for channel in 0..=DRUM_CHANNEL {
+            encode::set_channel(&mut program, channel as u32);
+
+            for pattern_index in 0..MAX_PATTERN_COUNT {
+                encode::set_pattern_length(&mut program, pattern_index as u8, MAX_PATTERN_LENGTH as u8);
+            }
+        }
+
+        encode::request_snapshot(&mut program);
+        run(&mut kernel, &program);
+
+        // The largest snapshot fits in the outbound buffer along with its header
+        let snapshot = kernel.snapshot();
+        assert!(5 + snapshot.len() <= MAX_OUTBOUND_SIZE, "{} bytes", snapshot.len());
+        assert!(kernel.vm.borrow().outbound_instructions().flatten().any(|instruction| matches!(instruction.opcode, Opcode::Snapshot)));
+    }
+
+    #[test]
+    fn test_midi() {
+        let mut kernel = Kernel::new();
+        kernel.set_voice_count(2);
+
+        // Note on for the second voice, the cutoff controller and a legato note split across
+        // instructions using running status
+        let mut program = Vec::new();
+        encode::midi_input(&mut program, &[0x91, 48, 127, 0xb1, 74, 127]);
+        encode::midi_input(&mut program, &[0x91, 55]);
+        encode::midi_input(&mut program, &[90]);
+        run(&mut kernel, &program);
+
+        assert_eq!(kernel.parameter(1, ParameterId::Cutoff), Some(10000.0));
+        assert_eq!(kernel.parameter(0, ParameterId::Cutoff), Some(450.0));
+
+        for _ in 0..16 {
+            kernel.process(0, DEFAULT_MAX_BLOCK_SIZE);
+        }
+
+        // The legato note did not retrigger the envelope, which has been decaying since the first note
+        let envelope = kernel.voices[1].amplitude_envelope();
+        assert!(envelope > 0.0 && envelope < 1.0);
+        assert!(kernel.left_buffer.iter().any(|&sample| sample != 0.0));
+
+        // Releasing the last held note silences the voice quickly
+        let mut program = Vec::new();
+        encode::midi_input(&mut program, &[0x81, 48, 0, 55, 0]);
+        run(&mut kernel, &program);
+
+        for _ in 0..16 {
+            kernel.process(0, DEFAULT_MAX_BLOCK_SIZE);
+        }
+
+        assert!(kernel.voices[1].amplitude_envelope() < 0.001);
+
+        // Unmapping a controller, and mapping a controller to a parameter that does not exist
+        let mut program = Vec::new();
+        encode::set_midi_mapping(&mut program, 74, NO_MAPPING);
+        encode::midi_input(&mut program, &[0xb1, 74, 0]);
+        encode::set_midi_mapping(&mut program, 74, 254);
+        run(&mut kernel, &program);
+
+        assert_eq!(kernel.parameter(1, ParameterId::Cutoff), Some(10000.0));
+        assert_eq!(kernel.vm.borrow().outbound_instructions().flatten().filter(|instruction| matches!(instruction.opcode, Opcode::DecodeError)).count(), 1);
+    }
+
+    #[test]
+    fn test_song() {
+        let mut kernel = Kernel::new();
+
+        // Pattern 5 a fifth up on the voice, pattern 1 twice and then pattern 2 on the drums
+        let mut program = Vec::new();
+        encode::set_tempo(&mut program, 300.0);
+        encode::set_song(&mut program, &[NO_ENTRY, 0, 0, 0, 5, 1, 7]);
+        encode::set_channel(&mut program, DRUM_CHANNEL as u32);
+        encode::set_song(&mut program, &[NO_ENTRY, 0, 0, 0, 1, 2, 0, 2, 1, 0]);
+        encode::queue_song_entry(&mut program, 1);
+        encode::queue_song_entry(&mut program, 2);
+        run(&mut kernel, &program);
+
+        // The entry past the end of the drum song was rejected
+        let errors = kernel.vm.borrow().outbound_instructions().flatten().filter(|instruction| matches!(instruction.opcode, Opcode::DecodeError)).count();
+        assert_eq!(errors, 1);
+
+        let mut reports = Vec::new();
+
+        // Two patterns of 16 steps at 300 BPM
+        for _ in 0..(2 * 16 * 2205) / DEFAULT_MAX_BLOCK_SIZE {
+            kernel.process(0, DEFAULT_MAX_BLOCK_SIZE);
+
+            for instruction in kernel.vm.borrow().outbound_instructions().flatten() {
+                if matches!(instruction.opcode, Opcode::SetActivePattern) {
+                    reports.push(decode::set_active_pattern(instruction.operands()).to_le_bytes());
+                }
+            }
+        }
+
+        assert_eq!(reports, vec![
+            [5, 0, 0, 0],
+            [2, DRUM_CHANNEL as u8, 1, 0],
+            [5, 0, 0, 0],
+            [1, DRUM_CHANNEL as u8, 0, 0]
+        ]);
+    }
+
+    // Sequencer steps of the first channel over a number of frames, a sixteenth note at 300 BPM
+    // is 2205 frames long
+    fn sequencer_steps(program: &[u8], frame_count: usize) -> Vec<u32> {
+        let mut kernel = Kernel::new();
+        run(&mut kernel, program);
+
+        let mut steps = Vec::new();
+
+        for block in (0..frame_count).step_by(DEFAULT_MAX_BLOCK_SIZE) {
+            kernel.process(0, (frame_count - block).min(DEFAULT_MAX_BLOCK_SIZE));
+
+            for instruction in kernel.vm.borrow().outbound_instructions().flatten() {
+                if matches!(instruction.opcode, Opcode::SetSequencerStep) {
+                    steps.push(decode::set_sequencer_step(instruction.operands()));
+                }
+            }
+        }
+
+        steps
+    }
+
+    #[test]
+    fn test_pattern_length() {
+        let mut program = Vec::new();
+        encode::set_tempo(&mut program, 300.0);
+        encode::set_pattern_length(&mut program, 0, 3);
+        encode::set_pattern_resolution(&mut program, 0, StepResolution::Eighth as u8);
+        encode::set_pattern_length(&mut program, 0, 0);
+        encode::set_pattern_length(&mut program, 0, 65);
+        encode::set_pattern_resolution(&mut program, 0, 6);
+
+        assert_eq!(sequencer_steps(&program, 16 * 2205), vec![0, 1, 2, 0, 1, 2, 0, 1]);
+
+        let mut program = Vec::new();
+        encode::set_tempo(&mut program, 300.0);
+        encode::set_pattern_length(&mut program, 0, 64);
+        encode::set_pattern_resolution(&mut program, 0, StepResolution::SixteenthTriplet as u8);
+
+        assert_eq!(sequencer_steps(&program, 16 * 2205), (0..24).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_groove() {
+        let mut program = Vec::new();
+        encode::set_tempo(&mut program, 300.0);
+        encode::set_swing(&mut program, 0.25);
+
+        // The second step is a quarter step late, the third one is on time
+        assert_eq!(sequencer_steps(&program, 2205 + 551).len(), 1);
+        assert_eq!(sequencer_steps(&program, 2205 + 552).len(), 2);
+        assert_eq!(sequencer_steps(&program, 2 * 2205 + 1).len(), 3);
+
+        // The groove adds to the swing
+        encode::set_groove(&mut program, &[0, 100, 64, 100]);
+        assert_eq!(sequencer_steps(&program, 2205 + 1102).len(), 1);
+        assert_eq!(sequencer_steps(&program, 2205 + 1103).len(), 2);
+    }
+
+    #[test]
+    fn test_transport() {
+        let mut kernel = Kernel::new();
+
+        // Runs a program, then renders a number of sixteenth notes at 300 BPM and returns the
+        // sequencer steps and transport states that were reported
+        let mut play = |program: &[u8], step_count: usize| {
+            let mut program = program.to_vec();
+            encode::set_tempo(&mut program, 300.0);
+            run(&mut kernel, &program);
+
+            let mut reports = Vec::new();
+
+            for _ in 0..step_count * 2205 / DEFAULT_MAX_BLOCK_SIZE {
+                for instruction in kernel.vm.borrow().outbound_instructions().flatten() {
+                    match instruction.opcode {
+                        Opcode::SetSequencerStep => reports.push(decode::set_sequencer_step(instruction.operands())),
+                        Opcode::SetTransportState => reports.push(100 + decode::set_transport_state(instruction.operands())),
+                        _ => ()
+                    }
+                }
+
+                kernel.process(0, DEFAULT_MAX_BLOCK_SIZE);
+            }
+
+            (reports, kernel.voices[0].amplitude_envelope())
+        };
+
+        assert_eq!(play(&[], 3).0, vec![0, 1, 2]);
+
+        // The note that was playing is released
+        let mut program = Vec::new();
+        encode::stop_transport(&mut program);
+        let (reports, envelope) = play(&program, 2);
+        assert_eq!(reports, vec![100]);
+        assert!(envelope < 0.001);
+
+        let mut program = Vec::new();
+        encode::continue_transport(&mut program);
+        assert_eq!(play(&program, 2).0, vec![101, 3, 4]);
+
+        let mut program = Vec::new();
+        encode::reset_transport(&mut program, 9);
+        assert_eq!(play(&program, 2).0, vec![9, 10]);
+
+        let mut program = Vec::new();
+        encode::start_transport(&mut program);
+        assert_eq!(play(&program, 2).0, vec![101, 0, 1]);
+    }
+
+    #[test]
+    fn test_clock_sync() {
+        // Processes a block with a program, and returns the sequencer steps and the MIDI output
+        // of that block
+        fn block(kernel: &mut Kernel, program: &[u8]) -> (Vec<u32>, Vec<u8>) {
+            let program_size = kernel.vm.borrow_mut().load(program);
+            kernel.process(program_size as u32, DEFAULT_MAX_BLOCK_SIZE);
+
+            let mut steps = Vec::new();
+            let mut midi_output = Vec::new();
+
+            for instruction in kernel.vm.borrow().outbound_instructions().flatten() {
+                match instruction.opcode {
+                    Opcode::SetSequencerStep => steps.push(decode::set_sequencer_step(instruction.operands())),
+                    Opcode::MidiOutput => midi_output.extend_from_slice(instruction.reader().read_bytes(instruction.reader().remaining()).unwrap()),
+                    _ => ()
+                }
+            }
+
+            (steps, midi_output)
+        }
+
+        // A MIDI clock byte at the start of every third block, a step every eighteen blocks
+        let mut kernel = Kernel::new();
+        let mut program = Vec::new();
+        encode::set_clock_source(&mut program, ClockSource::Midi as u32);
+        encode::midi_input(&mut program, &[midi::START]);
+        run(&mut kernel, &program);
+
+        let steps: Vec<u32> = (0..39).flat_map(|i| {
+            let program: &[u8] = if i % 3 == 0 { &[Opcode::MidiInput as u8, 1, 0, 0, 0, midi::CLOCK] } else { &[] };
+            block(&mut kernel, program).0
+        }).collect();
+
+        assert_eq!(steps, vec![0, 1, 2]);
+
+        let tempo = DEFAULT_SAMPLE_RATE * 60.0 / (3.0 * DEFAULT_MAX_BLOCK_SIZE as f32 * PULSES_PER_QUARTER_NOTE as f32);
+        assert!((kernel.tempo() - tempo).abs() < 0.01);
+
+        // Jumps to the ninth step of the song position
+        let mut program = Vec::new();
+        encode::midi_input(&mut program, &[0xf2, 8, 0, midi::CLOCK]);
+        assert_eq!(block(&mut kernel, &program).0, vec![8]);
+
+        // The host clock jumps to the position of the host, and then catches up with it
+        let mut kernel = Kernel::new();
+        let mut program = Vec::new();
+        encode::set_clock_source(&mut program, ClockSource::Host as u32);
+        encode::set_host_transport(&mut program, 120.0, 2.0);
+        assert_eq!(block(&mut kernel, &program).0, vec![8]);
+
+        let mut program = Vec::new();
+        encode::set_host_transport(&mut program, 120.0, 2.1);
+        block(&mut kernel, &program);
+        assert!(kernel.tempo() > 120.0 && kernel.tempo() <= 132.0);
+
+        let mut program = Vec::new();
+        encode::set_host_transport(&mut program, 120.0, 1.0);
+        assert_eq!(block(&mut kernel, &program).0, vec![4]);
+        assert_eq!(kernel.tempo(), 120.0);
+
+        // The internal clock sends MIDI clock when enabled, with a frame for every byte
+        let mut kernel = Kernel::new();
+        let mut program = Vec::new();
+        encode::set_midi_clock_output(&mut program, 1);
+        encode::start_transport(&mut program);
+        assert_eq!(block(&mut kernel, &program).1, vec![0, 0, midi::START, 0, 0, midi::CLOCK]);
+
+        let mut program = Vec::new();
+        encode::set_frame_offset(&mut program, 10);
+        encode::stop_transport(&mut program);
+        assert_eq!(block(&mut kernel, &program).1, vec![10, 0, midi::STOP]);
+
+        // Continuing sends the step that is due first, the first pulse already went by
+        let mut program = Vec::new();
+        encode::continue_transport(&mut program);
+        assert_eq!(block(&mut kernel, &program).1, vec![0, 0, midi::SONG_POSITION, 0, 0, 1, 0, 0, 0, 0, 0, midi::CONTINUE]);
+
+        let mut program = Vec::new();
+        encode::set_midi_clock_output(&mut program, 2);
+        encode::set_clock_source(&mut program, 3);
+        run(&mut kernel, &program);
+        assert_eq!(kernel.vm.borrow().outbound_instructions().flatten().filter(|instruction| matches!(instruction.opcode, Opcode::DecodeError)).count(), 2);
+    }
+
+    #[test]
+    fn test_snapshot_while_following() {
+        // A MIDI clock byte every eight frames is far faster than the tempo parameter allows
+        let mut kernel = Kernel::new();
+        let mut program = Vec::new();
+        encode::set_clock_source(&mut program, ClockSource::Midi as u32);
+        encode::midi_input(&mut program, &[midi::START]);
+
+        for frame in (0..DEFAULT_MAX_BLOCK_SIZE).step_by(8) {
+            encode::set_frame_offset(&mut program, frame as u32);
+            encode::midi_input(&mut program, &[midi::CLOCK]);
+        }
+
+        encode::request_snapshot(&mut program);
+
+        let program_size = kernel.vm.borrow_mut().load(&program);
+        kernel.process(program_size as u32, DEFAULT_MAX_BLOCK_SIZE);
+
+        assert_eq!(kernel.tempo(), ParameterId::Tempo.parameter().max);
+
+        let snapshot = kernel.vm.borrow().outbound_instructions().flatten()
+            .find(|instruction| matches!(instruction.opcode, Opcode::Snapshot))
+            .map(|instruction| {
+                let mut reader = instruction.reader();
+                reader.read_bytes(reader.remaining()).unwrap().to_vec()
+            })
+            .unwrap();
+
+        // The snapshot restores like any other
+        let mut restored = Kernel::new();
+        let mut program = Vec::new();
+        encode::restore_snapshot(&mut program, &snapshot);
+        run(&mut restored, &program);
 
-        return self.vm.borrow_mut().get_position() as u32;
+        assert!(!restored.vm.borrow().outbound_instructions().flatten().any(|instruction| matches!(instruction.opcode, Opcode::DecodeError)));
+        assert_eq!(restored.tempo(), kernel.tempo());
     }
 }
@@ -1,17 +1,26 @@
 use std::cell::RefCell;
-use std::panic;
 
 #[macro_use]
 mod macros;
 
+mod clock;
 mod delay;
 mod distortion;
+mod drums;
 pub mod filters;
-mod kernel;
+mod groove;
+pub mod host;
+pub mod kernel;
+mod midi;
+mod mixer;
+mod parameters;
 mod r303;
+mod ramp;
 mod sequencer;
+mod song;
+mod telemetry;
 mod vco;
-mod vm;
+pub mod vm;
 
 #[cfg(feature = "wee_alloc")]
 #[global_allocator]
@@ -22,45 +31,34 @@ thread_local! {
     static KERNEL: Box<RefCell<kernel::Kernel>> = Box::new(RefCell::new(kernel::Kernel::new()));
 }
 
-fn panic_handler(info: &panic::PanicInfo) {
-    error(&info.to_string());
+pub fn log(s: &str) {
+    host::log(s);
 }
 
-extern "C" {
-    #[link_name="console_log"]
-    fn _console_log(a_ptr: *const u8, a_len: usize);
-
-    #[link_name="console_error"]
-    fn _console_error(a_ptr: *const u8, a_len: usize);
+pub fn error(s: &str) {
+    host::error(s);
 }
 
-fn wrap(s: &str, f: unsafe extern "C" fn(*const u8, usize)) {
-    let ptr = s.as_ptr();
-    let len = s.len();
-
-    unsafe {
-        f(ptr, len);
-    }
-}
+#[no_mangle]
+pub extern "C" fn initialize(sample_rate: f32) {
+    host::install_panic_hook();
 
-pub fn log(s: &str) {
-    wrap(s, _console_log);
+    KERNEL.with(|k| k.borrow_mut().initialize(sample_rate));
 }
 
-pub fn error(s: &str) {
-    wrap(s, _console_error);
+#[no_mangle]
+pub extern "C" fn process(program_size: u32, frame_count: u32) -> u32 {
+    KERNEL.with(|k| k.borrow_mut().process(program_size, frame_count as usize))
 }
 
 #[no_mangle]
-pub extern "C" fn initialize() {
-    panic::set_hook(Box::new(panic_handler));
-
-    KERNEL.with(|k| k.borrow_mut().initialize());
+pub extern "C" fn set_voice_count(voice_count: u32) {
+    KERNEL.with(|k| k.borrow_mut().set_voice_count(voice_count as usize));
 }
 
 #[no_mangle]
-pub extern "C" fn process(program_size: u32) -> u32 {
-    KERNEL.with(|k| k.borrow_mut().process(program_size))
+pub extern "C" fn set_max_block_size(max_block_size: u32) {
+    KERNEL.with(|k| k.borrow_mut().set_max_block_size(max_block_size as usize));
 }
 
 #[no_mangle]
@@ -78,6 +76,16 @@ pub fn get_program_pointer() -> *mut u8 {
     KERNEL.with(|k| k.borrow_mut().vm.borrow_mut().get_program_ptr())
 }
 
+#[no_mangle]
+pub fn get_outbound_pointer() -> *mut u8 {
+    KERNEL.with(|k| k.borrow_mut().vm.borrow_mut().get_outbound_ptr())
+}
+
+#[no_mangle]
+pub fn get_dropped_message_count() -> u32 {
+    KERNEL.with(|k| k.borrow().vm.borrow().dropped_message_count())
+}
+
 #[no_mangle]
 pub fn handle_message() {
 }
@@ -0,0 +1,258 @@
+use crate::parameters::ParameterId;
+use crate::vm::{Opcode, VM, MAX_OUTBOUND_SIZE};
+
+pub const CONTROLLER_COUNT: usize = 128;
+
+// Controller that releases all held notes on its channel
+pub const ALL_NOTES_OFF: u8 = 123;
+
+// Parameter ID that removes the mapping of a controller
+pub const NO_MAPPING: u8 = 255;
+
+// Notes with at least this velocity are accented
+pub const ACCENT_VELOCITY: u8 = 100;
+
+// System messages that are sent when the kernel is the clock master, song position pointers go
+// before continue messages
+pub const CLOCK: u8 = 0xf8;
+pub const START: u8 = 0xfa;
+pub const CONTINUE: u8 = 0xfb;
+pub const STOP: u8 = 0xfc;
+pub const SONG_POSITION: u8 = 0xf2;
+
+// Controllers that are mapped when the kernel starts, following the General MIDI conventions
+const DEFAULT_MAPPINGS: [(u8, ParameterId); 4] = [
+    (7, ParameterId::ChannelGain),
+    (10, ParameterId::Pan),
+    (71, ParameterId::Resonance),
+    (74, ParameterId::Cutoff)
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiEvent {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8 },
+    ControlChange { channel: u8, controller: u8, value: u8 },
+    Clock,
+    Start,
+    Continue,
+    Stop,
+    SongPosition { position: u16 } // in sixteenth notes
+}
+
+// Turns a stream of raw MIDI bytes into events. The stream may be split up at any byte, and
+// running status is supported. Messages other than notes, controllers and the clock and transport
+// messages are skipped.
+pub struct MidiDecoder {
+    status: u8, // 0 when there is no running status
+    data: [u8; 2],
+    data_count: usize
+}
+
+impl MidiDecoder {
+    pub fn new() -> Self {
+        MidiDecoder {
+            status: 0,
+            data: [0; 2],
+            data_count: 0
+        }
+    }
+
+    pub fn decode(&mut self, byte: u8) -> Option<MidiEvent> {
+        match byte {
+            // Real time messages can appear anywhere, even in the middle of other messages
+            CLOCK => Some(MidiEvent::Clock),
+            START => Some(MidiEvent::Start),
+            CONTINUE => Some(MidiEvent::Continue),
+            STOP => Some(MidiEvent::Stop),
+            0xf9 | 0xfd..=0xff => None,
+
+            // Other system messages cancel running status, their data is skipped
+            0xf0 | 0xf1 | 0xf3..=0xf7 => {
+                self.status = 0;
+                None
+            },
+
+            0x80..=0xef | SONG_POSITION => {
+                self.status = byte;
+                self.data_count = 0;
+                None
+            },
+
+            _ if self.status == 0 => None,
+
+            _ => {
+                self.data[self.data_count] = byte;
+                self.data_count += 1;
+
+                // Program change and channel pressure have a single data byte
+                let size = if matches!(self.status & 0xf0, 0xc0 | 0xd0) { 1 } else { 2 };
+
+                if self.data_count < size {
+                    return None;
+                }
+
+                self.data_count = 0;
+                let event = self.event();
+
+                // System messages have no running status
+                if self.status == SONG_POSITION {
+                    self.status = 0;
+                }
+
+                event
+            }
+        }
+    }
+
+    fn event(&self) -> Option<MidiEvent> {
+        let channel = self.status & 0x0f;
+
+        if self.status == SONG_POSITION {
+            return Some(MidiEvent::SongPosition { position: self.data[0] as u16 | (self.data[1] as u16) << 7 });
+        }
+
+        match (self.status & 0xf0, self.data) {
+            (0x90, [note, velocity]) if velocity > 0 => Some(MidiEvent::NoteOn { channel, note, velocity }),
+            (0x80, [note, _]) | (0x90, [note, _]) => Some(MidiEvent::NoteOff { channel, note }),
+            (0xb0, [controller, value]) => Some(MidiEvent::ControlChange { channel, controller, value }),
+            _ => None
+        }
+    }
+}
+
+impl Default for MidiDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Room for the MIDI output of a block, a small part of the outbound buffer
+const MAX_OUTPUT_SIZE: usize = MAX_OUTBOUND_SIZE / 16;
+
+// MIDI bytes that the kernel sends during a block, each one stored with the frame it belongs to
+pub struct MidiOutput {
+    pub is_enabled: bool,
+    data: Vec<u8> // the frame as a little endian u16, followed by the byte
+}
+
+impl MidiOutput {
+    pub fn new() -> Self {
+        MidiOutput {
+            is_enabled: false,
+            data: Vec::with_capacity(MAX_OUTPUT_SIZE)
+        }
+    }
+
+    // Messages that do not fit in the output of the block are dropped as a whole
+    pub fn send(&mut self, frame: usize, bytes: &[u8]) {
+        if !self.is_enabled || self.data.len() + 3 * bytes.len() > MAX_OUTPUT_SIZE {
+            return;
+        }
+
+        for &byte in bytes {
+            self.data.extend_from_slice(&(frame as u16).to_le_bytes());
+            self.data.push(byte);
+        }
+    }
+
+    // Sends the bytes of the block to the frontend in a single instruction
+    pub fn flush(&mut self, vm: &mut VM) {
+        if !self.data.is_empty() {
+            vm.push_blob(Opcode::MidiOutput, &self.data);
+            self.data.clear();
+        }
+    }
+}
+
+impl Default for MidiOutput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Maps controllers to parameters from the parameter table
+pub struct MidiMapping {
+    parameters: [Option<ParameterId>; CONTROLLER_COUNT]
+}
+
+impl MidiMapping {
+    pub fn new() -> Self {
+        let mut mapping = MidiMapping {
+            parameters: [None; CONTROLLER_COUNT]
+        };
+
+        for (controller, id) in DEFAULT_MAPPINGS.iter() {
+            mapping.parameters[*controller as usize] = Some(*id);
+        }
+
+        mapping
+    }
+
+    pub fn set(&mut self, controller: u8, id: Option<ParameterId>) {
+        self.parameters[controller as usize] = id;
+    }
+
+    pub fn get(&self, controller: u8) -> Option<ParameterId> {
+        self.parameters[controller as usize]
+    }
+}
+
+impl Default for MidiMapping {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(bytes: &[u8]) -> Vec<MidiEvent> {
+        let mut decoder = MidiDecoder::new();
+        bytes.iter().filter_map(|&byte| decoder.decode(byte)).collect()
+    }
+
+    #[test]
+    fn test_decoder() {
+        // Running status, a clock tick in the middle of a message and a note on with velocity 0
+        assert_eq!(decode(&[0x91, 60, 100, 62, 0xf8, 90, 60, 0]), vec![
+            MidiEvent::NoteOn { channel: 1, note: 60, velocity: 100 },
+            MidiEvent::Clock,
+            MidiEvent::NoteOn { channel: 1, note: 62, velocity: 90 },
+            MidiEvent::NoteOff { channel: 1, note: 60 }
+        ]);
+
+        // Program changes are skipped, system exclusive data cancels running status
+        assert_eq!(decode(&[0xc0, 5, 0xb0, 74, 64, 0xf0, 1, 2, 0xf7, 74, 64, 0x80, 60, 64]), vec![
+            MidiEvent::ControlChange { channel: 0, controller: 74, value: 64 },
+            MidiEvent::NoteOff { channel: 0, note: 60 }
+        ]);
+
+        // Song position pointers are followed by data without running status
+        assert_eq!(decode(&[0xfa, 0xf2, 0x10, 0x01, 0x10, 0xfe, 0xfc]), vec![
+            MidiEvent::Start,
+            MidiEvent::SongPosition { position: 144 },
+            MidiEvent::Stop
+        ]);
+    }
+
+    #[test]
+    fn test_output_size() {
+        let mut output = MidiOutput::new();
+        output.is_enabled = true;
+
+        for frame in 0..MAX_OUTPUT_SIZE {
+            output.send(frame, &[SONG_POSITION, 0, 0]);
+        }
+
+        // The output never grows past its capacity, and only whole messages are kept
+        assert_eq!(output.data.len(), MAX_OUTPUT_SIZE / 9 * 9);
+        assert_eq!(output.data.capacity(), MAX_OUTPUT_SIZE);
+
+        let mut vm = VM::new();
+        output.flush(&mut vm);
+        assert!(output.data.is_empty());
+        assert_eq!(vm.outbound_instructions().count(), 1);
+    }
+}
@@ -0,0 +1,336 @@
+use crate::delay::{Delay, MAX_DELAY_TIME};
+use crate::filters::BiQuad;
+use crate::kernel::{DRUM_CHANNEL, MAX_VOICE_COUNT};
+use crate::parameters::ParameterId;
+use crate::vm::{check_range, decode, encode, ErrorReason, Instruction, Opcode};
+
+const LOW_SHELF_FREQUENCY: f32 = 200.0;
+const HIGH_SHELF_FREQUENCY: f32 = 5000.0;
+const SHELF_SLOPE: f32 = 1.0;
+
+// Square root pan law, this has constant power and unity gain in the center position. Returns the
+// gains of the left and right channel for a pan position in -1..1.
+pub fn pan_law(pan: f32) -> (f32, f32) {
+    ((1.0 - pan).sqrt(), (1.0 + pan).sqrt())
+}
+
+// Scales the side signal of a stereo frame, 0 is mono and 1 leaves the frame unchanged
+pub fn stereo_width(left: f32, right: f32, width: f32) -> (f32, f32) {
+    let mid = 0.5 * (left + right);
+    let side = 0.5 * (left - right) * width;
+
+    (mid + side, mid - side)
+}
+
+struct ChannelStrip {
+    gain: f32, // 0..1
+    delay_send: f32, // 0..1
+    is_muted: bool,
+    is_soloed: bool,
+
+    pan: f32, // -1..1
+    pan_left: f32,
+    pan_right: f32
+}
+
+impl ChannelStrip {
+    fn new(delay_send: f32) -> Self {
+        ChannelStrip {
+            gain: 1.0,
+            delay_send,
+            is_muted: false,
+            is_soloed: false,
+
+            pan: 0.0,
+            pan_left: 1.0,
+            pan_right: 1.0
+        }
+    }
+
+    // -1..1, balances the left and right channel of a stereo source
+    fn set_pan(&mut self, pan: f32) {
+        self.pan = pan.clamp(-1.0, 1.0);
+        (self.pan_left, self.pan_right) = pan_law(self.pan);
+    }
+}
+
+struct MasterBus {
+    gain: f32, // 0..1
+    width: f32, // 0..2
+    low_shelf_gain: f32, // in dB
+    high_shelf_gain: f32, // in dB
+
+    sample_rate: f32,
+
+    low_shelf_left: BiQuad,
+    low_shelf_right: BiQuad,
+    high_shelf_left: BiQuad,
+    high_shelf_right: BiQuad
+}
+
+impl MasterBus {
+    fn new(sample_rate: f32) -> Self {
+        let mut master = MasterBus {
+            gain: 1.0,
+            width: 1.0,
+            low_shelf_gain: 0.0,
+            high_shelf_gain: 0.0,
+
+            sample_rate,
+
+            low_shelf_left: BiQuad::bypass(),
+            low_shelf_right: BiQuad::bypass(),
+            high_shelf_left: BiQuad::bypass(),
+            high_shelf_right: BiQuad::bypass()
+        };
+
+        master.set_low_shelf_gain(master.low_shelf_gain);
+        master.set_high_shelf_gain(master.high_shelf_gain);
+
+        master
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+
+        self.set_low_shelf_gain(self.low_shelf_gain);
+        self.set_high_shelf_gain(self.high_shelf_gain);
+    }
+
+    // The filter state is kept, so that the gain can be ramped without clicks
+    fn set_low_shelf_gain(&mut self, gain: f32) {
+        let shelf = BiQuad::low_shelf(LOW_SHELF_FREQUENCY, gain, SHELF_SLOPE, self.sample_rate);
+
+        self.low_shelf_gain = gain;
+        self.low_shelf_left.set_coefficients(&shelf);
+        self.low_shelf_right.set_coefficients(&shelf);
+    }
+
+    fn set_high_shelf_gain(&mut self, gain: f32) {
+        let shelf = BiQuad::high_shelf(HIGH_SHELF_FREQUENCY, gain, SHELF_SLOPE, self.sample_rate);
+
+        self.high_shelf_gain = gain;
+        self.high_shelf_left.set_coefficients(&shelf);
+        self.high_shelf_right.set_coefficients(&shelf);
+    }
+
+    #[allow(clippy::float_cmp)]
+    fn render(&mut self, mut left: f32, mut right: f32) -> (f32, f32) {
+        // Equalizer, skipped when flat
+        if self.low_shelf_gain != 0.0 {
+            left = self.low_shelf_left.render(left);
+            right = self.low_shelf_right.render(right);
+        }
+
+        if self.high_shelf_gain != 0.0 {
+            left = self.high_shelf_left.render(left);
+            right = self.high_shelf_right.render(right);
+        }
+
+        let (left, right) = stereo_width(left, right, self.width);
+
+        (left * self.gain, right * self.gain)
+    }
+}
+
+pub struct Mixer {
+    channels: Vec<ChannelStrip>,
+    solo_count: usize,
+
+    // Accumulators for the current frame
+    left: f32,
+    right: f32,
+    send_left: f32,
+    send_right: f32,
+
+    delay: Delay,
+    master: MasterBus
+}
+
+impl Mixer {
+    pub fn new(sample_rate: f32) -> Self {
+        // The drums are not sent to the delay by default
+        let channels = (0..=MAX_VOICE_COUNT).map(|channel| {
+            ChannelStrip::new(if channel == DRUM_CHANNEL { 0.0 } else { 0.2 })
+        }).collect();
+
+        Mixer {
+            channels,
+            solo_count: 0,
+
+            left: 0.0,
+            right: 0.0,
+            send_left: 0.0,
+            send_right: 0.0,
+
+            delay: Delay::new(sample_rate),
+            master: MasterBus::new(sample_rate)
+        }
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.delay.set_sample_rate(sample_rate);
+        self.master.set_sample_rate(sample_rate);
+    }
+
+    fn set_solo(&mut self, channel: usize, is_soloed: bool) {
+        self.channels[channel].is_soloed = is_soloed;
+        self.solo_count = self.channels.iter().filter(|strip| strip.is_soloed).count();
+    }
+
+    // Adds a stereo frame of a source to the current frame
+    pub fn add(&mut self, channel: usize, (left, right): (f32, f32)) {
+        let strip = &self.channels[channel];
+
+        if strip.is_muted || (self.solo_count > 0 && !strip.is_soloed) {
+            return;
+        }
+
+        let left = left * strip.gain * strip.pan_left;
+        let right = right * strip.gain * strip.pan_right;
+
+        self.left += left;
+        self.right += right;
+
+        self.send_left += left * strip.delay_send;
+        self.send_right += right * strip.delay_send;
+    }
+
+    // Mixes in the effect returns, runs the master bus and starts a new frame
+    pub fn render(&mut self) -> (f32, f32) {
+        let (delay_left, delay_right) = self.delay.render(self.send_left, self.send_right);
+
+        let output = self.master.render(self.left + delay_left, self.right + delay_right);
+
+        self.left = 0.0;
+        self.right = 0.0;
+        self.send_left = 0.0;
+        self.send_right = 0.0;
+
+        output
+    }
+
+    // Current value of a continuous parameter, None for parameters of other subsystems
+    pub fn parameter(&self, channel: usize, id: ParameterId) -> Option<f32> {
+        use ParameterId::*;
+
+        match id {
+            DelayFeedback => Some(self.delay.feedback),
+            DelayPingPong => Some(self.delay.ping_pong),
+
+            MasterWidth => Some(self.master.width),
+            MasterGain => Some(self.master.gain),
+            MasterLowShelf => Some(self.master.low_shelf_gain),
+            MasterHighShelf => Some(self.master.high_shelf_gain),
+
+            ChannelGain => self.channels.get(channel).map(|strip| strip.gain),
+            ChannelPan => self.channels.get(channel).map(|strip| strip.pan),
+            DelaySend => self.channels.get(channel).map(|strip| strip.delay_send),
+
+            _ => None
+        }
+    }
+
+    // The value has already been checked against the parameter table. Channel strip parameters
+    // are ignored for channels that do not exist.
+    pub fn set_parameter(&mut self, channel: usize, id: ParameterId, value: f32) {
+        use ParameterId::*;
+
+        match id {
+            DelayFeedback => self.delay.feedback = value,
+            DelayPingPong => self.delay.ping_pong = value,
+
+            MasterWidth => self.master.width = value,
+            MasterGain => self.master.gain = value,
+            MasterLowShelf => self.master.set_low_shelf_gain(value),
+            MasterHighShelf => self.master.set_high_shelf_gain(value),
+
+            _ => {
+                if let Some(strip) = self.channels.get_mut(channel) {
+                    match id {
+                        ChannelGain => strip.gain = value,
+                        ChannelPan => strip.set_pan(value),
+                        DelaySend => strip.delay_send = value,
+
+                        _ => ()
+                    }
+                }
+            }
+        }
+    }
+
+    // Appends the instructions that restore the state that is not in the parameter table. The
+    // channel strip state must be sent on its channel.
+    pub fn snapshot(&self, program: &mut Vec<u8>) {
+        encode::set_delay_length(program, self.delay.time());
+    }
+
+    pub fn snapshot_channel(&self, channel: usize, program: &mut Vec<u8>) {
+        let strip = &self.channels[channel];
+
+        encode::set_channel_mute(program, strip.is_muted as u32);
+        encode::set_channel_solo(program, strip.is_soloed as u32);
+    }
+
+    pub fn execute(&mut self, channel: usize, instruction: Instruction) -> Result<(), ErrorReason> {
+        use Opcode::*;
+
+        match instruction.opcode {
+            SetDelayLength => self.delay.set_time(check_range(decode::set_delay_length(instruction.operands()), 1..=MAX_DELAY_TIME)?),
+
+            _ => {
+                // Channel strip instructions, ignored for channels that do not exist
+                if channel >= self.channels.len() {
+                    return Ok(());
+                }
+
+                match instruction.opcode {
+                    SetChannelMute => self.channels[channel].is_muted = decode::set_channel_mute(instruction.operands()) != 0,
+                    SetChannelSolo => self.set_solo(channel, decode::set_channel_solo(instruction.operands()) != 0),
+
+                    _ => ()
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mute_and_solo() {
+        let mut mixer = Mixer::new(44100.0);
+
+        mixer.channels[1].is_muted = true;
+        mixer.add(0, (1.0, 1.0));
+        mixer.add(1, (2.0, 2.0));
+        assert_eq!(mixer.render(), (1.0, 1.0));
+
+        // Soloing a channel silences all others, even when the soloed channel is muted
+        mixer.set_solo(1, true);
+        mixer.add(0, (1.0, 1.0));
+        mixer.add(1, (2.0, 2.0));
+        assert_eq!(mixer.render(), (0.0, 0.0));
+
+        mixer.channels[1].is_muted = false;
+        mixer.add(0, (1.0, 1.0));
+        mixer.add(1, (2.0, 2.0));
+        assert_eq!(mixer.render(), (2.0, 2.0));
+    }
+
+    #[test]
+    fn test_pan() {
+        let mut mixer = Mixer::new(44100.0);
+
+        mixer.channels[0].set_pan(-1.0);
+        mixer.add(0, (1.0, 1.0));
+
+        let (left, right) = mixer.render();
+        assert!((left - 2.0_f32.sqrt()).abs() < 1.0e-6);
+        assert_eq!(right, 0.0);
+    }
+}
@@ -0,0 +1,212 @@
+use crate::vm::{ErrorReason, Opcode, VM};
+
+use enumtojs::enum_to_js;
+
+use std::convert::TryFrom;
+
+// Longest parameter name, in bytes
+const MAX_NAME_LENGTH: usize = 32;
+
+// Size of a ParameterInfo blob without the name
+const INFO_SIZE: usize = 15;
+
+#[repr(u8)]
+#[enum_to_js(filename="../src/ParameterUnit.ts")]
+#[derive(Clone, Copy)]
+pub enum ParameterUnit {
+    Unitless = 0,
+    Hertz,
+    Milliseconds,
+    Decibels,
+    BeatsPerMinute
+}
+
+// How a control should map its position to the value of a parameter
+#[repr(u8)]
+#[enum_to_js(filename="../src/ParameterTaper.ts")]
+#[derive(Clone, Copy)]
+pub enum ParameterTaper {
+    Linear = 0,
+    Logarithmic
+}
+
+// The ID of a parameter is its index in the parameter table, so new parameters must be added at
+// the end
+#[repr(u8)]
+#[enum_to_js(filename="../src/ParameterId.ts")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ParameterId {
+    Tempo = 0,
+    Cutoff,
+    Resonance,
+    EnvMod,
+    Decay,
+    Accent,
+    DistortionThreshold,
+    DistortionShape,
+    ChannelGain,
+    Pan,
+    DelaySend,
+    DelayFeedback,
+    DelayPingPong,
+    StereoWidth,
+    MasterGain,
+    MasterLowShelf,
+    MasterHighShelf,
+    DrumVolume,
+    KickTune,
+    KickDecay,
+    SnareSnappy,
+    HatDecay,
+    Swing,
+    ChannelPan,
+    MasterWidth
+}
+
+impl ParameterId {
+    pub fn decode(value: u8) -> Result<Self, ErrorReason> {
+        Self::try_from(value).map_err(|_| ErrorReason::OutOfRange)
+    }
+
+    pub fn parameter(self) -> &'static Parameter {
+        &PARAMETERS[self as usize]
+    }
+}
+
+pub struct Parameter {
+    pub id: ParameterId,
+    pub name: &'static str,
+    pub unit: ParameterUnit,
+    pub min: f32,
+    pub max: f32,
+    pub default: f32,
+    pub taper: ParameterTaper,
+    pub alias: Option<Opcode> // An instruction that sets only this parameter, like SetParameter
+}
+
+impl Parameter {
+    #[allow(clippy::too_many_arguments)]
+    const fn new(id: ParameterId, name: &'static str, unit: ParameterUnit, min: f32, max: f32, default: f32, taper: ParameterTaper, alias: Option<Opcode>) -> Self {
+        Parameter {
+            id,
+            name,
+            unit,
+            min,
+            max,
+            default,
+            taper,
+            alias
+        }
+    }
+
+    pub fn contains(&self, value: f32) -> bool {
+        (self.min..=self.max).contains(&value)
+    }
+
+    pub fn check(&self, value: f32) -> Result<f32, ErrorReason> {
+        if self.contains(value) {
+            Ok(value)
+        } else {
+            Err(ErrorReason::OutOfRange)
+        }
+    }
+
+    // Maps a control position in 0..=1 to a value, following the taper
+    pub fn value_at(&self, position: f32) -> f32 {
+        let value = match self.taper {
+            Linear => self.min + (self.max - self.min) * position,
+            Logarithmic => self.min * (self.max / self.min).powf(position)
+        };
+
+        value.clamp(self.min, self.max)
+    }
+
+    // ID, unit, taper, minimum, maximum and default, followed by the name
+    pub fn describe(&self, vm: &mut VM) {
+        let mut info = [0; INFO_SIZE + MAX_NAME_LENGTH];
+
+        info[0] = self.id as u8;
+        info[1] = self.unit as u8;
+        info[2] = self.taper as u8;
+        info[3..7].copy_from_slice(&self.min.to_le_bytes());
+        info[7..11].copy_from_slice(&self.max.to_le_bytes());
+        info[11..15].copy_from_slice(&self.default.to_le_bytes());
+        info[INFO_SIZE..INFO_SIZE + self.name.len()].copy_from_slice(self.name.as_bytes());
+
+        vm.push_blob(Opcode::ParameterInfo, &info[..INFO_SIZE + self.name.len()]);
+    }
+}
+
+use ParameterTaper::*;
+use ParameterUnit::*;
+
+// The continuous parameters of the kernel, in the order of their IDs. Voice and channel strip
+// parameters apply to the channel selected by SetChannel.
+pub static PARAMETERS: [Parameter; 25] = [
+    Parameter::new(ParameterId::Tempo, "Tempo", BeatsPerMinute, 20.0, 300.0, 120.0, Linear, Some(Opcode::SetTempo)),
+
+    Parameter::new(ParameterId::Cutoff, "Cutoff", Hertz, 20.0, 10000.0, 450.0, Logarithmic, Some(Opcode::SetCutoff)),
+    Parameter::new(ParameterId::Resonance, "Resonance", Unitless, 0.0, 1.0, 0.2, Linear, Some(Opcode::SetResonance)),
+    Parameter::new(ParameterId::EnvMod, "Env Mod", Unitless, 0.0, 1.0, 0.7, Linear, Some(Opcode::SetEnvMod)),
+    Parameter::new(ParameterId::Decay, "Decay", Milliseconds, 1.0, 2000.0, 150.0, Logarithmic, Some(Opcode::SetDecay)),
+    Parameter::new(ParameterId::Accent, "Accent", Unitless, 0.0, 1.0, 0.2, Linear, Some(Opcode::SetAccent)),
+    Parameter::new(ParameterId::DistortionThreshold, "Distortion Threshold", Unitless, 0.0, 1.0, 0.5 / 0.9, Linear, Some(Opcode::SetDistortionThreshold)),
+    Parameter::new(ParameterId::DistortionShape, "Distortion Shape", Unitless, 0.0, 1.0, 0.5, Linear, Some(Opcode::SetDistortionShape)),
+
+    Parameter::new(ParameterId::ChannelGain, "Channel Gain", Unitless, 0.0, 1.0, 1.0, Linear, Some(Opcode::SetChannelGain)),
+    Parameter::new(ParameterId::Pan, "Pan", Unitless, -1.0, 1.0, 0.0, Linear, Some(Opcode::SetPan)),
+    Parameter::new(ParameterId::DelaySend, "Delay Send", Unitless, 0.0, 1.0, 0.2, Linear, Some(Opcode::SetDelaySend)),
+
+    Parameter::new(ParameterId::DelayFeedback, "Delay Feedback", Unitless, 0.0, 0.99, 0.5, Linear, Some(Opcode::SetDelayFeedback)),
+    Parameter::new(ParameterId::DelayPingPong, "Delay Ping Pong", Unitless, 0.0, 1.0, 0.0, Linear, Some(Opcode::SetDelayPingPong)),
+
+    Parameter::new(ParameterId::StereoWidth, "Stereo Width", Unitless, 0.0, 2.0, 1.0, Linear, Some(Opcode::SetStereoWidth)),
+    Parameter::new(ParameterId::MasterGain, "Master Gain", Unitless, 0.0, 1.0, 1.0, Linear, Some(Opcode::SetMasterGain)),
+    Parameter::new(ParameterId::MasterLowShelf, "Master Low Shelf", Decibels, -24.0, 24.0, 0.0, Linear, Some(Opcode::SetMasterLowShelf)),
+    Parameter::new(ParameterId::MasterHighShelf, "Master High Shelf", Decibels, -24.0, 24.0, 0.0, Linear, Some(Opcode::SetMasterHighShelf)),
+
+    Parameter::new(ParameterId::DrumVolume, "Drum Volume", Unitless, 0.0, 1.0, 0.8, Linear, Some(Opcode::SetDrumVolume)),
+    Parameter::new(ParameterId::KickTune, "Kick Tune", Hertz, 30.0, 120.0, 50.0, Linear, Some(Opcode::SetKickTune)),
+    Parameter::new(ParameterId::KickDecay, "Kick Decay", Milliseconds, 10.0, 2000.0, 300.0, Logarithmic, Some(Opcode::SetKickDecay)),
+    Parameter::new(ParameterId::SnareSnappy, "Snare Snappy", Unitless, 0.0, 1.0, 0.7, Linear, Some(Opcode::SetSnareSnappy)),
+    Parameter::new(ParameterId::HatDecay, "Hat Decay", Milliseconds, 10.0, 2000.0, 300.0, Logarithmic, Some(Opcode::SetHatDecay)),
+
+    Parameter::new(ParameterId::Swing, "Swing", Unitless, 0.0, 0.5, 0.0, Linear, Some(Opcode::SetSwing)),
+
+    Parameter::new(ParameterId::ChannelPan, "Channel Pan", Unitless, -1.0, 1.0, 0.0, Linear, Some(Opcode::SetChannelPan)),
+    Parameter::new(ParameterId::MasterWidth, "Master Width", Unitless, 0.0, 2.0, 1.0, Linear, Some(Opcode::SetMasterWidth))
+];
+
+// The parameter that an instruction is an alias for
+pub fn find_by_alias(opcode: Opcode) -> Option<ParameterId> {
+    PARAMETERS.iter().find(|parameter| matches!(parameter.alias, Some(alias) if alias as u8 == opcode as u8)).map(|parameter| parameter.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_names_fit() {
+        assert!(PARAMETERS.iter().all(|parameter| parameter.name.len() <= MAX_NAME_LENGTH));
+        assert!(PARAMETERS.iter().all(|parameter| parameter.contains(parameter.default)));
+    }
+
+    #[test]
+    fn test_positions() {
+        assert!(PARAMETERS.iter().all(|parameter| parameter.value_at(0.0) == parameter.min));
+        assert!(PARAMETERS.iter().all(|parameter| parameter.value_at(1.0) == parameter.max));
+        assert_eq!(find_by_alias(Opcode::SetCutoff), Some(ParameterId::Cutoff));
+        assert_eq!(find_by_alias(Opcode::SetChannel), None);
+    }
+
+    #[test]
+    fn test_ids() {
+        for (index, parameter) in PARAMETERS.iter().enumerate() {
+            assert_eq!(parameter.id as usize, index);
+            assert_eq!(ParameterId::decode(index as u8), Ok(parameter.id));
+        }
+
+        assert_eq!(ParameterId::decode(PARAMETERS.len() as u8), Err(ErrorReason::OutOfRange));
+    }
+}
@@ -1,14 +1,26 @@
-use crate::delay::Delay;
+use crate::clock::Clock;
 use crate::distortion::FoldbackDistortion;
 use crate::filters::{OnePole,BiQuad,TBFilter};
-use crate::kernel::{ANTI_DENORMAL,SAMPLE_RATE};
-use crate::sequencer::{Sequencer,Step};
-use crate::vco::VCO;
-use crate::vm::{Instruction, Opcode, VM};
+use crate::kernel::ANTI_DENORMAL;
+use crate::midi::ACCENT_VELOCITY;
+use crate::mixer::{pan_law, stereo_width};
+use crate::parameters::ParameterId;
+use crate::sequencer::{Gate,GateEvent,Sequencer,Step};
+use crate::vco::{VCO,WAVEFORM_COUNT};
+use crate::vm::{check_range, decode, encode, ErrorReason, Instruction, Opcode, VM};
 
 use std::rc::Rc;
 use std::cell::RefCell;
 
+// Notes beyond this are forgotten, oldest first
+const MAX_HELD_NOTES: usize = 16;
+
+// Amplitude release when the last MIDI note is released, in ms
+const RELEASE_TIME: f32 = 5.0;
+
+// Slides and the cutoff modulation are updated every this many samples
+const MODULATION_INTERVAL: u32 = 63;
+
 pub struct R303 {
     waveform_index: u32, // 0..1
     cutoff: f32, // Hz
@@ -16,22 +28,36 @@ pub struct R303 {
     envmod: f32, // 0..1
     pub decay: f32, // in ms
     pub accent: f32, // 0..1
+    pan: f32, // -1..1
+    width: f32, // 0..2
 
+    sample_rate: f32,
+
+    channel: usize,
     vm: Rc<RefCell<VM>>,
 
-    sequencer: Sequencer,
+    sequencer: Sequencer<Step>,
+    gate: Gate,
+    held_notes: Vec<u8>, // MIDI notes, in the order they were played
     vco: VCO,
     distortion: FoldbackDistortion,
-    delay: Delay,
 
     accent_gain: f32,
 
+    pan_left: f32,
+    pan_right: f32,
+
     amplitude_envelope: f32,
     amplitude_multiplier: f32,
 
     filter_envelope: f32,
     filter_multiplier: f32,
 
+    effective_cutoff: f32, // Hz
+
+    // Counts samples whether the transport runs or not, so that held notes keep modulating
+    modulation_position: u32,
+
     envelope_scaler: f32,
     envelope_offset: f32,
 
@@ -44,7 +70,7 @@ pub struct R303 {
 }
 
 impl R303 {
-    pub fn new(vm: Rc<RefCell<VM>>) -> Self {
+    pub fn new(vm: Rc<RefCell<VM>>, channel: usize, sample_rate: f32) -> Self {
         let mut r303 = R303 {
             waveform_index: 0,
             cutoff: 450.0,
@@ -52,34 +78,49 @@ impl R303 {
             envmod: 0.7,
             decay: 150.0,
             accent: 0.2,
+            pan: 0.0,
+            width: 1.0,
+
+            sample_rate,
 
+            channel,
             vm,
 
-            delay: Delay::new(),
             sequencer: Sequencer::new(),
-            vco: VCO::new(),
+            gate: Gate::new(),
+            held_notes: Vec::with_capacity(MAX_HELD_NOTES),
+            vco: VCO::new(sample_rate),
             distortion: FoldbackDistortion::new(),
 
             accent_gain: 0.0,
 
+            pan_left: 1.0,
+            pan_right: 1.0,
+
             amplitude_envelope: 0.0,
             amplitude_multiplier: 0.0,
 
             filter_envelope: 0.0,
             filter_multiplier: 0.0,
 
+            effective_cutoff: 0.0,
+
+            modulation_position: 0,
+
             envelope_scaler: 0.0,
             envelope_offset: 0.0,
 
-            highpass1: OnePole::high_pass(44.486),
-            allpass: OnePole::all_pass(14.008),
-            highpass2: OnePole::high_pass(24.167),
-            tb_filter: TBFilter::new(),
-            notch: BiQuad::notch(7.5164, 4.7),
-            declicker: BiQuad::lowpass_12db(200.0, (0.5_f32).sqrt())
+            highpass1: OnePole::bypass(),
+            allpass: OnePole::bypass(),
+            highpass2: OnePole::bypass(),
+            tb_filter: TBFilter::new(sample_rate),
+            notch: BiQuad::bypass(),
+            declicker: BiQuad::bypass()
         };
 
+        r303.update_filters();
         r303.set_waveform_index(r303.waveform_index);
+        r303.set_pan(r303.pan);
         r303.tb_filter.set_resonance(r303.resonance);
 
         r303.update_envmod_coefficients();
@@ -87,11 +128,33 @@ impl R303 {
         r303
     }
 
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+
+        self.vco.set_sample_rate(sample_rate);
+        self.tb_filter.set_sample_rate(sample_rate);
+
+        self.update_filters();
+    }
+
+    fn update_filters(&mut self) {
+        self.highpass1 = OnePole::high_pass(44.486, self.sample_rate);
+        self.allpass = OnePole::all_pass(14.008, self.sample_rate);
+        self.highpass2 = OnePole::high_pass(24.167, self.sample_rate);
+        self.notch = BiQuad::notch(7.5164, 4.7, self.sample_rate);
+        self.declicker = BiQuad::lowpass_12db(200.0, (0.5_f32).sqrt(), self.sample_rate);
+    }
+
     fn set_waveform_index(&mut self, waveform_index: u32) {
         self.waveform_index = waveform_index;
         self.vco.waveform_index = waveform_index as usize;
     }
 
+    fn set_pan(&mut self, pan: f32) {
+        self.pan = pan.clamp(-1.0, 1.0);
+        (self.pan_left, self.pan_right) = pan_law(self.pan);
+    }
+
     fn set_cutoff(&mut self, cutoff: f32) {
         self.cutoff = cutoff;
         self.update_envmod_coefficients();
@@ -120,39 +183,193 @@ impl R303 {
         self.envelope_offset = 0.048292930943553 * c + 0.294391201442418;
     }
 
-    pub fn render(&mut self) -> f32 {
-        if let Some(step) = self.sequencer.update() {
-            // decay multiplier
-            self.amplitude_multiplier = (-1.0 / (0.001 * self.decay * SAMPLE_RATE)).exp();
+    // Filter cutoff including envelope modulation, updated at the modulation rate
+    pub fn effective_cutoff(&self) -> f32 {
+        self.effective_cutoff
+    }
 
-            if step.has_accent {
-                self.filter_multiplier = (-1.0 / (0.001 * 200.0 * SAMPLE_RATE)).exp();
-                self.accent_gain = self.accent;
-            } else {
-                self.filter_multiplier = self.amplitude_multiplier;
-                self.accent_gain = 0.0_f32;
-            }
+    pub fn amplitude_envelope(&self) -> f32 {
+        self.amplitude_envelope
+    }
 
-            self.amplitude_envelope = (1.0 / self.amplitude_multiplier) * step.is_enabled as u32 as f32;
+    pub fn filter_envelope(&self) -> f32 {
+        self.filter_envelope
+    }
+
+    // Current value of a continuous parameter, None for parameters of other subsystems
+    pub fn parameter(&self, id: ParameterId) -> Option<f32> {
+        use ParameterId::*;
+
+        match id {
+            Cutoff => Some(self.cutoff),
+            Resonance => Some(self.resonance),
+            EnvMod => Some(self.envmod),
+            Decay => Some(self.decay),
+            Accent => Some(self.accent),
+            DistortionThreshold => Some(self.distortion.threshold()),
+            DistortionShape => Some(self.distortion.shape),
+            Pan => Some(self.pan),
+            StereoWidth => Some(self.width),
+
+            _ => None
+        }
+    }
 
-            // calculate target pitch
-            let pitch = step.pitch - step.has_down as u8 * 12 + step.has_up as u8 * 12;
+    // The value has already been checked against the parameter table
+    pub fn set_parameter(&mut self, id: ParameterId, value: f32) {
+        use ParameterId::*;
+
+        match id {
+            Cutoff => self.set_cutoff(value),
+            Resonance => self.set_resonance(value),
+            EnvMod => self.set_envmod(value),
+            Decay => self.decay = value,
+            Accent => self.accent = value,
+            DistortionThreshold => self.distortion.set_threshold(value),
+            DistortionShape => self.distortion.shape = value,
+            Pan => self.set_pan(value),
+            StereoWidth => self.width = value,
 
-            // VCO parameters
-            if step.has_slide {
-                self.vco.slide(pitch as f32);
-            } else {
-                self.filter_envelope = 1.0 / self.filter_multiplier;
-                self.vco.reset(pitch as f32);
-            }
+            _ => ()
+        }
+    }
+
+    // Appends the instructions that restore the state that is not in the parameter table
+    pub fn snapshot(&self, program: &mut Vec<u8>) {
+        encode::set_waveform_index(program, self.waveform_index);
+        self.sequencer.snapshot(program, encode::set_pattern);
+    }
+
+    fn trigger(&mut self, pitch: u8, has_accent: bool) {
+        // decay multiplier
+        self.amplitude_multiplier = (-1.0 / (0.001 * self.decay * self.sample_rate)).exp();
 
-            // Tell VM that we advanced a step
-            let mut vm = self.vm.borrow_mut();
-            vm.push_opcode(Opcode::SetSequencerStep);
-            vm.push_u8(self.sequencer.pattern_position as u8);
+        if has_accent {
+            self.filter_multiplier = (-1.0 / (0.001 * 200.0 * self.sample_rate)).exp();
+            self.accent_gain = self.accent;
+        } else {
+            self.filter_multiplier = self.amplitude_multiplier;
+            self.accent_gain = 0.0_f32;
         }
 
-        // TODO: set amplitude_envelope to 0 when not running sequencer
+        self.amplitude_envelope = 1.0 / self.amplitude_multiplier;
+        self.filter_envelope = 1.0 / self.filter_multiplier;
+
+        // VCO parameters
+        self.vco.reset(pitch as f32);
+    }
+
+    // Notes played while other notes are held slide to the new pitch without retriggering the
+    // envelopes, like tied steps on the sequencer
+    pub fn note_on(&mut self, note: u8, velocity: u8) {
+        let is_legato = !self.held_notes.is_empty();
+
+        self.held_notes.retain(|&held_note| held_note != note);
+
+        if self.held_notes.len() == MAX_HELD_NOTES {
+            self.held_notes.remove(0);
+        }
+
+        self.held_notes.push(note);
+
+        if is_legato {
+            self.vco.slide(note as f32);
+        } else {
+            self.trigger(note, velocity >= ACCENT_VELOCITY);
+        }
+    }
+
+    // The most recent note that is still held sounds, releasing it slides back to the previous one
+    pub fn note_off(&mut self, note: u8) {
+        let was_held = self.held_notes.contains(&note);
+        let was_sounding = self.held_notes.last() == Some(&note);
+
+        self.held_notes.retain(|&held_note| held_note != note);
+
+        match self.held_notes.last() {
+            Some(&held_note) if was_sounding => self.vco.slide(held_note as f32),
+            None if was_held => self.release(),
+            _ => ()
+        }
+    }
+
+    pub fn all_notes_off(&mut self) {
+        if !self.held_notes.is_empty() {
+            self.held_notes.clear();
+            self.release();
+        }
+    }
+
+    // The step that plays when the clock starts a step next
+    pub fn restart(&mut self, position: usize) {
+        self.sequencer.restart(position);
+    }
+
+    // The step that is due when the clock continues from a position in pulses
+    pub fn relocate(&mut self, pulse: u32) {
+        self.sequencer.relocate(pulse);
+    }
+
+    pub fn rewind_song(&mut self) {
+        self.sequencer.song.rewind();
+    }
+
+    // Releases the note that the sequencer was playing, held MIDI notes keep playing
+    pub fn stop(&mut self) {
+        self.gate.close();
+
+        if self.held_notes.is_empty() {
+            self.release();
+        }
+    }
+
+    fn release(&mut self) {
+        self.amplitude_multiplier = (-1.0 / (0.001 * RELEASE_TIME * self.sample_rate)).exp();
+    }
+
+    // Follows the gate of the sequencer
+    fn play(&mut self, event: GateEvent) {
+        let step = self.sequencer.step();
+
+        // calculate target pitch, transposed in song mode
+        let pitch = step.pitch - step.has_down as u8 * 12 + step.has_up as u8 * 12;
+        let pitch = (pitch as i16 + self.sequencer.song.transpose() as i16).clamp(0, 127) as u8;
+        let has_accent = step.has_accent;
+
+        match event {
+            GateEvent::Open => {
+                self.trigger(pitch, has_accent);
+
+                // Grooves scale the accent
+                self.accent_gain *= self.sequencer.step_delay.velocity;
+            },
+
+            // The envelopes keep running, like with legato MIDI notes
+            GateEvent::Slide => self.vco.slide(pitch as f32),
+            GateEvent::Tie => (),
+            GateEvent::Rest | GateEvent::Close => self.release()
+        }
+    }
+
+    pub fn render(&mut self, clock: &Clock) -> (f32, f32) {
+        if let Some(event) = self.gate.update(&mut self.sequencer, clock) {
+            // Held MIDI notes take priority over the sequencer
+            if self.held_notes.is_empty() {
+                self.play(event);
+            }
+
+            // Every event but the gate closing is a new step
+            if event != GateEvent::Close {
+                let mut vm = self.vm.borrow_mut();
+
+                if self.sequencer.pattern_position == 0 {
+                    self.sequencer.song.report(&mut vm, self.sequencer.current_pattern(), self.channel);
+                }
+
+                // Tell VM that we advanced a step, the channel goes in the second byte
+                vm.push_u32(Opcode::SetSequencerStep, self.sequencer.pattern_position as u32 | (self.channel as u32) << 8);
+            }
+        }
 
         // envelopes
         self.amplitude_envelope = self.amplitude_envelope * self.amplitude_multiplier + ANTI_DENORMAL;
@@ -162,14 +379,16 @@ impl R303 {
         let mut sample = self.vco.render();
 
         // Modulators
-        if self.sequencer.sample_position % 63 == 0 {
+        if self.modulation_position == 0 {
             self.vco.update();
 
             // Cutoff modulation
-            let effective_cutoff = (self.cutoff * (2.0_f32).powf(self.envelope_scaler * (self.filter_envelope - self.envelope_offset) + self.accent_gain * self.filter_envelope)).min(20000.0);
-            self.tb_filter.update_coefficients(effective_cutoff);
+            self.effective_cutoff = (self.cutoff * (2.0_f32).powf(self.envelope_scaler * (self.filter_envelope - self.envelope_offset) + self.accent_gain * self.filter_envelope)).min(20000.0);
+            self.tb_filter.update_coefficients(self.effective_cutoff);
         }
 
+        self.modulation_position = (self.modulation_position + 1) % MODULATION_INTERVAL;
+
         // Filter bank
         sample = self.highpass1.render(sample);
         sample = self.tb_filter.render(sample);
@@ -182,49 +401,33 @@ impl R303 {
         output_gain = self.declicker.render(output_gain);
         sample *= output_gain;
 
-        // Foldback distortion
-        sample = self.distortion.render(sample);
+        // Panning
+        let (left, right) = (sample * self.pan_left, sample * self.pan_right);
 
-        // Delay
-        sample = self.delay.render(sample);
+        // Foldback distortion
+        let (left, right) = self.distortion.render_stereo(left, right);
 
-        sample
+        stereo_width(left, right, self.width)
     }
 
-    pub fn execute(&mut self, instruction: Instruction) {
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), ErrorReason> {
         use Opcode::*;
 
         match instruction.opcode {
-            SetCutoff => self.set_cutoff(instruction.decode(0)),
-            SetResonance => self.set_resonance(instruction.decode(0)),
-            SetEnvMod => self.set_envmod(instruction.decode(0)),
-            SetDecay => self.decay = instruction.decode(0),
-            SetTempo => self.sequencer.set_tempo(instruction.decode(0)),
             SetTuning => (), // TODO
-            SetAccent => self.accent = instruction.decode(0),
-            SetDistortionThreshold => self.distortion.set_threshold(instruction.decode(0)),
-            SetDistortionShape => self.distortion.shape = instruction.decode(0),
-            SetDelaySend => self.delay.send = instruction.decode(0),
-            SetDelayFeedback => self.delay.feedback = instruction.decode(0),
-            SetWaveformIndex => self.set_waveform_index(instruction.decode(0)),
-            SetDelayLength => self.delay.length = instruction.decode_u32(0) as usize,
+            SetWaveformIndex => self.set_waveform_index(check_range(decode::set_waveform_index(instruction.operands()), 0..WAVEFORM_COUNT as u32)?),
 
             SetPatternData => {
-                let flags: u8 = instruction.decode(3);
-
-                let step = Step {
-                    pitch: instruction.decode(2),
-                    is_enabled: (flags & (1 << 0)) != 0,
-                    has_accent: (flags & (1 << 1)) != 0,
-                    has_slide: (flags & (1 << 2)) != 0,
-                    has_up: (flags & (1 << 3)) != 0,
-                    has_down: (flags & (1 << 4)) != 0
-                };
-
-                self.sequencer.set_pattern_data(instruction.decode::<u8>(0) as usize, instruction.decode::<u8>(1) as usize, step);
+                let (pattern_index, step_index, pitch, flags) = decode::set_pattern_data(instruction.operands());
+                self.sequencer.set_step(pattern_index, step_index, pitch, flags)?;
             },
 
-            _ => ()
+            // Pattern index and step count, followed by the pitch and flags of every step
+            SetPattern => self.sequencer.set_pattern(&mut instruction.reader())?,
+
+            _ => return self.sequencer.execute(&instruction)
         }
+
+        Ok(())
     }
 }
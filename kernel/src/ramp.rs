@@ -0,0 +1,141 @@
+use crate::parameters::ParameterId;
+use crate::vm::ErrorReason;
+
+use enumtojs::enum_to_js;
+
+use std::convert::TryFrom;
+
+// Ramps update their parameter once every interval instead of every frame, because some
+// parameters (such as the master shelving filters) are expensive to change
+pub const RAMP_INTERVAL: usize = 32; // in frames
+
+#[repr(u8)]
+#[enum_to_js(filename="../src/Curve.ts")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Curve {
+    Linear = 0,
+    Exponential // Constant ratio per frame, for frequencies and times
+}
+
+impl Curve {
+    pub fn decode(value: u8) -> Result<Self, ErrorReason> {
+        Self::try_from(value).map_err(|_| ErrorReason::OutOfRange)
+    }
+}
+
+struct Ramp {
+    channel: usize,
+    id: ParameterId,
+    value: f32,
+    target: f32,
+    remaining: usize, // in frames
+    curve: Curve
+}
+
+impl Ramp {
+    fn is_parameter(&self, channel: usize, id: ParameterId) -> bool {
+        self.channel == channel && self.id == id
+    }
+}
+
+// Moves parameters towards a target value over time
+pub struct Ramps {
+    ramps: Vec<Ramp>
+}
+
+impl Ramps {
+    pub fn new() -> Self {
+        Ramps {
+            ramps: Vec::new()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ramps.is_empty()
+    }
+
+    // Replaces the ramp of a parameter, if any. Exponential ramps between values that do not have
+    // the same sign are linear, since the ratio between them is undefined.
+    pub fn start(&mut self, channel: usize, id: ParameterId, value: f32, target: f32, frame_count: usize, curve: Curve) {
+        self.cancel(channel, id);
+
+        let curve = match curve {
+            Curve::Exponential if value * target <= 0.0 => Curve::Linear,
+            _ => curve
+        };
+
+        self.ramps.push(Ramp {
+            channel,
+            id,
+            value,
+            target,
+            remaining: frame_count,
+            curve
+        });
+    }
+
+    pub fn cancel(&mut self, channel: usize, id: ParameterId) {
+        self.ramps.retain(|ramp| !ramp.is_parameter(channel, id));
+    }
+
+    // Advances all ramps and calls apply with the channel, parameter ID and new value of every
+    // parameter. Ramps end exactly at their target and are removed once they get there.
+    pub fn advance(&mut self, frame_count: usize, mut apply: impl FnMut(usize, ParameterId, f32)) {
+        for ramp in self.ramps.iter_mut() {
+            if frame_count >= ramp.remaining {
+                ramp.value = ramp.target;
+                ramp.remaining = 0;
+            } else {
+                let fraction = frame_count as f32 / ramp.remaining as f32;
+
+                ramp.value = match ramp.curve {
+                    Curve::Linear => ramp.value + (ramp.target - ramp.value) * fraction,
+                    Curve::Exponential => ramp.value * (ramp.target / ramp.value).powf(fraction)
+                };
+
+                ramp.remaining -= frame_count;
+            }
+
+            apply(ramp.channel, ramp.id, ramp.value);
+        }
+
+        self.ramps.retain(|ramp| ramp.remaining > 0);
+    }
+}
+
+impl Default for Ramps {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn advance(ramps: &mut Ramps, frame_count: usize) -> Vec<f32> {
+        let mut values = Vec::new();
+        ramps.advance(frame_count, |_, _, value| values.push(value));
+        values
+    }
+
+    #[test]
+    fn test_ramps() {
+        let mut ramps = Ramps::new();
+
+        ramps.start(0, ParameterId::Cutoff, 100.0, 1600.0, 4, Curve::Exponential);
+        ramps.start(1, ParameterId::Pan, -1.0, 1.0, 4, Curve::Exponential);
+
+        // The pan ramp crosses zero, so it falls back to linear
+        assert_eq!(advance(&mut ramps, 2), vec![400.0, 0.0]);
+        assert_eq!(advance(&mut ramps, 1), vec![800.0, 0.5]);
+
+        // Starting a ramp for a parameter replaces its current ramp
+        ramps.start(1, ParameterId::Pan, 0.5, 0.0, 2, Curve::Linear);
+        assert_eq!(advance(&mut ramps, 1), vec![1600.0, 0.25]);
+        assert_eq!(advance(&mut ramps, 10), vec![0.0]);
+        assert!(ramps.is_empty());
+
+        assert_eq!(Curve::decode(2), Err(ErrorReason::OutOfRange));
+    }
+}
@@ -1,62 +1,195 @@
-use crate::kernel::SAMPLE_RATE;
+use crate::clock::{Clock, PULSES_PER_QUARTER_NOTE};
+use crate::groove::StepDelay;
+use crate::song::Song;
+use crate::vm::{check_range, decode, encode, ErrorReason, Instruction, OperandReader, Opcode};
 
-const MAX_PATTERN_LENGTH: usize = 16;
-const MAX_PATTERN_COUNT: usize = 8;
+use enumtojs::enum_to_js;
 
+use std::convert::TryFrom;
+
+pub const MAX_PATTERN_LENGTH: usize = 64;
+pub const DEFAULT_PATTERN_LENGTH: usize = 16;
+pub const MAX_PATTERN_COUNT: usize = 8;
+
+// The note value of a step
+#[repr(u8)]
+#[enum_to_js(filename="../src/StepResolution.ts")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StepResolution {
+    Eighth = 0,
+    Sixteenth,
+    ThirtySecond,
+    EighthTriplet,
+    SixteenthTriplet,
+    ThirtySecondTriplet
+}
+
+impl StepResolution {
+    pub fn decode(value: u8) -> Result<Self, ErrorReason> {
+        Self::try_from(value).map_err(|_| ErrorReason::OutOfRange)
+    }
+
+    // Length of a step in clock pulses
+    pub fn pulses(self) -> u32 {
+        use StepResolution::*;
+
+        match self {
+            Eighth => PULSES_PER_QUARTER_NOTE / 2,
+            Sixteenth => PULSES_PER_QUARTER_NOTE / 4,
+            ThirtySecond => PULSES_PER_QUARTER_NOTE / 8,
+            EighthTriplet => PULSES_PER_QUARTER_NOTE / 3,
+            SixteenthTriplet => PULSES_PER_QUARTER_NOTE / 6,
+            ThirtySecondTriplet => PULSES_PER_QUARTER_NOTE / 12
+        }
+    }
+}
+
+// The part of a step that the gate stays open for, unless the step slides or the next step is
+// a tie. Half a step is what the TB-303 does.
+#[repr(u8)]
+#[enum_to_js(filename="../src/GateLength.ts")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GateLength {
+    Half = 0,
+    Quarter,
+    ThreeQuarters,
+    Full // open until the next step, which retriggers without sliding
+}
+
+impl GateLength {
+    pub fn decode(value: u8) -> Result<Self, ErrorReason> {
+        Self::try_from(value).map_err(|_| ErrorReason::OutOfRange)
+    }
+
+    pub fn fraction(self) -> f32 {
+        use GateLength::*;
+
+        match self {
+            Half => 0.5,
+            Quarter => 0.25,
+            ThreeQuarters => 0.75,
+            Full => 1.0
+        }
+    }
+}
+
+// What a voice does when the gate of its sequencer changes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GateEvent {
+    Open, // a step opens the gate with a new note
+    Slide, // a step glides to its note, the gate was held open by the slide of the step before it
+    Tie, // a step holds the note of the step before it
+    Rest, // a step without a note, the gate closes
+    Close // the gate length of the last step has passed
+}
+
+// Steps are encoded in two bytes by the pattern instructions and in snapshots
+pub trait PatternStep: Copy + Default {
+    fn decode(first: u8, second: u8) -> Result<Self, ErrorReason>;
+    fn encode(&self) -> [u8; 2];
+}
+
+#[derive(Clone, Copy)]
 pub struct Step {
     pub pitch: u8,
     pub is_enabled: bool,
     pub has_accent: bool,
-    pub has_slide: bool,
+    pub has_slide: bool, // the gate stays open into the next step, which slides to its note
     pub has_down: bool,
-    pub has_up: bool
+    pub has_up: bool,
+    pub has_tie: bool, // holds the note of the step before, a tie after a rest is a rest
+    pub gate_length: GateLength
 }
 
-impl Step {
-    fn new() -> Self {
+impl Default for Step {
+    fn default() -> Self {
         Step {
             pitch: 36,
             is_enabled: true,
             has_accent: false,
             has_slide: false,
             has_down: false,
-            has_up: false
+            has_up: false,
+            has_tie: false,
+            gate_length: GateLength::Half
         }
     }
 }
 
-struct Pattern {
-    steps: Vec<Step>,
-    length: usize
+impl PatternStep for Step {
+    // Decodes a step from the pitch and flags bytes, the transposed pitch has to be in the MIDI
+    // range
+    fn decode(pitch: u8, flags: u8) -> Result<Self, ErrorReason> {
+        let step = Step {
+            pitch,
+            is_enabled: (flags & (1 << 0)) != 0,
+            has_accent: (flags & (1 << 1)) != 0,
+            has_slide: (flags & (1 << 2)) != 0,
+            has_up: (flags & (1 << 3)) != 0,
+            has_down: (flags & (1 << 4)) != 0,
+            has_tie: (flags & (1 << 5)) != 0,
+            gate_length: GateLength::decode(flags >> 6)?
+        };
+
+        let lowest = if step.has_down { 12 } else { 0 };
+        let highest = if step.has_up { 115 } else { 127 };
+
+        if step.pitch < lowest || step.pitch > highest {
+            return Err(ErrorReason::OutOfRange);
+        }
+
+        Ok(step)
+    }
+
+    // The pitch and flags bytes, the inverse of decode
+    fn encode(&self) -> [u8; 2] {
+        let flags = self.is_enabled as u8 |
+            (self.has_accent as u8) << 1 |
+            (self.has_slide as u8) << 2 |
+            (self.has_up as u8) << 3 |
+            (self.has_down as u8) << 4 |
+            (self.has_tie as u8) << 5 |
+            (self.gate_length as u8) << 6;
+
+        [self.pitch, flags]
+    }
+}
+
+struct Pattern<S> {
+    steps: Vec<S>, // steps past the length are kept, so that shortening a pattern can be undone
+    length: usize,
+    resolution: StepResolution
 }
 
-impl Pattern {
+impl<S: PatternStep> Pattern<S> {
     fn new() -> Self {
         Pattern {
-            steps: (0..MAX_PATTERN_LENGTH).map(|_| Step::new()).collect(),
-            length: MAX_PATTERN_LENGTH
+            steps: vec![S::default(); MAX_PATTERN_LENGTH],
+            length: DEFAULT_PATTERN_LENGTH,
+            resolution: StepResolution::Sixteenth
         }
     }
 }
 
-pub struct Sequencer {
-    pub is_running: bool,
-    pub sample_position: u32,
-    step_length: u32,
+// Plays patterns of steps on the clock, shared by the voices and the drum machine
+pub struct Sequencer<S> {
     pub pattern_position: usize,
+    restart_position: Option<usize>, // the position of the next step after a reset
+    pub song: Song,
+    pub step_delay: StepDelay,
 
-    patterns: Vec<Pattern>,
+    patterns: Vec<Pattern<S>>,
     current_pattern: usize,
     next_pattern: usize
 }
 
-impl Sequencer {
+impl<S: PatternStep> Sequencer<S> {
     pub fn new() -> Self {
         Sequencer {
-            is_running: true,
-            sample_position: 10000000,
-            step_length: (SAMPLE_RATE / 8.0) as u32, // 120 bpm
-            pattern_position: 10000000,
+            pattern_position: 0,
+            restart_position: Some(0),
+            song: Song::new(),
+            step_delay: StepDelay::new(),
 
             patterns: (0..MAX_PATTERN_COUNT).map(|_| Pattern::new()).collect(),
             current_pattern: 0,
@@ -64,37 +197,333 @@ impl Sequencer {
         }
     }
 
-    pub fn set_pattern_data(&mut self, pattern_index: usize, step_index: usize, step: Step) {
+    // The step that plays when the clock starts a step next, 0 starts a new pattern
+    pub fn restart(&mut self, position: usize) {
+        self.restart_position = Some(position);
+    }
+
+    // Plays the step that is due when the clock continues from a position in pulses
+    pub fn relocate(&mut self, pulse: u32) {
+        self.restart(pulse.div_ceil(self.pulses()) as usize);
+    }
+
+    pub fn set_pattern_data(&mut self, pattern_index: usize, step_index: usize, step: S) {
         self.patterns[pattern_index].steps[step_index] = step;
     }
 
-    pub fn set_tempo(&mut self, tempo: f32) {
-        self.step_length = (SAMPLE_RATE * 60.0 / tempo / 4.0) as u32;
+    pub fn set_length(&mut self, pattern_index: usize, length: usize) {
+        self.patterns[pattern_index].length = length;
     }
 
-    pub fn update(&mut self) -> Option<&Step> {
-        if !self.is_running {
-            // TODO: this.amp_env = 0
-            return None;
+    pub fn set_resolution(&mut self, pattern_index: usize, resolution: StepResolution) {
+        self.patterns[pattern_index].resolution = resolution;
+    }
+
+    pub fn current_pattern(&self) -> usize {
+        self.current_pattern
+    }
+
+    pub fn set_current_pattern(&mut self, pattern_index: usize) {
+        self.current_pattern = pattern_index;
+        self.next_pattern = pattern_index;
+    }
+
+    pub fn set_next_pattern(&mut self, pattern_index: usize) {
+        self.next_pattern = pattern_index;
+    }
+
+    // Length of a step of the current pattern in clock pulses
+    pub fn pulses(&self) -> u32 {
+        self.patterns[self.current_pattern].resolution.pulses()
+    }
+
+    // The step that played last
+    pub fn step(&self) -> &S {
+        &self.patterns[self.current_pattern].steps[self.pattern_position]
+    }
+
+    // The step that plays after the current one, as far as it is known already
+    pub fn next_step(&self) -> &S {
+        let pattern = &self.patterns[self.current_pattern];
+
+        if self.pattern_position + 1 < pattern.length {
+            return &pattern.steps[self.pattern_position + 1];
         }
 
-        self.sample_position += 1;
-        if self.sample_position < self.step_length {
+        let next_pattern = self.song.peek().unwrap_or(self.next_pattern);
+        &self.patterns[next_pattern].steps[0]
+    }
+
+    // Returns true when a step plays at this sample. Steps are timed by the clock and then
+    // delayed by its groove.
+    pub fn update(&mut self, clock: &Clock) -> bool {
+        let pulses = self.pulses();
+
+        // Steps start on the pulses that are a multiple of the resolution of the current pattern
+        if clock.pulse_started && clock.pulse.is_multiple_of(pulses) {
+            let length = self.patterns[self.current_pattern].length;
+
+            // advance sequencer
+            self.pattern_position = match self.restart_position.take() {
+                Some(position) => position % length,
+                None => self.pattern_position + 1
+            };
+
+            // advance pattern if we reached the end or restarted from the first step, in song mode
+            // the song decides what comes next
+            if self.pattern_position == 0 || self.pattern_position >= length {
+                self.pattern_position = 0;
+
+                if let Some(pattern_index) = self.song.advance() {
+                    self.next_pattern = pattern_index;
+                }
+
+                self.current_pattern = self.next_pattern;
+            }
+
+            self.step_delay.schedule(clock, pulses);
+        }
+
+        // Steps that are held back by the groove wait while the clock is stopped
+        clock.is_running() && self.step_delay.tick()
+    }
+
+    // Pattern index and step count, followed by the encoded steps. The step count becomes the
+    // length of the pattern.
+    pub fn set_pattern(&mut self, reader: &mut OperandReader) -> Result<(), ErrorReason> {
+        let pattern_index = reader.read_u8()? as usize;
+        let step_count = reader.read_u8()? as usize;
+
+        if pattern_index >= MAX_PATTERN_COUNT || !(1..=MAX_PATTERN_LENGTH).contains(&step_count) {
+            return Err(ErrorReason::OutOfRange);
+        }
+
+        let steps = reader.read_bytes(2 * step_count)?;
+
+        // Check all steps first so that a pattern is never partially updated
+        for step in steps.chunks_exact(2) {
+            S::decode(step[0], step[1])?;
+        }
+
+        for (step_index, step) in steps.chunks_exact(2).enumerate() {
+            self.set_pattern_data(pattern_index, step_index, S::decode(step[0], step[1])?);
+        }
+
+        self.set_length(pattern_index, step_count);
+
+        Ok(())
+    }
+
+    // A single encoded step
+    pub fn set_step(&mut self, pattern_index: u8, step_index: u8, first: u8, second: u8) -> Result<(), ErrorReason> {
+        let pattern_index = check_range(pattern_index, 0..MAX_PATTERN_COUNT as u8)?;
+        let step_index = check_range(step_index, 0..MAX_PATTERN_LENGTH as u8)?;
+        let step = S::decode(first, second)?;
+
+        self.set_pattern_data(pattern_index as usize, step_index as usize, step);
+
+        Ok(())
+    }
+
+    // Executes the instructions that are the same for all sequencers, the others are ignored
+    pub fn execute(&mut self, instruction: &Instruction) -> Result<(), ErrorReason> {
+        use Opcode::*;
+
+        match instruction.opcode {
+            SetCurrentPattern => self.set_current_pattern(check_range(decode::set_current_pattern(instruction.operands()), 0..MAX_PATTERN_COUNT as u32)? as usize),
+            SetNextPattern => self.set_next_pattern(check_range(decode::set_next_pattern(instruction.operands()), 0..MAX_PATTERN_COUNT as u32)? as usize),
+
+            SetPatternLength => {
+                let (pattern_index, length) = decode::set_pattern_length(instruction.operands());
+
+                let pattern_index = check_range(pattern_index, 0..MAX_PATTERN_COUNT as u8)?;
+                let length = check_range(length, 1..=MAX_PATTERN_LENGTH as u8)?;
+
+                self.set_length(pattern_index as usize, length as usize);
+            },
+
+            SetPatternResolution => {
+                let (pattern_index, resolution) = decode::set_pattern_resolution(instruction.operands());
+
+                let pattern_index = check_range(pattern_index, 0..MAX_PATTERN_COUNT as u8)?;
+                let resolution = StepResolution::decode(resolution)?;
+
+                self.set_resolution(pattern_index as usize, resolution);
+            },
+
+            SetSong => self.song.decode(&mut instruction.reader())?,
+            QueueSongEntry => self.song.queue(decode::queue_song_entry(instruction.operands()) as usize)?,
+
+            _ => ()
+        }
+
+        Ok(())
+    }
+
+    // Appends the instructions that restore the patterns and the song, each pattern is sent with
+    // the given pattern instruction encoder
+    pub fn snapshot(&self, program: &mut Vec<u8>, encode_pattern: fn(&mut Vec<u8>, &[u8])) {
+        for (pattern_index, pattern) in self.patterns.iter().enumerate() {
+            let mut blob = vec![pattern_index as u8, pattern.steps.len() as u8];
+            blob.extend(pattern.steps.iter().flat_map(|step| step.encode()));
+
+            // Steps past the length are included, the length is set afterwards
+            encode_pattern(program, &blob);
+            encode::set_pattern_length(program, pattern_index as u8, pattern.length as u8);
+            encode::set_pattern_resolution(program, pattern_index as u8, pattern.resolution as u8);
+        }
+
+        encode::set_current_pattern(program, self.current_pattern as u32);
+        encode::set_next_pattern(program, self.next_pattern as u32);
+        encode::set_song(program, &self.song.encode());
+    }
+}
+
+impl<S: PatternStep> Default for Sequencer<S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The gate of a voice, which follows the steps of its sequencer
+pub struct Gate {
+    is_open: bool,
+    remaining: Option<u32>, // samples until the gate closes, None while it is held open or closed
+    is_sliding: bool // the last step slides into the next one
+}
+
+impl Gate {
+    pub fn new() -> Self {
+        Gate {
+            is_open: false,
+            remaining: None,
+            is_sliding: false
+        }
+    }
+
+    // Closes the gate right away, so that the next step does not tie or slide
+    pub fn close(&mut self) {
+        self.is_open = false;
+        self.remaining = None;
+        self.is_sliding = false;
+    }
+
+    // Returns what happens to the gate at this sample, if anything. The gate closes after the
+    // gate length of the step that played.
+    pub fn update(&mut self, sequencer: &mut Sequencer<Step>, clock: &Clock) -> Option<GateEvent> {
+        if sequencer.update(clock) {
+            return Some(self.play_step(sequencer, clock.step_length(sequencer.pulses())));
+        }
+
+        if !clock.is_running() {
             return None;
         }
 
-        // advance sequencer
-        self.sample_position = 0;
-        self.pattern_position += 1;
+        match self.remaining {
+            Some(0) => {
+                self.close();
+                Some(GateEvent::Close)
+            },
+            Some(remaining) => {
+                self.remaining = Some(remaining - 1);
+                None
+            },
+            None => None
+        }
+    }
+
+    // Follows the TB-303 in time mode: the gate closes during the step unless the step slides
+    // or the next step ties to it
+    fn play_step(&mut self, sequencer: &Sequencer<Step>, step_length: u32) -> GateEvent {
+        let step = sequencer.step();
+
+        let event = if step.has_tie && self.is_open {
+            GateEvent::Tie
+        } else if step.has_tie || !step.is_enabled {
+            GateEvent::Rest
+        } else if self.is_open && self.is_sliding {
+            GateEvent::Slide
+        } else {
+            GateEvent::Open
+        };
 
-        // advance pattern if we reached the end
-        if self.pattern_position >= self.patterns[self.current_pattern].length {
-            self.pattern_position = 0;
-            self.current_pattern = self.next_pattern;
+        if event == GateEvent::Rest {
+            self.close();
+            return event;
         }
 
-        // return new step
-        Some(&self.patterns[self.current_pattern].steps[self.pattern_position])
+        let is_held = step.has_slide || step.gate_length == GateLength::Full || sequencer.next_step().has_tie;
+
+        self.is_open = true;
+        self.is_sliding = step.has_slide;
+        self.remaining = if is_held { None } else { Some((step.gate_length.fraction() * step_length as f32) as u32) };
+
+        event
+    }
+}
+
+impl Default for Gate {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Runs the sequencer over a number of steps and returns the gate events with the step and
+    // the sample they happened at
+    fn gate_events(sequencer: &mut Sequencer<Step>, step_count: usize) -> Vec<(GateEvent, usize, u32)> {
+        let mut clock = Clock::new(44100.0);
+        clock.set_tempo(300.0);
+
+        let mut gate = Gate::new();
+        let mut events = Vec::new();
+
+        for _ in 0..step_count * 2205 {
+            clock.tick();
+
+            if let Some(event) = gate.update(sequencer, &clock) {
+                events.push((event, sequencer.pattern_position, clock.sample_position));
+            }
+        }
+
+        events
+    }
+
+    #[test]
+    fn test_gate() {
+        let mut sequencer = Sequencer::<Step>::new();
+        sequencer.set_length(0, 4);
+
+        // A quarter gate, a slide into a tie, and a rest
+        sequencer.set_pattern_data(0, 0, Step::decode(36, 1 | (GateLength::Quarter as u8) << 6).unwrap());
+        sequencer.set_pattern_data(0, 1, Step::decode(38, 1 | 1 << 2).unwrap());
+        sequencer.set_pattern_data(0, 2, Step::decode(40, 1 << 5).unwrap());
+        sequencer.set_pattern_data(0, 3, Step::decode(40, 0).unwrap());
+
+        assert_eq!(gate_events(&mut sequencer, 4), vec![
+            (GateEvent::Open, 0, 0),
+            (GateEvent::Close, 0, 552),
+            (GateEvent::Open, 1, 0),
+            (GateEvent::Tie, 2, 0),
+            (GateEvent::Close, 2, 1103),
+            (GateEvent::Rest, 3, 0)
+        ]);
+
+        // A slide into the next note, and a tie after a rest is a rest
+        let mut sequencer = Sequencer::<Step>::new();
+        sequencer.set_length(0, 5);
+
+        sequencer.set_pattern_data(0, 1, Step::decode(38, 1 | 1 << 2).unwrap());
+        sequencer.set_pattern_data(0, 2, Step::decode(40, 1).unwrap());
+        sequencer.set_pattern_data(0, 3, Step::decode(40, 0).unwrap());
+        sequencer.set_pattern_data(0, 4, Step::decode(40, 1 << 5).unwrap());
+
+        let events: Vec<_> = gate_events(&mut sequencer, 5).into_iter().map(|(event, _, _)| event).collect();
+        assert_eq!(events, vec![GateEvent::Open, GateEvent::Close, GateEvent::Open, GateEvent::Slide, GateEvent::Close, GateEvent::Rest, GateEvent::Rest]);
+
+        assert_eq!(Step::decode(36, 0xff).unwrap().encode(), [36, 0xff]);
+    }
+}
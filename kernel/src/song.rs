@@ -0,0 +1,195 @@
+use crate::sequencer::MAX_PATTERN_COUNT;
+use crate::vm::{ErrorReason, OperandReader, Opcode, VM};
+
+pub const MAX_SONG_LENGTH: usize = 64;
+
+// Position byte for a current pattern that was not started by the song
+pub const NO_ENTRY: u8 = 255;
+
+// Size of the position at the start of a song blob
+const HEADER_SIZE: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SongEntry {
+    pub pattern: usize,
+    pub repeat_count: usize,
+    pub transpose: i8 // in semitones
+}
+
+// A chain of patterns that plays in a loop. Each entry plays its pattern a number of times,
+// optionally transposed. Positions are an entry index and the number of repeats so far.
+pub struct Song {
+    entries: Vec<SongEntry>,
+    current: Option<(usize, usize)>, // the position that is playing
+    next: (usize, usize) // the position that plays after the current pattern
+}
+
+impl Song {
+    pub fn new() -> Self {
+        Song {
+            entries: Vec::with_capacity(MAX_SONG_LENGTH),
+            current: None,
+            next: (0, 0)
+        }
+    }
+
+    // Transpose of the pattern that is playing
+    pub fn transpose(&self) -> i8 {
+        self.current.map_or(0, |(entry, _)| self.entries[entry].transpose)
+    }
+
+    // The pattern that plays after the current one when the song is on
+    pub fn peek(&self) -> Option<usize> {
+        self.entries.get(self.next.0).map(|entry| entry.pattern)
+    }
+
+    // The entry that plays after the current pattern
+    pub fn queue(&mut self, entry: usize) -> Result<(), ErrorReason> {
+        if entry >= self.entries.len() {
+            return Err(ErrorReason::OutOfRange);
+        }
+
+        self.next = (entry, 0);
+        Ok(())
+    }
+
+    // The first entry plays after the current pattern
+    pub fn rewind(&mut self) {
+        self.current = None;
+        self.next = (0, 0);
+    }
+
+    // Called at the end of a pattern, returns the pattern to play next when the song is on
+    pub fn advance(&mut self) -> Option<usize> {
+        if self.entries.is_empty() {
+            return None;
+        }
+
+        let (entry, repeat) = self.next;
+
+        self.current = Some(self.next);
+        self.next = if repeat + 1 < self.entries[entry].repeat_count {
+            (entry, repeat + 1)
+        } else {
+            ((entry + 1) % self.entries.len(), 0)
+        };
+
+        Some(self.entries[entry].pattern)
+    }
+
+    // Tells the frontend that a pattern started: the pattern, channel, song entry (NO_ENTRY
+    // outside of song mode) and repeat each go in a byte of the operand
+    pub fn report(&self, vm: &mut VM, pattern_index: usize, channel: usize) {
+        let (entry, repeat) = self.current.map_or((NO_ENTRY as u32, 0), |(entry, repeat)| (entry as u32, repeat as u32));
+        vm.push_u32(Opcode::SetActivePattern, pattern_index as u32 | (channel as u32) << 8 | entry << 16 | repeat << 24);
+    }
+
+    fn is_position(&self, entry: u8, repeat: u8) -> bool {
+        matches!(self.entries.get(entry as usize), Some(song_entry) if (repeat as usize) < song_entry.repeat_count)
+    }
+
+    // The current entry and repeat (NO_ENTRY when the current pattern is not from the song), the
+    // next entry and repeat, followed by the pattern, repeat count and transpose of every entry.
+    // A song without entries turns song mode off.
+    pub fn decode(&mut self, reader: &mut OperandReader) -> Result<(), ErrorReason> {
+        let header = reader.read_bytes(HEADER_SIZE)?;
+        let data = reader.read_bytes(reader.remaining())?;
+
+        if !data.len().is_multiple_of(3) || data.len() / 3 > MAX_SONG_LENGTH {
+            return Err(ErrorReason::OutOfRange);
+        }
+
+        let mut song = Song::new();
+
+        for entry in data.chunks_exact(3) {
+            if entry[0] as usize >= MAX_PATTERN_COUNT || entry[1] == 0 {
+                return Err(ErrorReason::OutOfRange);
+            }
+
+            song.entries.push(SongEntry {
+                pattern: entry[0] as usize,
+                repeat_count: entry[1] as usize,
+                transpose: entry[2] as i8
+            });
+        }
+
+        if !song.entries.is_empty() {
+            if header[0] != NO_ENTRY {
+                if !song.is_position(header[0], header[1]) {
+                    return Err(ErrorReason::OutOfRange);
+                }
+
+                song.current = Some((header[0] as usize, header[1] as usize));
+            }
+
+            if !song.is_position(header[2], header[3]) {
+                return Err(ErrorReason::OutOfRange);
+            }
+
+            song.next = (header[2] as usize, header[3] as usize);
+        }
+
+        // Only replace the song once all of it was checked
+        self.entries.clear();
+        self.entries.extend_from_slice(&song.entries);
+        self.current = song.current;
+        self.next = song.next;
+
+        Ok(())
+    }
+
+    // The inverse of decode
+    pub fn encode(&self) -> Vec<u8> {
+        let (current_entry, current_repeat) = self.current.map_or((NO_ENTRY, 0), |(entry, repeat)| (entry as u8, repeat as u8));
+
+        let mut blob = vec![current_entry, current_repeat, self.next.0 as u8, self.next.1 as u8];
+        blob.extend(self.entries.iter().flat_map(|entry| [entry.pattern as u8, entry.repeat_count as u8, entry.transpose as u8]));
+
+        blob
+    }
+}
+
+impl Default for Song {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(song: &mut Song, blob: &[u8]) -> Result<(), ErrorReason> {
+        song.decode(&mut OperandReader::new(blob))
+    }
+
+    #[test]
+    fn test_song() {
+        let mut song = Song::new();
+        assert_eq!(song.advance(), None);
+
+        // Pattern 2 twice, then pattern 5 a fifth up
+        let blob = [NO_ENTRY, 0, 0, 0, 2, 2, 0, 5, 1, 7];
+        decode(&mut song, &blob).unwrap();
+        assert_eq!(song.encode(), blob);
+
+        let patterns: Vec<_> = (0..5).map(|_| song.advance().unwrap()).collect();
+        assert_eq!(patterns, vec![2, 2, 5, 2, 2]);
+        assert_eq!(song.encode()[..HEADER_SIZE], [0, 1, 1, 0]);
+
+        song.queue(1).unwrap();
+        assert_eq!(song.advance(), Some(5));
+        assert_eq!(song.transpose(), 7);
+        assert_eq!(song.queue(2), Err(ErrorReason::OutOfRange));
+
+        // Invalid songs leave the current one alone
+        assert_eq!(decode(&mut song, &[NO_ENTRY, 0, 0, 0, 8, 1, 0]), Err(ErrorReason::OutOfRange));
+        assert_eq!(decode(&mut song, &[NO_ENTRY, 0, 0, 0, 1, 0, 0]), Err(ErrorReason::OutOfRange));
+        assert_eq!(decode(&mut song, &[0, 2, 0, 0, 1, 2, 0]), Err(ErrorReason::OutOfRange));
+        assert_eq!(song.encode()[..HEADER_SIZE], [1, 0, 0, 0]);
+
+        decode(&mut song, &[NO_ENTRY, 0, 0, 0]).unwrap();
+        assert_eq!(song.advance(), None);
+        assert_eq!(song.transpose(), 0);
+    }
+}
@@ -0,0 +1,165 @@
+use crate::r303::R303;
+use crate::vm::{Opcode, VM};
+
+// Default number of reports per second, roughly the frame rate of the frontend
+const DEFAULT_RATE: f32 = 60.0;
+
+// Reports per second, more would mostly fill the outbound buffer
+pub const MAX_TELEMETRY_RATE: f32 = 1000.0;
+
+// Peak and RMS level of the output since the last reset
+pub struct Meter {
+    peak_left: f32,
+    peak_right: f32,
+    sum_left: f32,
+    sum_right: f32,
+    frame_count: usize
+}
+
+impl Meter {
+    pub fn new() -> Self {
+        Meter {
+            peak_left: 0.0,
+            peak_right: 0.0,
+            sum_left: 0.0,
+            sum_right: 0.0,
+            frame_count: 0
+        }
+    }
+
+    pub fn add(&mut self, left: f32, right: f32) {
+        self.peak_left = self.peak_left.max(left.abs());
+        self.peak_right = self.peak_right.max(right.abs());
+        self.sum_left += left * left;
+        self.sum_right += right * right;
+        self.frame_count += 1;
+    }
+
+    pub fn peak(&self) -> (f32, f32) {
+        (self.peak_left, self.peak_right)
+    }
+
+    pub fn rms(&self) -> (f32, f32) {
+        if self.frame_count == 0 {
+            return (0.0, 0.0);
+        }
+
+        let frame_count = self.frame_count as f32;
+        ((self.sum_left / frame_count).sqrt(), (self.sum_right / frame_count).sqrt())
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Default for Meter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Periodically reports the output levels and the state of the voices to the frontend
+pub struct Telemetry {
+    rate: f32, // in Hz, 0 disables reports
+    sample_rate: f32,
+    interval: usize, // in samples
+    meter: Meter
+}
+
+impl Telemetry {
+    pub fn new(sample_rate: f32) -> Self {
+        let mut telemetry = Telemetry {
+            rate: DEFAULT_RATE,
+            sample_rate,
+            interval: 0,
+            meter: Meter::new()
+        };
+
+        telemetry.update_interval();
+
+        telemetry
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.update_interval();
+    }
+
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+
+    pub fn set_rate(&mut self, rate: f32) {
+        self.rate = rate;
+        self.update_interval();
+        self.meter.reset();
+    }
+
+    fn update_interval(&mut self) {
+        self.interval = if self.rate > 0.0 {
+            ((self.sample_rate / self.rate) as usize).max(1)
+        } else {
+            0
+        };
+    }
+
+    pub fn add(&mut self, left: f32, right: f32) {
+        if self.interval > 0 {
+            self.meter.add(left, right);
+        }
+    }
+
+    // Pushes a report to the VM once an interval has passed. The voice reports are preceded by the
+    // channel they belong to, just like voice instructions sent by the frontend.
+    pub fn report(&mut self, vm: &mut VM, voices: &[R303]) {
+        if self.interval == 0 || self.meter.frame_count < self.interval {
+            return;
+        }
+
+        let (peak_left, peak_right) = self.meter.peak();
+        let (rms_left, rms_right) = self.meter.rms();
+
+        self.meter.reset();
+
+        for (opcode, value) in [
+            (Opcode::SetPeakLeft, peak_left),
+            (Opcode::SetPeakRight, peak_right),
+            (Opcode::SetRmsLeft, rms_left),
+            (Opcode::SetRmsRight, rms_right)
+        ] {
+            vm.push_f32(opcode, value);
+        }
+
+        for (channel, voice) in voices.iter().enumerate() {
+            vm.push_u32(Opcode::SetChannel, channel as u32);
+
+            for (opcode, value) in [
+                (Opcode::SetEffectiveCutoff, voice.effective_cutoff()),
+                (Opcode::SetAmplitudeEnvelope, voice.amplitude_envelope()),
+                (Opcode::SetFilterEnvelope, voice.filter_envelope())
+            ] {
+                vm.push_f32(opcode, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_meter() {
+        let mut meter = Meter::new();
+
+        meter.add(0.5, -1.0);
+        meter.add(-0.5, 0.0);
+
+        assert_eq!(meter.peak(), (0.5, 1.0));
+        assert_eq!(meter.rms(), (0.5, 0.5_f32.sqrt()));
+
+        meter.reset();
+        assert_eq!(meter.rms(), (0.0, 0.0));
+    }
+}
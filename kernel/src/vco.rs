@@ -1,13 +1,14 @@
+use std::cell::RefCell;
 use std::f32::consts::PI;
-
-use crate::kernel::SAMPLE_RATE;
+use std::rc::Rc;
 
 const WAVEFORM_SIZE: usize = 4096;
 const WAVEFORM_GROUP_SIZE: usize = 128 * WAVEFORM_SIZE;
-const WAVETABLE_SIZE: usize = 2 * WAVEFORM_GROUP_SIZE;
+pub const WAVEFORM_COUNT: usize = 2;
+const WAVETABLE_SIZE: usize = WAVEFORM_COUNT * WAVEFORM_GROUP_SIZE;
 
 #[allow(clippy::float_cmp, clippy::needless_range_loop)]
-fn make_wavetable() -> Vec<f32> {
+fn make_wavetable(sample_rate: f32) -> Vec<f32> {
     // create sine table to speed up initialization
     let mut sine_table: Vec<f32> = vec![0.0; WAVEFORM_SIZE];
     for i in 0..WAVEFORM_SIZE {
@@ -21,7 +22,7 @@ fn make_wavetable() -> Vec<f32> {
     let mut last: f32 = 0.0;
     for i in 0..128 {
         // compute the number of partials in the waveform
-        let h = ((SAMPLE_RATE / 2.0) / (440.0 * 2.0_f32.powf((i as f32 - 69.0) / 12.0))).round();
+        let h = ((sample_rate / 2.0) / (440.0 * 2.0_f32.powf((i as f32 - 69.0) / 12.0))).round();
 
         // skip this note if the number of partials is equal to the previously generated
         // waveform
@@ -91,8 +92,30 @@ fn make_wavetable() -> Vec<f32> {
     wavetable
 }
 
-#[allow(clippy::float_cmp, clippy::needless_range_loop)]
-fn make_wavetable_orig() -> Vec<f32> {
+thread_local! {
+    // The most recently generated wavetable and its sample rate, shared by all VCOs
+    static WAVETABLE: RefCell<Option<(f32, Rc<Vec<f32>>)>> = const { RefCell::new(None) };
+}
+
+#[allow(clippy::float_cmp)]
+fn shared_wavetable(sample_rate: f32) -> Rc<Vec<f32>> {
+    WAVETABLE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+
+        match &*cache {
+            Some((cached_sample_rate, wavetable)) if *cached_sample_rate == sample_rate => Rc::clone(wavetable),
+            _ => {
+                let wavetable = Rc::new(make_wavetable(sample_rate));
+                *cache = Some((sample_rate, Rc::clone(&wavetable)));
+
+                wavetable
+            }
+        }
+    })
+}
+
+#[allow(dead_code, clippy::float_cmp, clippy::needless_range_loop)]
+fn make_wavetable_orig(sample_rate: f32) -> Vec<f32> {
     // create sine table to speed up initialization
     let mut sine_table: Vec<f32> = vec![0.0; WAVEFORM_SIZE];
     for i in 0..WAVEFORM_SIZE {
@@ -106,7 +129,7 @@ fn make_wavetable_orig() -> Vec<f32> {
     let mut last: f32 = 0.0;
     for i in 0..128 {
         // compute the number of partials in the waveform
-        let h = ((SAMPLE_RATE / 2.0) / (440.0 * 2.0_f32.powf((i as f32 - 69.0) / 12.0))).round();
+        let h = ((sample_rate / 2.0) / (440.0 * 2.0_f32.powf((i as f32 - 69.0) / 12.0))).round();
 
         // skip this note if the number of partials is equal to the previously generated
         // waveform
@@ -154,18 +177,10 @@ fn make_wavetable_orig() -> Vec<f32> {
     wavetable
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn test_make_wavetable() {
-        make_wavetable();
-    }
-}
-
+#[allow(clippy::upper_case_acronyms)]
 pub struct VCO {
-    wavetable: Vec<f32>,
+    wavetable: Rc<Vec<f32>>,
+    sample_rate: f32,
 
     position: f32,
     delta: f32,
@@ -179,9 +194,10 @@ pub struct VCO {
 }
 
 impl VCO {
-    pub fn new() -> Self {
+    pub fn new(sample_rate: f32) -> Self {
         Self {
-            wavetable: make_wavetable(),
+            wavetable: shared_wavetable(sample_rate),
+            sample_rate,
 
             position: 0.0,
             delta: 0.0,
@@ -194,12 +210,28 @@ impl VCO {
         }
     }
 
+    #[allow(clippy::float_cmp)]
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        // The wavetable is expensive to compute, so only rebuild it when needed
+        if sample_rate == self.sample_rate {
+            return;
+        }
+
+        self.wavetable = shared_wavetable(sample_rate);
+
+        // Keep the current pitch and slide when switching sample rates
+        self.delta *= self.sample_rate / sample_rate;
+        self.slide *= self.sample_rate / sample_rate;
+
+        self.sample_rate = sample_rate;
+    }
+
     pub fn reset(&mut self, pitch: f32) {
         let frequency = 440.0 * (2.0_f32).powf((pitch - 69.0) / 12.0);
 
         self.position = 0.0;
         // TODO: use wavetable length constant
-        self.delta = frequency * WAVEFORM_SIZE as f32 / SAMPLE_RATE;
+        self.delta = frequency * WAVEFORM_SIZE as f32 / self.sample_rate;
 
         // Compute waveform index
         self.current_waveform_start = self.waveform_index * WAVEFORM_GROUP_SIZE + (pitch as usize * WAVEFORM_SIZE);
@@ -212,7 +244,7 @@ impl VCO {
     pub fn slide(&mut self, pitch: f32) {
         let frequency = 440.0 * (2.0_f32).powf((pitch - 69.0) / 12.0);
 
-        self.slide = (self.delta - (frequency * WAVEFORM_SIZE as f32 / SAMPLE_RATE)) / 64.0;
+        self.slide = (self.delta - (frequency * WAVEFORM_SIZE as f32 / self.sample_rate)) / 64.0;
         self.slide_step = 0;
     }
 
@@ -238,3 +270,13 @@ impl VCO {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_make_wavetable() {
+        make_wavetable(44100.0);
+    }
+}
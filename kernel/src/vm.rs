@@ -1,149 +1,477 @@
-const MAX_PROGRAM_SIZE: usize = 32768;
+pub const MAX_PROGRAM_SIZE: usize = 32768;
+pub const MAX_OUTBOUND_SIZE: usize = 16384;
+
+// Room that is kept free for the dropped message report
+const RESERVED_OUTBOUND_SIZE: usize = 5;
 
 use enumtojs::enum_to_js;
 
+use std::convert::{TryFrom, TryInto};
+use std::ops::RangeBounds;
+
+// The encoders, decoders and operand sizes on both ends of the protocol are generated from the
+// #[operands(...)] of every opcode. Opcodes keep their number, the groups are only for reading.
 #[repr(u8)]
-#[enum_to_js(filename="../src/Opcode.ts")]
+#[enum_to_js(filename="../src/Opcode.ts", encoder="encode", decoder="decode")]
 #[derive(Clone, Copy)]
 pub enum Opcode {
     // No operands
     Nop = 0,
-
-    // Single u32 operand
+    DescribeParameters, // The kernel replies with a ParameterInfo instruction per parameter
+    RequestSnapshot, // The kernel replies with a Snapshot instruction
+    StartTransport, // Plays from the first step of the current patterns and songs
+    StopTransport,
+    ContinueTransport, // Plays from where the transport was stopped
+
+    // 4 bytes of operands, a single u32 or packed u8s
+    #[operands(u32)]
     SetWaveformIndex = 20,
-    SetDelayLength,
+    #[operands(u32)]
+    SetDelayLength, // in milliseconds
+    #[operands(u8, u8, u8, u8)]
     SetPatternData,
-
-    // Single f32 operand
+    #[operands(u32)]
+    SetChannel,
+    #[operands(u8, u8, u8, u8)]
+    SetDrumPatternData,
+    #[operands(u32)]
+    SetChannelMute,
+    #[operands(u32)]
+    SetChannelSolo,
+    #[operands(u32)]
+    SetFrameOffset, // The following instructions take effect at this frame of the block
+    #[operands(u8)]
+    GetParameter, // Parameter ID, the kernel replies with a ParameterValue instruction
+    #[operands(u32)]
+    SetCurrentPattern, // Switches right away, the position in the pattern is kept
+    #[operands(u32)]
+    SetNextPattern, // Switches at the end of the current pattern
+    #[operands(u32)]
+    QueueSongEntry, // The song entry that plays after the current pattern
+    #[operands(u32)]
+    ResetTransport, // The step that plays next in every pattern, 0 starts a new pattern
+    #[operands(u32)]
+    SetClockSource, // A ClockSource, see Clock
+    #[operands(u32)]
+    SetMidiClockOutput, // 1 sends MIDI clock and transport messages while the clock is internal
+
+    // Single f32 operand. Continuous parameters are aliases of SetParameter with their ID.
+    #[operands(f32)]
     SetCutoff = 40,
+    #[operands(f32)]
     SetResonance,
+    #[operands(f32)]
     SetEnvMod,
+    #[operands(f32)]
     SetDecay,
+    #[operands(f32)]
     SetTempo,
+    #[operands(f32)]
     SetTuning,
+    #[operands(f32)]
     SetAccent,
+    #[operands(f32)]
     SetDistortionThreshold,
+    #[operands(f32)]
     SetDistortionShape,
+    #[operands(f32)]
     SetDelaySend,
+    #[operands(f32)]
     SetDelayFeedback,
+    #[operands(f32)]
+    SetPan,
+    #[operands(f32)]
+    SetStereoWidth,
+    #[operands(f32)]
+    SetDelayPingPong,
+    #[operands(f32)]
+    SetDrumVolume,
+    #[operands(f32)]
+    SetKickTune,
+    #[operands(f32)]
+    SetKickDecay,
+    #[operands(f32)]
+    SetSnareSnappy,
+    #[operands(f32)]
+    SetHatDecay,
 
     // Opcodes for frontend, no operands
     BootstrapFinished = 60,
 
     // Opcodes for frontend, single u32 operand
+    #[operands(u32)]
     SetSequencerStep = 80,
+    #[operands(u32)]
+    DecodeError,
+    #[operands(u32)]
+    SetDroppedMessageCount,
+    #[operands(u32)]
+    SetActivePattern, // Sent when a pattern starts, see Song::report
+    #[operands(u32)]
+    SetTransportState, // 1 while running, 0 when stopped
 
     // Opcodes for frontend, single f32 operand
-    // ...
+    #[operands(f32)]
+    SetPeakLeft = 100,
+    #[operands(f32)]
+    SetPeakRight,
+    #[operands(f32)]
+    SetRmsLeft,
+    #[operands(f32)]
+    SetRmsRight,
+    #[operands(f32)]
+    SetEffectiveCutoff,
+    #[operands(f32)]
+    SetAmplitudeEnvelope,
+    #[operands(f32)]
+    SetFilterEnvelope,
+
+    // Single f32 operand, continued
+    #[operands(f32)]
+    SetChannelGain = 120,
+    #[operands(f32)]
+    SetMasterGain,
+    #[operands(f32)]
+    SetMasterLowShelf,
+    #[operands(f32)]
+    SetMasterHighShelf,
+    #[operands(f32)]
+    SetTelemetryRate,
+    #[operands(f32)]
+    SetSmoothingTime, // in ms, plain changes to continuous parameters are ramped over this time
+    #[operands(f32)]
+    SetSwing, // Delay of every other step, as a fraction of a step (0..=0.5)
+    #[operands(f32)]
+    SetChannelPan, // Balance of the stereo output of a channel
+    #[operands(f32)]
+    SetMasterWidth,
+
+    // Length-prefixed blob, a u32 byte count followed by that many bytes of operands
+    #[operands(blob)]
+    SetPattern = 140,
+    #[operands(blob)]
+    SetDrumPattern,
+    #[operands(blob)]
+    RestoreSnapshot,
+    #[operands(blob)]
+    MidiInput, // Raw MIDI bytes, messages may be split across instructions
+    #[operands(blob)]
+    SetSong, // The song position followed by its entries, see Song::decode
+    #[operands(blob)]
+    SetGroove, // Timing and velocity of every step, see Groove::decode
+
+    // Opcodes for frontend, length-prefixed blob
+    #[operands(blob)]
+    ParameterInfo = 150,
+    #[operands(blob)]
+    Snapshot,
+    #[operands(blob)]
+    MidiOutput, // MIDI bytes, each preceded by its frame in the block as a u16
+
+    // Parameter ramps: the ID of the parameter, the curve, the target value and the duration in ms
+    #[operands(u8, u8, f32, f32)]
+    RampParameter = 160,
+
+    // Parameters by ID from the parameter table, followed by the value
+    #[operands(u8, f32)]
+    SetParameter = 180,
+
+    // Opcodes for frontend, parameter ID followed by the value
+    #[operands(u8, f32)]
+    ParameterValue = 190,
+
+    // MIDI controller followed by the ID of the parameter it controls, 255 removes the mapping
+    #[operands(u8, u8)]
+    SetMidiMapping = 200,
+
+    // Pattern index followed by the number of steps (1..=64), or by a StepResolution
+    #[operands(u8, u8)]
+    SetPatternLength,
+    #[operands(u8, u8)]
+    SetPatternResolution,
+
+    // Host transport for the host clock source: the tempo in BPM and the position in quarter
+    // notes at the start of the block
+    #[operands(f32, f32)]
+    SetHostTransport = 220,
 
     // Sentinel
+    #[sentinel]
     Max
 }
 
 impl Opcode {
-    fn from_u8(value: u8) -> Self {
-        if value > Self::Max as u8 {
-            Self::Nop
-        } else {
-            unsafe { std::mem::transmute(value) }
+    // Returns None for bytes that are not an opcode
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match Self::try_from(value) {
+            Ok(Self::Max) | Err(_) => None,
+            Ok(opcode) => Some(opcode)
         }
     }
+}
 
-    fn operand_size(&self) -> usize {
-        match *self as u8 {
-            20..=39 => 4,
-            40..=59 => 4,
-            _ => 0
-        }
+// Checks a decoded operand against the values it may have
+pub fn check_range<T>(value: T, range: impl RangeBounds<T>) -> Result<T, ErrorReason> where T: PartialOrd {
+    if range.contains(&value) {
+        Ok(value)
+    } else {
+        Err(ErrorReason::OutOfRange)
+    }
+}
+
+#[repr(u8)]
+#[enum_to_js(filename="../src/ErrorReason.ts")]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorReason {
+    InvalidOpcode = 1,
+    TruncatedOperand,
+    OutOfRange
+}
+
+// An instruction that could not be decoded or executed, reported to the frontend with a
+// DecodeError instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ProgramError {
+    pub offset: usize,
+    pub opcode: u8,
+    pub reason: ErrorReason
+}
+
+impl ProgramError {
+    // The offset goes in the lower two bytes, followed by the opcode and the reason
+    pub fn encode(&self) -> u32 {
+        self.offset.min(0xffff) as u32 | (self.opcode as u32) << 16 | (self.reason as u32) << 24
+    }
+
+    pub fn decode(value: u32) -> Option<Self> {
+        let reason = ErrorReason::try_from((value >> 24) as u8).ok()?;
+
+        Some(ProgramError {
+            offset: (value & 0xffff) as usize,
+            opcode: (value >> 16) as u8,
+            reason
+        })
     }
 }
 
 pub struct Instruction<'a> {
     pub opcode: Opcode,
-    operands: &'a [u8]
+    pub offset: usize, // in bytes from the start of the program
+    operands: &'a [u8] // without the length prefix for blobs
 }
 
 impl<'a> Instruction<'a> {
-    pub fn decode<T>(&self, slot: usize) -> T where T: Copy {
-        let ptr = self.operands.as_ptr() as *const T;
-        unsafe { ptr.add(slot).read_unaligned() }
+    // Laid out as in the schema, to be read with the function for the opcode in decode
+    pub fn operands(&self) -> &'a [u8] {
+        self.operands
+    }
+
+    pub fn reader(&self) -> OperandReader<'a> {
+        OperandReader::new(self.operands)
+    }
+}
+
+// Reads consecutive operands of different types, mostly useful for blobs
+pub struct OperandReader<'a> {
+    operands: &'a [u8],
+    position: usize
+}
+
+impl<'a> OperandReader<'a> {
+    pub fn new(operands: &'a [u8]) -> Self {
+        OperandReader {
+            operands,
+            position: 0
+        }
     }
 
-    pub fn decode_u32(&self, slot: usize) -> u32 {
-        self.decode(slot)
+    pub fn read_bytes(&mut self, size: usize) -> Result<&'a [u8], ErrorReason> {
+        if size > self.remaining() {
+            return Err(ErrorReason::TruncatedOperand);
+        }
+
+        let bytes = &self.operands[self.position..self.position + size];
+        self.position += size;
+
+        Ok(bytes)
     }
 
-    pub fn decode_f32(&self, slot: usize) -> f32 {
-        self.decode(slot)
+    pub fn read_u8(&mut self) -> Result<u8, ErrorReason> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, ErrorReason> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_f32(&mut self) -> Result<f32, ErrorReason> {
+        let value = f32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap());
+
+        if value.is_finite() {
+            Ok(value)
+        } else {
+            Err(ErrorReason::OutOfRange)
+        }
+    }
+
+    // in bytes
+    pub fn remaining(&self) -> usize {
+        self.operands.len() - self.position
     }
 }
 
+// The inbound buffer holds the program sent by the frontend, the outbound buffer collects the
+// messages for the frontend while a block is rendered. Messages that do not fit in the outbound
+// buffer are dropped and counted, the count is reported at the start of the next block. Because
+// the newest messages are dropped, a message is never delivered without the ones before it.
 pub struct VM {
-    program: Vec<u8>,
-    position: usize
+    inbound: Vec<u8>,
+    inbound_size: usize,
+
+    outbound: Vec<u8>,
+    outbound_size: usize,
+
+    dropped_message_count: u32,
+    has_dropped_messages: bool
 }
 
 impl VM {
     pub fn new() -> Self {
         Self {
-            program: vec![0; MAX_PROGRAM_SIZE],
-            position: 0
+            inbound: vec![0; MAX_PROGRAM_SIZE],
+            inbound_size: 0,
+
+            outbound: vec![0; MAX_OUTBOUND_SIZE],
+            outbound_size: 0,
+
+            dropped_message_count: 0,
+            has_dropped_messages: false
         }
     }
 
     pub fn get_program_ptr(&mut self) -> *mut u8 {
-        self.program.as_mut_ptr()
+        self.inbound.as_mut_ptr()
     }
 
-    pub fn set_position(&mut self, position: usize) {
-        self.position = position;
+    pub fn get_outbound_ptr(&mut self) -> *mut u8 {
+        self.outbound.as_mut_ptr()
     }
 
-    pub fn get_position(&self) -> usize {
-        self.position
+    // Copies a program into the inbound buffer, returns the number of bytes copied
+    pub fn load(&mut self, program: &[u8]) -> usize {
+        let size = program.len().min(MAX_PROGRAM_SIZE);
+        self.inbound[..size].copy_from_slice(&program[..size]);
+
+        size
     }
 
+    pub fn set_inbound_size(&mut self, size: usize) {
+        self.inbound_size = size.min(MAX_PROGRAM_SIZE);
+    }
+
+    pub fn inbound(&self) -> &[u8] {
+        &self.inbound[..self.inbound_size]
+    }
+
+    pub fn outbound(&self) -> &[u8] {
+        &self.outbound[..self.outbound_size]
+    }
+
+    pub fn outbound_instructions(&self) -> ProgramIterator<'_> {
+        ProgramIterator::new(self.outbound())
+    }
+
+    // Number of bytes that can still be pushed
+    pub fn remaining(&self) -> usize {
+        MAX_OUTBOUND_SIZE - RESERVED_OUTBOUND_SIZE - self.outbound_size
+    }
+
+    // Total number of messages dropped since the VM was created
+    pub fn dropped_message_count(&self) -> u32 {
+        self.dropped_message_count
+    }
+
+    // Starts a new block, the previous outbound messages are expected to have been sent
     pub fn drain(&mut self) {
-        self.position = 0;
+        self.outbound_size = 0;
+
+        if self.has_dropped_messages {
+            self.has_dropped_messages = false;
+            self.write(Opcode::SetDroppedMessageCount, &self.dropped_message_count.to_le_bytes());
+        }
     }
 
-    pub fn push_opcode(&mut self, opcode: Opcode) {
-        self.program[self.position] = opcode as u8;
-        self.position += 1;
+    fn write(&mut self, opcode: Opcode, operand: &[u8]) {
+        self.outbound[self.outbound_size] = opcode as u8;
+        self.outbound[self.outbound_size + 1..self.outbound_size + 1 + operand.len()].copy_from_slice(operand);
+        self.outbound_size += 1 + operand.len();
     }
 
-    pub fn push_u8(&mut self, value: u8) {
-        self.program[self.position] = value;
-        self.position += 1;
+    fn push_message(&mut self, opcode: Opcode, operand: &[u8]) {
+        if 1 + operand.len() > self.remaining() {
+            self.drop_message();
+            return;
+        }
+
+        self.write(opcode, operand);
     }
-}
 
-impl Default for VM {
-    fn default() -> Self {
-        Self::new()
+    fn drop_message(&mut self) {
+        self.dropped_message_count = self.dropped_message_count.wrapping_add(1);
+        self.has_dropped_messages = true;
     }
-}
 
-impl<'a> IntoIterator for &'a VM {
-    type Item = <Self::IntoIter as Iterator>::Item;
-    type IntoIter = ProgramIterator<'a>;
+    // For instructions with several operands, which are passed already encoded
+    pub fn push_operands(&mut self, opcode: Opcode, operands: &[u8]) {
+        self.push_message(opcode, operands);
+    }
 
-    fn into_iter(self) -> Self::IntoIter {
-        ProgramIterator {
-            program: &self.program,
-            position: 0,
-            end: self.position
+    pub fn push_blob(&mut self, opcode: Opcode, blob: &[u8]) {
+        if 5 + blob.len() > self.remaining() {
+            self.drop_message();
+            return;
         }
+
+        self.write(opcode, &(blob.len() as u32).to_le_bytes());
+
+        self.outbound[self.outbound_size..self.outbound_size + blob.len()].copy_from_slice(blob);
+        self.outbound_size += blob.len();
+    }
+
+    pub fn push(&mut self, opcode: Opcode) {
+        self.push_message(opcode, &[]);
+    }
+
+    pub fn push_u32(&mut self, opcode: Opcode, value: u32) {
+        self.push_message(opcode, &value.to_le_bytes());
+    }
+
+    pub fn push_f32(&mut self, opcode: Opcode, value: f32) {
+        self.push_message(opcode, &value.to_le_bytes());
+    }
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 pub struct ProgramIterator<'a> {
-    program: &'a Vec<u8>,
+    program: &'a [u8],
     position: usize,
     end: usize
 }
 
 impl<'a> ProgramIterator<'a> {
+    pub fn new(program: &'a [u8]) -> Self {
+        ProgramIterator {
+            program,
+            position: 0,
+            end: program.len()
+        }
+    }
+
     fn eat_u8(&mut self) -> Option<u8> {
         if self.position >= self.end {
             return None;
@@ -172,27 +500,160 @@ impl<'a> ProgramIterator<'a> {
 }
 
 impl<'a> Iterator for ProgramIterator<'a> {
-    type Item = Instruction<'a>;
+    type Item = Result<Instruction<'a>, ProgramError>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let opcode = match self.eat_u8() {
-            Some(byte) => Opcode::from_u8(byte),
-            None => return None
+        let offset = self.position;
+        let value = self.eat_u8()?;
+
+        let error = |reason| Some(Err(ProgramError { offset, opcode: value, reason }));
+
+        // Without the schema of the opcode there is no way to find the next instruction
+        let opcode = match Opcode::from_u8(value) {
+            Some(opcode) => opcode,
+
+            None => {
+                self.position = self.end;
+                return error(ErrorReason::InvalidOpcode);
+            }
         };
 
-        // Check if we will not exhaust the buffer
-        let operand_size = opcode.operand_size();
-        if self.position + operand_size > self.end {
-            return None;
-        }
+        let operand_size = match decode::operand_size(opcode) {
+            Some(operand_size) => operand_size,
+
+            // Blobs are preceded by their size
+            None => {
+                if 4 > self.end - self.position {
+                    self.position = self.end;
+                    return error(ErrorReason::TruncatedOperand);
+                }
 
-        let instruction = Instruction {
-            operands: &self.program[self.position..self.position + operand_size],
-            opcode
+                let length = u32::from_le_bytes(self.program[self.position..self.position + 4].try_into().unwrap());
+                self.position += 4;
+
+                length as usize
+            }
         };
 
+        // Check if we will not exhaust the buffer
+        if operand_size > self.end - self.position {
+            self.position = self.end;
+            return error(ErrorReason::TruncatedOperand);
+        }
+
+        let operands = &self.program[self.position..self.position + operand_size];
         self.position += operand_size;
 
-        Some(instruction)
+        if !decode::is_finite(opcode, operands) {
+            return error(ErrorReason::OutOfRange);
+        }
+
+        Some(Ok(Instruction {
+            opcode,
+            offset,
+            operands
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_errors() {
+        let mut vm = VM::new();
+
+        let mut program = Vec::new();
+        encode::set_cutoff(&mut program, 1000.0);
+        encode::set_resonance(&mut program, f32::NAN);
+        encode::set_host_transport(&mut program, 120.0, f32::INFINITY);
+        program.push(255); // Unknown opcode
+        encode::nop(&mut program);
+
+        let size = vm.load(&program);
+        vm.set_inbound_size(size);
+
+        let results: Vec<Result<u8, ProgramError>> = ProgramIterator::new(vm.inbound()).map(|result| result.map(|instruction| instruction.opcode as u8)).collect();
+
+        // Decoding stops at an unknown opcode, since the size of its operands is unknown
+        assert_eq!(results, vec![
+            Ok(Opcode::SetCutoff as u8),
+            Err(ProgramError { offset: 5, opcode: Opcode::SetResonance as u8, reason: ErrorReason::OutOfRange }),
+            Err(ProgramError { offset: 10, opcode: Opcode::SetHostTransport as u8, reason: ErrorReason::OutOfRange }),
+            Err(ProgramError { offset: 19, opcode: 255, reason: ErrorReason::InvalidOpcode })
+        ]);
+
+        let size = vm.load(&[Opcode::SetDecay as u8, 0, 0]);
+        vm.set_inbound_size(size);
+
+        assert_eq!(ProgramIterator::new(vm.inbound()).next().unwrap().err().unwrap().reason, ErrorReason::TruncatedOperand);
+    }
+
+    #[test]
+    fn test_schema() {
+        let mut program = Vec::new();
+        encode::set_cutoff(&mut program, 1000.0);
+        encode::set_pattern_data(&mut program, 1, 2, 3, 4);
+        encode::ramp_parameter(&mut program, 5, 1, 0.5, 100.0);
+        encode::set_pattern(&mut program, &[6, 7]);
+
+        let instructions: Vec<Instruction> = ProgramIterator::new(&program).map(|result| result.unwrap()).collect();
+        assert_eq!(instructions.len(), 4);
+
+        // The operands are decoded the way they were encoded
+        assert_eq!(decode::set_cutoff(instructions[0].operands()), 1000.0);
+        assert_eq!(decode::set_pattern_data(instructions[1].operands()), (1, 2, 3, 4));
+        assert_eq!(decode::ramp_parameter(instructions[2].operands()), (5, 1, 0.5, 100.0));
+        assert_eq!(decode::set_pattern(instructions[3].operands()), &[6, 7]);
+    }
+
+    #[test]
+    fn test_blob() {
+        let mut vm = VM::new();
+
+        let mut program = vec![Opcode::SetPattern as u8];
+        program.extend_from_slice(&9_u32.to_le_bytes());
+        program.push(3);
+        program.extend_from_slice(&1000_u32.to_le_bytes());
+        program.extend_from_slice(&0.5_f32.to_le_bytes());
+        program.push(Opcode::SetDrumPattern as u8);
+        program.extend_from_slice(&100_u32.to_le_bytes());
+
+        let size = vm.load(&program);
+        vm.set_inbound_size(size);
+
+        let mut instructions = ProgramIterator::new(vm.inbound());
+
+        let instruction = instructions.next().unwrap().unwrap();
+        let mut reader = instruction.reader();
+        assert_eq!(reader.read_u8(), Ok(3));
+        assert_eq!(reader.read_u32(), Ok(1000));
+        assert_eq!(reader.read_f32(), Ok(0.5));
+        assert_eq!(reader.read_u8(), Err(ErrorReason::TruncatedOperand));
+
+        // The length prefix of the second blob points past the end of the program
+        assert_eq!(instructions.next().unwrap().err().unwrap().reason, ErrorReason::TruncatedOperand);
+        assert!(instructions.next().is_none());
+    }
+
+    #[test]
+    fn test_outbound_overflow() {
+        let mut vm = VM::new();
+
+        let capacity = vm.remaining() / 5;
+        for i in 0..capacity + 3 {
+            vm.push_u32(Opcode::SetSequencerStep, i as u32);
+        }
+
+        assert_eq!(vm.outbound_instructions().count(), capacity);
+        assert_eq!(vm.dropped_message_count(), 3);
+
+        // The next block starts with the number of dropped messages
+        vm.drain();
+        vm.push_u32(Opcode::SetSequencerStep, 0);
+
+        let opcodes: Vec<u8> = vm.outbound_instructions().flatten().map(|instruction| instruction.opcode as u8).collect();
+        assert_eq!(opcodes, vec![Opcode::SetDroppedMessageCount as u8, Opcode::SetSequencerStep as u8]);
     }
 }
@@ -0,0 +1,257 @@
+// Golden audio regression tests
+//
+// Every test renders a deterministic program through the kernel and compares the output against a
+// reference render in tests/golden. The references are raw little endian f32 files with
+// interleaved stereo frames. After an intentional change to the sound, regenerate them with:
+//
+//     UPDATE_GOLDEN=1 cargo test --test golden
+
+use kernel::kernel::Kernel;
+use kernel::vm::{encode, Opcode};
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+
+const SAMPLE_RATE: f32 = 44100.0;
+const FRAMES: usize = 16384;
+const BLOCK_SIZE: usize = 128;
+const TOLERANCE: f32 = 1.0e-4;
+
+// Step flags for SetPatternData
+const NOTE: u8 = 1 << 0;
+const ACCENT: u8 = 1 << 1;
+const SLIDE: u8 = 1 << 2;
+const UP: u8 = 1 << 3;
+const DOWN: u8 = 1 << 4;
+const TIE: u8 = 1 << 5;
+const QUARTER_GATE: u8 = 1 << 6;
+const FULL_GATE: u8 = 3 << 6;
+
+// Instrument bits for SetDrumPatternData
+const KICK: u8 = 1 << 0;
+const SNARE: u8 = 1 << 1;
+const CLOSED_HAT: u8 = 1 << 2;
+const CLAP: u8 = 1 << 4;
+
+struct Program {
+    bytes: Vec<u8>
+}
+
+impl Program {
+    fn new() -> Self {
+        Program { bytes: Vec::new() }
+    }
+
+    fn f32(mut self, opcode: Opcode, value: f32) -> Self {
+        self.bytes.push(opcode as u8);
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn u32(mut self, opcode: Opcode, value: u32) -> Self {
+        self.bytes.push(opcode as u8);
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn step(mut self, step: u8, pitch: u8, flags: u8) -> Self {
+        encode::set_pattern_data(&mut self.bytes, 0, step, pitch, flags);
+        self
+    }
+
+    fn drum_step(mut self, step: u8, instruments: u8, has_accent: bool) -> Self {
+        encode::set_drum_pattern_data(&mut self.bytes, 0, step, instruments, has_accent as u8);
+        self
+    }
+
+    // A short acid line that exercises accents, slides and octave switches in the first steps
+    fn acid_line(self) -> Self {
+        self.f32(Opcode::SetTempo, 180.0)
+            .step(0, 36, NOTE | ACCENT)
+            .step(1, 39, NOTE | SLIDE)
+            .step(2, 43, NOTE)
+            .step(3, 36, NOTE | UP | ACCENT)
+            .step(4, 41, NOTE | DOWN | SLIDE)
+            .step(5, 36, 0)
+    }
+}
+
+fn render(voice_count: usize, program: Program) -> Vec<f32> {
+    let mut kernel = Kernel::new();
+    kernel.initialize(SAMPLE_RATE);
+    kernel.set_voice_count(voice_count);
+    kernel.set_max_block_size(BLOCK_SIZE);
+
+    let program_size = kernel.vm.borrow_mut().load(&program.bytes);
+    kernel.process(program_size as u32, 0);
+
+    let mut output = Vec::with_capacity(2 * FRAMES);
+
+    for _ in 0..FRAMES / BLOCK_SIZE {
+        kernel.process(0, BLOCK_SIZE);
+
+        for i in 0..BLOCK_SIZE {
+            output.push(kernel.left_buffer[i]);
+            output.push(kernel.right_buffer[i]);
+        }
+    }
+
+    output
+}
+
+fn describe(index: usize) -> String {
+    let frame = index / 2;
+    let channel = if index.is_multiple_of(2) { "left" } else { "right" };
+
+    format!("frame {} ({} channel, {:.4} s)", frame, channel, frame as f32 / SAMPLE_RATE)
+}
+
+fn compare(name: &str, output: &[f32]) {
+    let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "tests", "golden", &format!("{}.f32", name)].iter().collect();
+
+    if env::var_os("UPDATE_GOLDEN").is_some() {
+        let bytes: Vec<u8> = output.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+        fs::write(&path, bytes).expect("error writing reference render");
+        return;
+    }
+
+    let bytes = fs::read(&path).unwrap_or_else(|err| {
+        panic!("error reading reference render {}: {} (run with UPDATE_GOLDEN=1 to create it)", path.display(), err)
+    });
+
+    let reference: Vec<f32> = bytes.chunks_exact(4).map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]])).collect();
+
+    assert_eq!(reference.len(), output.len(), "render '{}' has {} samples, the reference has {}", name, output.len(), reference.len());
+
+    let mut first: Option<usize> = None;
+    let mut largest = 0;
+    let mut largest_difference = 0.0;
+    let mut count = 0;
+
+    for (index, (expected, actual)) in reference.iter().zip(output).enumerate() {
+        // NaN is treated as an infinitely large difference
+        let difference = (expected - actual).abs();
+        let difference = if difference.is_nan() { f32::INFINITY } else { difference };
+
+        if difference <= TOLERANCE {
+            continue;
+        }
+
+        count += 1;
+        first.get_or_insert(index);
+
+        if difference > largest_difference {
+            largest = index;
+            largest_difference = difference;
+        }
+    }
+
+    if let Some(first) = first {
+        panic!(
+            "render '{}' diverges from the reference:\n  \
+             {} of {} samples differ by more than {}\n  \
+             first divergence at {}: expected {}, got {}\n  \
+             largest divergence at {}: expected {}, got {} (difference {})",
+            name,
+            count, output.len(), TOLERANCE,
+            describe(first), reference[first], output[first],
+            describe(largest), reference[largest], output[largest], largest_difference
+        );
+    }
+}
+
+#[test]
+fn test_default_pattern() {
+    compare("default_pattern", &render(1, Program::new()));
+}
+
+#[test]
+fn test_acid_line() {
+    let program = Program::new()
+        .acid_line()
+        .f32(Opcode::SetCutoff, 600.0)
+        .f32(Opcode::SetResonance, 0.85)
+        .f32(Opcode::SetEnvMod, 0.8)
+        .f32(Opcode::SetDecay, 300.0)
+        .f32(Opcode::SetAccent, 0.7)
+        .f32(Opcode::SetDelaySend, 0.0);
+
+    compare("acid_line", &render(1, program));
+}
+
+#[test]
+fn test_distortion() {
+    let program = Program::new()
+        .acid_line()
+        .u32(Opcode::SetWaveformIndex, 1)
+        .f32(Opcode::SetCutoff, 1200.0)
+        .f32(Opcode::SetResonance, 0.6)
+        .f32(Opcode::SetDistortionThreshold, 0.8)
+        .f32(Opcode::SetDistortionShape, 0.3)
+        .f32(Opcode::SetDelaySend, 0.0);
+
+    compare("distortion", &render(1, program));
+}
+
+#[test]
+fn test_stereo_delay() {
+    let program = Program::new()
+        .acid_line()
+        .f32(Opcode::SetPan, 0.4)
+        .f32(Opcode::SetDelaySend, 0.5)
+        .f32(Opcode::SetDelayFeedback, 0.6)
+        .f32(Opcode::SetDelayPingPong, 0.7)
+        .u32(Opcode::SetDelayLength, 68)
+        .f32(Opcode::SetStereoWidth, 1.5);
+
+    compare("stereo_delay", &render(1, program));
+}
+
+#[test]
+fn test_gate() {
+    // Fast enough that the tie after the rest is still in the render
+    let program = Program::new()
+        .f32(Opcode::SetTempo, 300.0)
+        .step(0, 36, NOTE | QUARTER_GATE)
+        .step(1, 43, NOTE | FULL_GATE)
+        .step(2, 48, NOTE | SLIDE)
+        .step(3, 36, NOTE | ACCENT)
+        .step(4, 36, TIE)
+        .step(5, 36, 0)
+        .step(6, 41, TIE)
+        .f32(Opcode::SetCutoff, 800.0)
+        .f32(Opcode::SetResonance, 0.7)
+        .f32(Opcode::SetDelaySend, 0.0);
+
+    compare("gate", &render(1, program));
+}
+
+#[test]
+fn test_drums() {
+    let program = Program::new()
+        .f32(Opcode::SetTempo, 180.0)
+        .u32(Opcode::SetChannelMute, 1)
+        .drum_step(0, KICK | CLOSED_HAT, true)
+        .drum_step(1, CLOSED_HAT, false)
+        .drum_step(2, SNARE | CLOSED_HAT, false)
+        .drum_step(3, CLAP, true);
+
+    compare("drums", &render(1, program));
+}
+
+#[test]
+fn test_dual_voice() {
+    let program = Program::new()
+        .acid_line()
+        .f32(Opcode::SetPan, -0.5)
+        .u32(Opcode::SetChannel, 1)
+        .step(0, 48, NOTE)
+        .step(1, 51, NOTE | ACCENT)
+        .step(2, 55, NOTE | SLIDE)
+        .step(3, 48, NOTE)
+        .f32(Opcode::SetPan, 0.5)
+        .f32(Opcode::SetCutoff, 900.0);
+
+    compare("dual_voice", &render(2, program));
+}
@@ -0,0 +1,7 @@
+const ClockSource = {
+    Internal: 0,
+    Midi: 1,
+    Host: 2
+} as const;
+
+export default ClockSource;
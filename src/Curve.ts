@@ -0,0 +1,6 @@
+const Curve = {
+    Linear: 0,
+    Exponential: 1
+} as const;
+
+export default Curve;
@@ -1,4 +1,8 @@
-import Opcode from "./Opcode";
+import ClockSource from "./ClockSource";
+import ErrorReason from "./ErrorReason";
+import Opcode, { Instruction, decodeInstruction, encodeInstruction } from "./Opcode";
+import ParameterTaper from "./ParameterTaper";
+import ParameterUnit from "./ParameterUnit";
 import RingBuffer from "./RingBuffer";
 
 // TypeScript doesn't understand Atomics.waitAsync, so we'll have to provide
@@ -13,66 +17,61 @@ declare namespace Atomics {
     };
 }
 
-export type OpcodeWithoutOperand =
-    typeof Opcode.Nop |
-    typeof Opcode.BootstrapFinished;
-
-export type OpcodeWithU32 =
-    typeof Opcode.SetWaveformIndex |
-    typeof Opcode.SetDelayLength |
-    typeof Opcode.SetSequencerStep |
-    typeof Opcode.SetPatternData;
-
-export type OpcodeWithF32 =
-    typeof Opcode.SetTuning |
-    typeof Opcode.SetCutoff |
-    typeof Opcode.SetResonance |
-    typeof Opcode.SetEnvMod |
-    typeof Opcode.SetDecay |
-    typeof Opcode.SetAccent |
-    typeof Opcode.SetDistortionThreshold |
-    typeof Opcode.SetDistortionShape |
-    typeof Opcode.SetDelaySend |
-    typeof Opcode.SetDelayFeedback;
-
-export type Opcode =
-    OpcodeWithoutOperand |
-    OpcodeWithU32 |
-    OpcodeWithF32;
-
-export const isOpcodeWithU32 = (x: Opcode): x is OpcodeWithU32 => (x >= 20 && x < 40) || (x >= 80 && x < 100);
-export const isOpcodeWithF32 = (x: Opcode): x is OpcodeWithF32 => (x >= 40 && x < 60) || (x >= 100 && x < 120);
-
-export interface InstructionWithoutOperand {
-    opcode: OpcodeWithoutOperand
+// Only the parts of Web MIDI that are used here, it is not in every DOM library yet
+interface MIDIInput {
+    onmidimessage: ((event: { data: Uint8Array }) => void) | null;
 }
 
-export interface InstructionWithU32 {
-    opcode: OpcodeWithU32,
-    operand: number
+interface MIDIOutput {
+    send(data: number[], timestamp?: number): void;
 }
 
-export interface InstructionWithF32 {
-    opcode: OpcodeWithF32,
-    operand: number
+interface MIDIAccess {
+    inputs: Map<string, MIDIInput>;
+    outputs: Map<string, MIDIOutput>;
 }
 
-export type Instruction =
-    InstructionWithoutOperand |
-    InstructionWithU32 |
-    InstructionWithF32;
+declare global {
+    interface Navigator {
+        requestMIDIAccess?: () => Promise<MIDIAccess>;
+    }
+}
 
-const isInstructionWithU32 = (x: Instruction): x is InstructionWithU32 => isOpcodeWithU32(x.opcode);
-const isInstructionWithF32 = (x: Instruction): x is InstructionWithF32 => isOpcodeWithF32(x.opcode);
+export type { Instruction };
 
 export type InstructionCallback = (instruction: Instruction) => void;
 
+// An entry of the parameter table of the kernel, the ID is used with SetParameter and GetParameter
+export interface ParameterInfo {
+    id: number,
+    name: string,
+    unit: typeof ParameterUnit[keyof typeof ParameterUnit],
+    taper: typeof ParameterTaper[keyof typeof ParameterTaper],
+    min: number,
+    max: number,
+    default: number
+}
+
+// ID, unit, taper, minimum, maximum and default, followed by the name
+function decodeParameterInfo(blob: Uint8Array): ParameterInfo {
+    const view = new DataView(blob.buffer, blob.byteOffset, blob.byteLength);
+
+    return {
+        id: view.getUint8(0),
+        unit: view.getUint8(1) as ParameterInfo["unit"],
+        taper: view.getUint8(2) as ParameterInfo["taper"],
+        min: view.getFloat32(3, true),
+        max: view.getFloat32(7, true),
+        default: view.getFloat32(11, true),
+        name: new TextDecoder().decode(blob.subarray(15))
+    };
+}
+
 export default class Engine {
     private sendBuffer: RingBuffer;
     private receiveBuffer: RingBuffer;
 
     private programBuffer: Uint8Array;
-    private programDataView: DataView;
 
     private encodeBuffer: Uint8Array;
     private encodeDataView: DataView;
@@ -83,6 +82,12 @@ export default class Engine {
 
     private instructionCallback: InstructionCallback;
 
+    private parameterInfo: ParameterInfo[] = [];
+
+    private resolveSnapshotPromises: ((snapshot: Uint8Array) => void)[] = [];
+
+    private midiOutputs: MIDIOutput[] = [];
+
     async initialize(instructionCallback: InstructionCallback): Promise<void> {
         this.instructionCallback = instructionCallback;
 
@@ -96,7 +101,6 @@ export default class Engine {
 
         // Initialize audio context
         this.context = new AudioContext({
-            sampleRate: 44100,
             latencyHint: "interactive"
         });
 
@@ -119,10 +123,11 @@ export default class Engine {
         this.sendBuffer = new RingBuffer(sendStorage);
         this.receiveBuffer = new RingBuffer(receiveStorage);
 
-        this.programBuffer = new Uint8Array(1024);
-        this.programDataView = new DataView(this.programBuffer.buffer);
+        // Large enough for everything in the receive buffer, so that reads never end halfway
+        // through an instruction
+        this.programBuffer = new Uint8Array(32768);
 
-        this.encodeBuffer = new Uint8Array(1024);
+        this.encodeBuffer = new Uint8Array(32768);
         this.encodeDataView = new DataView(this.encodeBuffer.buffer);
 
         const bootstrapPromise = new Promise<void>((resolve, reject) => {
@@ -140,45 +145,125 @@ export default class Engine {
         this.waitCallback("initialize");
 
         await bootstrapPromise;
+
+        this.sendInstruction({ opcode: Opcode.DescribeParameters });
     }
 
-    toggleStart(): void {
-        if (this.context.state === "running") {
-            this.context.suspend();
-        } else {
-            this.context.resume();
+    // Filled in shortly after initialization, when the kernel has described its parameters
+    get parameters(): ParameterInfo[] {
+        return this.parameterInfo;
+    }
+
+    setParameter(id: number, value: number): void {
+        this.sendInstruction({ opcode: Opcode.SetParameter, operands: [id, value] });
+    }
+
+    // The complete state of the kernel as an opaque, versioned blob
+    requestSnapshot(): Promise<Uint8Array> {
+        const snapshotPromise = new Promise<Uint8Array>((resolve) => {
+            this.resolveSnapshotPromises.push(resolve);
+        });
+
+        this.sendInstruction({ opcode: Opcode.RequestSnapshot });
+
+        return snapshotPromise;
+    }
+
+    // Snapshots are restored completely or not at all, failures are reported as decode errors
+    restoreSnapshot(snapshot: Uint8Array): void {
+        this.sendInstruction({ opcode: Opcode.RestoreSnapshot, operand: snapshot });
+    }
+
+    // Raw MIDI bytes, MIDI channels play the voice with the same index
+    sendMidi(data: Uint8Array): void {
+        this.sendInstruction({ opcode: Opcode.MidiInput, operand: data });
+    }
+
+    // Maps a MIDI controller to a parameter ID, or removes the mapping when id is undefined
+    setMidiMapping(controller: number, id?: number): void {
+        this.sendInstruction({ opcode: Opcode.SetMidiMapping, operands: [controller, id ?? 255] });
+    }
+
+    // Forwards every MIDI input to the kernel, returns false when Web MIDI is not available
+    async enableMidiInput(): Promise<boolean> {
+        if (!navigator.requestMIDIAccess) {
+            return false;
+        }
+
+        try {
+            const access = await navigator.requestMIDIAccess();
+
+            access.inputs.forEach((input) => {
+                input.onmidimessage = (event) => this.sendMidi(event.data);
+            });
+
+            return true;
+        } catch {
+            return false;
         }
     }
 
-    encodeInstruction(instruction: Instruction, ptr: number): number {
-        this.encodeBuffer[ptr++] = instruction.opcode;
+    // Sends MIDI clock and transport messages from the kernel to every output, returns false when
+    // Web MIDI is not available. The kernel only sends them while its clock is internal.
+    async enableMidiClockOutput(): Promise<boolean> {
+        if (!navigator.requestMIDIAccess) {
+            return false;
+        }
+
+        try {
+            const access = await navigator.requestMIDIAccess();
+
+            this.midiOutputs = Array.from(access.outputs.values());
+            this.sendInstruction({ opcode: Opcode.SetMidiClockOutput, operands: [1] });
 
-        if (isInstructionWithU32(instruction)) {
-            this.encodeDataView.setUint32(ptr, instruction.operand, true);
-            ptr += 4;
-        } else if (isInstructionWithF32(instruction)) {
-            this.encodeDataView.setFloat32(ptr, instruction.operand, true);
-            ptr += 4;
+            return true;
+        } catch {
+            return false;
         }
+    }
+
+    setClockSource(source: typeof ClockSource[keyof typeof ClockSource]): void {
+        this.sendInstruction({ opcode: Opcode.SetClockSource, operands: [source] });
+    }
 
-        return ptr;
+    // Sent before every block while the clock follows the host, the position is in quarter notes
+    setHostTransport(tempo: number, position: number): void {
+        this.sendInstruction({ opcode: Opcode.SetHostTransport, operands: [tempo, position] });
+    }
+
+    toggleStart(): void {
+        if (this.context.state === "running") {
+            this.context.suspend();
+        } else {
+            this.context.resume();
+        }
     }
 
     sendInstruction(instruction: Instruction): void {
-        const ptr = this.encodeInstruction(instruction, 0);
-        this.sendBuffer.write(this.encodeBuffer, ptr);
+        this.sendInstructions([instruction]);
     }
 
+    // Instructions are preceded by the frame they were sent at, on the clock of the audio context.
+    // The worklet turns it into an offset in the block that they take effect at.
     sendInstructions(instructions: Instruction[]): void {
-        let ptr = 0;
+        let ptr = encodeInstruction(this.encodeDataView, 0, { opcode: Opcode.SetFrameOffset, operand: this.currentFrame() });
 
         for (const instruction of instructions) {
-            ptr = this.encodeInstruction(instruction, ptr);
+            ptr = encodeInstruction(this.encodeDataView, ptr, instruction);
         }
 
         this.sendBuffer.write(this.encodeBuffer, ptr);
     }
 
+    // The frame that the audio context is rendering right now, extrapolated from its last output
+    // timestamp. Frames wrap around like the u32 they are sent in.
+    private currentFrame(): number {
+        const { contextTime = 0, performanceTime = performance.now() } = this.context.getOutputTimestamp();
+        const time = contextTime + (performance.now() - performanceTime) / 1000 + (this.context.outputLatency ?? 0);
+
+        return Math.round(time * this.context.sampleRate) >>> 0;
+    }
+
     private waitCallback: (result: string) => void = (result: string) => {
         while (true) {
             const bytesRead = this.receiveBuffer.read(this.programBuffer, this.programBuffer.length);
@@ -199,29 +284,20 @@ export default class Engine {
     }
 
     private *decodeInstructions(bytesToProcess: number): Generator<Instruction> {
+        const view = new DataView(this.programBuffer.buffer, 0, bytesToProcess);
         let ptr = 0;
 
         while (ptr < bytesToProcess) {
-            const opcode = this.programBuffer[ptr++] as Opcode;
-
-            // Extract operands
-            if (isOpcodeWithU32(opcode)) {
-                yield {
-                    opcode,
-                    operand: this.programDataView.getInt32(ptr, true)
-                };
-
-                ptr += 4;
-            } else if (isOpcodeWithF32(opcode)) {
-                yield {
-                    opcode,
-                    operand: this.programDataView.getFloat32(ptr, true)
-                };
-
-                ptr += 4;
+            const [instruction, nextPtr] = decodeInstruction(view, ptr);
+
+            // The kernel only sends opcodes we know, so this means the schemas are out of sync
+            if (instruction === undefined) {
+                console.error(`unknown opcode ${this.programBuffer[ptr]} from kernel at offset ${ptr}`);
             } else {
-                yield { opcode };
+                yield instruction;
             }
+
+            ptr = nextPtr;
         }
     }
 
@@ -229,12 +305,48 @@ export default class Engine {
         for (const instruction of this.decodeInstructions(bytesToProcess)) {
             if (instruction.opcode === Opcode.BootstrapFinished) {
                 this.resolveBootstrapPromise();
+            } else if (instruction.opcode === Opcode.DecodeError) {
+                this.reportDecodeError(instruction.operand);
+            } else if (instruction.opcode === Opcode.Snapshot) {
+                this.resolveSnapshotPromises.shift()?.(instruction.operand);
+            } else if (instruction.opcode === Opcode.ParameterInfo) {
+                this.parameterInfo[instruction.operand[0]] = decodeParameterInfo(instruction.operand);
+            } else if (instruction.opcode === Opcode.MidiOutput) {
+                this.sendMidiOutput(instruction.operand);
+            } else if (instruction.opcode === Opcode.SetDroppedMessageCount) {
+                console.warn(`kernel dropped messages, ${instruction.operand} in total`);
             } else {
                 this.instructionCallback(instruction);
             }
         }
     }
 
+    // Each byte is preceded by its frame in the block, which becomes its timestamp
+    private sendMidiOutput(blob: Uint8Array): void {
+        const view = new DataView(blob.buffer, blob.byteOffset, blob.byteLength);
+        const now = performance.now();
+
+        for (let ptr = 0; ptr + 3 <= blob.length; ptr += 3) {
+            const timestamp = now + view.getUint16(ptr, true) / this.context.sampleRate * 1000;
+
+            for (const output of this.midiOutputs) {
+                output.send([blob[ptr + 2]], timestamp);
+            }
+        }
+    }
+
+    // The kernel skipped an instruction we sent, which means there is a bug in the encoder
+    private reportDecodeError(operand: number): void {
+        const offset = operand & 0xffff;
+        const opcode = (operand >> 16) & 0xff;
+        const reason = (operand >> 24) & 0xff;
+
+        const opcodeName = Object.keys(Opcode).find(key => Opcode[key as keyof typeof Opcode] === opcode) ?? opcode;
+        const reasonName = Object.keys(ErrorReason).find(key => ErrorReason[key as keyof typeof ErrorReason] === reason) ?? reason;
+
+        console.error(`kernel skipped instruction ${opcodeName} at offset ${offset}: ${reasonName}`);
+    }
+
     private handleMessage: (event: Event) => void = (event: Event) => {
         console.log("message from worklet:", event);
     }
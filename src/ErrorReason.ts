@@ -0,0 +1,7 @@
+const ErrorReason = {
+    InvalidOpcode: 1,
+    TruncatedOperand: 2,
+    OutOfRange: 3
+} as const;
+
+export default ErrorReason;
@@ -0,0 +1,8 @@
+const GateLength = {
+    Half: 0,
+    Quarter: 1,
+    ThreeQuarters: 2,
+    Full: 3
+} as const;
+
+export default GateLength;
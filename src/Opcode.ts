@@ -1,8 +1,25 @@
 const Opcode = {
     Nop: 0,
+    DescribeParameters: 1,
+    RequestSnapshot: 2,
+    StartTransport: 3,
+    StopTransport: 4,
+    ContinueTransport: 5,
     SetWaveformIndex: 20,
     SetDelayLength: 21,
     SetPatternData: 22,
+    SetChannel: 23,
+    SetDrumPatternData: 24,
+    SetChannelMute: 25,
+    SetChannelSolo: 26,
+    SetFrameOffset: 27,
+    GetParameter: 28,
+    SetCurrentPattern: 29,
+    SetNextPattern: 30,
+    QueueSongEntry: 31,
+    ResetTransport: 32,
+    SetClockSource: 33,
+    SetMidiClockOutput: 34,
     SetCutoff: 40,
     SetResonance: 41,
     SetEnvMod: 42,
@@ -14,9 +31,727 @@ const Opcode = {
     SetDistortionShape: 48,
     SetDelaySend: 49,
     SetDelayFeedback: 50,
+    SetPan: 51,
+    SetStereoWidth: 52,
+    SetDelayPingPong: 53,
+    SetDrumVolume: 54,
+    SetKickTune: 55,
+    SetKickDecay: 56,
+    SetSnareSnappy: 57,
+    SetHatDecay: 58,
     BootstrapFinished: 60,
     SetSequencerStep: 80,
-    Max: 81
+    DecodeError: 81,
+    SetDroppedMessageCount: 82,
+    SetActivePattern: 83,
+    SetTransportState: 84,
+    SetPeakLeft: 100,
+    SetPeakRight: 101,
+    SetRmsLeft: 102,
+    SetRmsRight: 103,
+    SetEffectiveCutoff: 104,
+    SetAmplitudeEnvelope: 105,
+    SetFilterEnvelope: 106,
+    SetChannelGain: 120,
+    SetMasterGain: 121,
+    SetMasterLowShelf: 122,
+    SetMasterHighShelf: 123,
+    SetTelemetryRate: 124,
+    SetSmoothingTime: 125,
+    SetSwing: 126,
+    SetChannelPan: 127,
+    SetMasterWidth: 128,
+    SetPattern: 140,
+    SetDrumPattern: 141,
+    RestoreSnapshot: 142,
+    MidiInput: 143,
+    SetSong: 144,
+    SetGroove: 145,
+    ParameterInfo: 150,
+    Snapshot: 151,
+    MidiOutput: 152,
+    RampParameter: 160,
+    SetParameter: 180,
+    ParameterValue: 190,
+    SetMidiMapping: 200,
+    SetPatternLength: 201,
+    SetPatternResolution: 202,
+    SetHostTransport: 220,
+    Max: 221
 } as const;
 
 export default Opcode;
+
+export type Instruction =
+    { opcode: typeof Opcode.Nop } |
+    { opcode: typeof Opcode.DescribeParameters } |
+    { opcode: typeof Opcode.RequestSnapshot } |
+    { opcode: typeof Opcode.StartTransport } |
+    { opcode: typeof Opcode.StopTransport } |
+    { opcode: typeof Opcode.ContinueTransport } |
+    { opcode: typeof Opcode.SetWaveformIndex, operand: number } |
+    { opcode: typeof Opcode.SetDelayLength, operand: number } |
+    { opcode: typeof Opcode.SetPatternData, operands: [number, number, number, number] } |
+    { opcode: typeof Opcode.SetChannel, operand: number } |
+    { opcode: typeof Opcode.SetDrumPatternData, operands: [number, number, number, number] } |
+    { opcode: typeof Opcode.SetChannelMute, operand: number } |
+    { opcode: typeof Opcode.SetChannelSolo, operand: number } |
+    { opcode: typeof Opcode.SetFrameOffset, operand: number } |
+    { opcode: typeof Opcode.GetParameter, operand: number } |
+    { opcode: typeof Opcode.SetCurrentPattern, operand: number } |
+    { opcode: typeof Opcode.SetNextPattern, operand: number } |
+    { opcode: typeof Opcode.QueueSongEntry, operand: number } |
+    { opcode: typeof Opcode.ResetTransport, operand: number } |
+    { opcode: typeof Opcode.SetClockSource, operand: number } |
+    { opcode: typeof Opcode.SetMidiClockOutput, operand: number } |
+    { opcode: typeof Opcode.SetCutoff, operand: number } |
+    { opcode: typeof Opcode.SetResonance, operand: number } |
+    { opcode: typeof Opcode.SetEnvMod, operand: number } |
+    { opcode: typeof Opcode.SetDecay, operand: number } |
+    { opcode: typeof Opcode.SetTempo, operand: number } |
+    { opcode: typeof Opcode.SetTuning, operand: number } |
+    { opcode: typeof Opcode.SetAccent, operand: number } |
+    { opcode: typeof Opcode.SetDistortionThreshold, operand: number } |
+    { opcode: typeof Opcode.SetDistortionShape, operand: number } |
+    { opcode: typeof Opcode.SetDelaySend, operand: number } |
+    { opcode: typeof Opcode.SetDelayFeedback, operand: number } |
+    { opcode: typeof Opcode.SetPan, operand: number } |
+    { opcode: typeof Opcode.SetStereoWidth, operand: number } |
+    { opcode: typeof Opcode.SetDelayPingPong, operand: number } |
+    { opcode: typeof Opcode.SetDrumVolume, operand: number } |
+    { opcode: typeof Opcode.SetKickTune, operand: number } |
+    { opcode: typeof Opcode.SetKickDecay, operand: number } |
+    { opcode: typeof Opcode.SetSnareSnappy, operand: number } |
+    { opcode: typeof Opcode.SetHatDecay, operand: number } |
+    { opcode: typeof Opcode.BootstrapFinished } |
+    { opcode: typeof Opcode.SetSequencerStep, operand: number } |
+    { opcode: typeof Opcode.DecodeError, operand: number } |
+    { opcode: typeof Opcode.SetDroppedMessageCount, operand: number } |
+    { opcode: typeof Opcode.SetActivePattern, operand: number } |
+    { opcode: typeof Opcode.SetTransportState, operand: number } |
+    { opcode: typeof Opcode.SetPeakLeft, operand: number } |
+    { opcode: typeof Opcode.SetPeakRight, operand: number } |
+    { opcode: typeof Opcode.SetRmsLeft, operand: number } |
+    { opcode: typeof Opcode.SetRmsRight, operand: number } |
+    { opcode: typeof Opcode.SetEffectiveCutoff, operand: number } |
+    { opcode: typeof Opcode.SetAmplitudeEnvelope, operand: number } |
+    { opcode: typeof Opcode.SetFilterEnvelope, operand: number } |
+    { opcode: typeof Opcode.SetChannelGain, operand: number } |
+    { opcode: typeof Opcode.SetMasterGain, operand: number } |
+    { opcode: typeof Opcode.SetMasterLowShelf, operand: number } |
+    { opcode: typeof Opcode.SetMasterHighShelf, operand: number } |
+    { opcode: typeof Opcode.SetTelemetryRate, operand: number } |
+    { opcode: typeof Opcode.SetSmoothingTime, operand: number } |
+    { opcode: typeof Opcode.SetSwing, operand: number } |
+    { opcode: typeof Opcode.SetChannelPan, operand: number } |
+    { opcode: typeof Opcode.SetMasterWidth, operand: number } |
+    { opcode: typeof Opcode.SetPattern, operand: Uint8Array } |
+    { opcode: typeof Opcode.SetDrumPattern, operand: Uint8Array } |
+    { opcode: typeof Opcode.RestoreSnapshot, operand: Uint8Array } |
+    { opcode: typeof Opcode.MidiInput, operand: Uint8Array } |
+    { opcode: typeof Opcode.SetSong, operand: Uint8Array } |
+    { opcode: typeof Opcode.SetGroove, operand: Uint8Array } |
+    { opcode: typeof Opcode.ParameterInfo, operand: Uint8Array } |
+    { opcode: typeof Opcode.Snapshot, operand: Uint8Array } |
+    { opcode: typeof Opcode.MidiOutput, operand: Uint8Array } |
+    { opcode: typeof Opcode.RampParameter, operands: [number, number, number, number] } |
+    { opcode: typeof Opcode.SetParameter, operands: [number, number] } |
+    { opcode: typeof Opcode.ParameterValue, operands: [number, number] } |
+    { opcode: typeof Opcode.SetMidiMapping, operands: [number, number] } |
+    { opcode: typeof Opcode.SetPatternLength, operands: [number, number] } |
+    { opcode: typeof Opcode.SetPatternResolution, operands: [number, number] } |
+    { opcode: typeof Opcode.SetHostTransport, operands: [number, number] };
+
+// Writes an instruction at ptr, returns the position after it
+export function encodeInstruction(view: DataView, ptr: number, instruction: Instruction): number {
+    view.setUint8(ptr++, instruction.opcode);
+
+    switch (instruction.opcode) {
+        case Opcode.SetWaveformIndex:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetDelayLength:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetPatternData:
+            view.setUint8(ptr, instruction.operands[0]);
+            ptr += 1;
+            view.setUint8(ptr, instruction.operands[1]);
+            ptr += 1;
+            view.setUint8(ptr, instruction.operands[2]);
+            ptr += 1;
+            view.setUint8(ptr, instruction.operands[3]);
+            ptr += 1;
+            break;
+        case Opcode.SetChannel:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetDrumPatternData:
+            view.setUint8(ptr, instruction.operands[0]);
+            ptr += 1;
+            view.setUint8(ptr, instruction.operands[1]);
+            ptr += 1;
+            view.setUint8(ptr, instruction.operands[2]);
+            ptr += 1;
+            view.setUint8(ptr, instruction.operands[3]);
+            ptr += 1;
+            break;
+        case Opcode.SetChannelMute:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetChannelSolo:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetFrameOffset:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.GetParameter:
+            view.setUint8(ptr, instruction.operand);
+            ptr += 1;
+            break;
+        case Opcode.SetCurrentPattern:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetNextPattern:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.QueueSongEntry:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.ResetTransport:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetClockSource:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetMidiClockOutput:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetCutoff:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetResonance:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetEnvMod:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetDecay:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetTempo:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetTuning:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetAccent:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetDistortionThreshold:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetDistortionShape:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetDelaySend:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetDelayFeedback:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetPan:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetStereoWidth:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetDelayPingPong:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetDrumVolume:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetKickTune:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetKickDecay:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetSnareSnappy:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetHatDecay:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetSequencerStep:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.DecodeError:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetDroppedMessageCount:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetActivePattern:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetTransportState:
+            view.setUint32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetPeakLeft:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetPeakRight:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetRmsLeft:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetRmsRight:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetEffectiveCutoff:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetAmplitudeEnvelope:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetFilterEnvelope:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetChannelGain:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetMasterGain:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetMasterLowShelf:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetMasterHighShelf:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetTelemetryRate:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetSmoothingTime:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetSwing:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetChannelPan:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetMasterWidth:
+            view.setFloat32(ptr, instruction.operand, true);
+            ptr += 4;
+            break;
+        case Opcode.SetPattern:
+            view.setUint32(ptr, instruction.operand.length, true);
+            ptr += 4;
+            new Uint8Array(view.buffer, view.byteOffset + ptr, instruction.operand.length).set(instruction.operand);
+            ptr += instruction.operand.length;
+            break;
+        case Opcode.SetDrumPattern:
+            view.setUint32(ptr, instruction.operand.length, true);
+            ptr += 4;
+            new Uint8Array(view.buffer, view.byteOffset + ptr, instruction.operand.length).set(instruction.operand);
+            ptr += instruction.operand.length;
+            break;
+        case Opcode.RestoreSnapshot:
+            view.setUint32(ptr, instruction.operand.length, true);
+            ptr += 4;
+            new Uint8Array(view.buffer, view.byteOffset + ptr, instruction.operand.length).set(instruction.operand);
+            ptr += instruction.operand.length;
+            break;
+        case Opcode.MidiInput:
+            view.setUint32(ptr, instruction.operand.length, true);
+            ptr += 4;
+            new Uint8Array(view.buffer, view.byteOffset + ptr, instruction.operand.length).set(instruction.operand);
+            ptr += instruction.operand.length;
+            break;
+        case Opcode.SetSong:
+            view.setUint32(ptr, instruction.operand.length, true);
+            ptr += 4;
+            new Uint8Array(view.buffer, view.byteOffset + ptr, instruction.operand.length).set(instruction.operand);
+            ptr += instruction.operand.length;
+            break;
+        case Opcode.SetGroove:
+            view.setUint32(ptr, instruction.operand.length, true);
+            ptr += 4;
+            new Uint8Array(view.buffer, view.byteOffset + ptr, instruction.operand.length).set(instruction.operand);
+            ptr += instruction.operand.length;
+            break;
+        case Opcode.ParameterInfo:
+            view.setUint32(ptr, instruction.operand.length, true);
+            ptr += 4;
+            new Uint8Array(view.buffer, view.byteOffset + ptr, instruction.operand.length).set(instruction.operand);
+            ptr += instruction.operand.length;
+            break;
+        case Opcode.Snapshot:
+            view.setUint32(ptr, instruction.operand.length, true);
+            ptr += 4;
+            new Uint8Array(view.buffer, view.byteOffset + ptr, instruction.operand.length).set(instruction.operand);
+            ptr += instruction.operand.length;
+            break;
+        case Opcode.MidiOutput:
+            view.setUint32(ptr, instruction.operand.length, true);
+            ptr += 4;
+            new Uint8Array(view.buffer, view.byteOffset + ptr, instruction.operand.length).set(instruction.operand);
+            ptr += instruction.operand.length;
+            break;
+        case Opcode.RampParameter:
+            view.setUint8(ptr, instruction.operands[0]);
+            ptr += 1;
+            view.setUint8(ptr, instruction.operands[1]);
+            ptr += 1;
+            view.setFloat32(ptr, instruction.operands[2], true);
+            ptr += 4;
+            view.setFloat32(ptr, instruction.operands[3], true);
+            ptr += 4;
+            break;
+        case Opcode.SetParameter:
+            view.setUint8(ptr, instruction.operands[0]);
+            ptr += 1;
+            view.setFloat32(ptr, instruction.operands[1], true);
+            ptr += 4;
+            break;
+        case Opcode.ParameterValue:
+            view.setUint8(ptr, instruction.operands[0]);
+            ptr += 1;
+            view.setFloat32(ptr, instruction.operands[1], true);
+            ptr += 4;
+            break;
+        case Opcode.SetMidiMapping:
+            view.setUint8(ptr, instruction.operands[0]);
+            ptr += 1;
+            view.setUint8(ptr, instruction.operands[1]);
+            ptr += 1;
+            break;
+        case Opcode.SetPatternLength:
+            view.setUint8(ptr, instruction.operands[0]);
+            ptr += 1;
+            view.setUint8(ptr, instruction.operands[1]);
+            ptr += 1;
+            break;
+        case Opcode.SetPatternResolution:
+            view.setUint8(ptr, instruction.operands[0]);
+            ptr += 1;
+            view.setUint8(ptr, instruction.operands[1]);
+            ptr += 1;
+            break;
+        case Opcode.SetHostTransport:
+            view.setFloat32(ptr, instruction.operands[0], true);
+            ptr += 4;
+            view.setFloat32(ptr, instruction.operands[1], true);
+            ptr += 4;
+            break;
+    }
+
+    return ptr;
+}
+
+// Reads the instruction at ptr, returns it with the position after it. Unknown opcodes are
+// returned as undefined with the end of the view as position, since their size is unknown.
+export function decodeInstruction(view: DataView, ptr: number): [Instruction | undefined, number] {
+    switch (view.getUint8(ptr++)) {
+        case Opcode.Nop: {
+            return [{ opcode: Opcode.Nop }, ptr + 0];
+        }
+        case Opcode.DescribeParameters: {
+            return [{ opcode: Opcode.DescribeParameters }, ptr + 0];
+        }
+        case Opcode.RequestSnapshot: {
+            return [{ opcode: Opcode.RequestSnapshot }, ptr + 0];
+        }
+        case Opcode.StartTransport: {
+            return [{ opcode: Opcode.StartTransport }, ptr + 0];
+        }
+        case Opcode.StopTransport: {
+            return [{ opcode: Opcode.StopTransport }, ptr + 0];
+        }
+        case Opcode.ContinueTransport: {
+            return [{ opcode: Opcode.ContinueTransport }, ptr + 0];
+        }
+        case Opcode.SetWaveformIndex: {
+            return [{ opcode: Opcode.SetWaveformIndex, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetDelayLength: {
+            return [{ opcode: Opcode.SetDelayLength, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetPatternData: {
+            return [{ opcode: Opcode.SetPatternData, operands: [view.getUint8(ptr + 0), view.getUint8(ptr + 1), view.getUint8(ptr + 2), view.getUint8(ptr + 3)] }, ptr + 4];
+        }
+        case Opcode.SetChannel: {
+            return [{ opcode: Opcode.SetChannel, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetDrumPatternData: {
+            return [{ opcode: Opcode.SetDrumPatternData, operands: [view.getUint8(ptr + 0), view.getUint8(ptr + 1), view.getUint8(ptr + 2), view.getUint8(ptr + 3)] }, ptr + 4];
+        }
+        case Opcode.SetChannelMute: {
+            return [{ opcode: Opcode.SetChannelMute, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetChannelSolo: {
+            return [{ opcode: Opcode.SetChannelSolo, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetFrameOffset: {
+            return [{ opcode: Opcode.SetFrameOffset, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.GetParameter: {
+            return [{ opcode: Opcode.GetParameter, operand: view.getUint8(ptr + 0) }, ptr + 1];
+        }
+        case Opcode.SetCurrentPattern: {
+            return [{ opcode: Opcode.SetCurrentPattern, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetNextPattern: {
+            return [{ opcode: Opcode.SetNextPattern, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.QueueSongEntry: {
+            return [{ opcode: Opcode.QueueSongEntry, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.ResetTransport: {
+            return [{ opcode: Opcode.ResetTransport, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetClockSource: {
+            return [{ opcode: Opcode.SetClockSource, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetMidiClockOutput: {
+            return [{ opcode: Opcode.SetMidiClockOutput, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetCutoff: {
+            return [{ opcode: Opcode.SetCutoff, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetResonance: {
+            return [{ opcode: Opcode.SetResonance, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetEnvMod: {
+            return [{ opcode: Opcode.SetEnvMod, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetDecay: {
+            return [{ opcode: Opcode.SetDecay, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetTempo: {
+            return [{ opcode: Opcode.SetTempo, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetTuning: {
+            return [{ opcode: Opcode.SetTuning, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetAccent: {
+            return [{ opcode: Opcode.SetAccent, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetDistortionThreshold: {
+            return [{ opcode: Opcode.SetDistortionThreshold, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetDistortionShape: {
+            return [{ opcode: Opcode.SetDistortionShape, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetDelaySend: {
+            return [{ opcode: Opcode.SetDelaySend, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetDelayFeedback: {
+            return [{ opcode: Opcode.SetDelayFeedback, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetPan: {
+            return [{ opcode: Opcode.SetPan, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetStereoWidth: {
+            return [{ opcode: Opcode.SetStereoWidth, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetDelayPingPong: {
+            return [{ opcode: Opcode.SetDelayPingPong, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetDrumVolume: {
+            return [{ opcode: Opcode.SetDrumVolume, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetKickTune: {
+            return [{ opcode: Opcode.SetKickTune, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetKickDecay: {
+            return [{ opcode: Opcode.SetKickDecay, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetSnareSnappy: {
+            return [{ opcode: Opcode.SetSnareSnappy, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetHatDecay: {
+            return [{ opcode: Opcode.SetHatDecay, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.BootstrapFinished: {
+            return [{ opcode: Opcode.BootstrapFinished }, ptr + 0];
+        }
+        case Opcode.SetSequencerStep: {
+            return [{ opcode: Opcode.SetSequencerStep, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.DecodeError: {
+            return [{ opcode: Opcode.DecodeError, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetDroppedMessageCount: {
+            return [{ opcode: Opcode.SetDroppedMessageCount, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetActivePattern: {
+            return [{ opcode: Opcode.SetActivePattern, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetTransportState: {
+            return [{ opcode: Opcode.SetTransportState, operand: view.getUint32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetPeakLeft: {
+            return [{ opcode: Opcode.SetPeakLeft, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetPeakRight: {
+            return [{ opcode: Opcode.SetPeakRight, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetRmsLeft: {
+            return [{ opcode: Opcode.SetRmsLeft, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetRmsRight: {
+            return [{ opcode: Opcode.SetRmsRight, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetEffectiveCutoff: {
+            return [{ opcode: Opcode.SetEffectiveCutoff, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetAmplitudeEnvelope: {
+            return [{ opcode: Opcode.SetAmplitudeEnvelope, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetFilterEnvelope: {
+            return [{ opcode: Opcode.SetFilterEnvelope, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetChannelGain: {
+            return [{ opcode: Opcode.SetChannelGain, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetMasterGain: {
+            return [{ opcode: Opcode.SetMasterGain, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetMasterLowShelf: {
+            return [{ opcode: Opcode.SetMasterLowShelf, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetMasterHighShelf: {
+            return [{ opcode: Opcode.SetMasterHighShelf, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetTelemetryRate: {
+            return [{ opcode: Opcode.SetTelemetryRate, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetSmoothingTime: {
+            return [{ opcode: Opcode.SetSmoothingTime, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetSwing: {
+            return [{ opcode: Opcode.SetSwing, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetChannelPan: {
+            return [{ opcode: Opcode.SetChannelPan, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetMasterWidth: {
+            return [{ opcode: Opcode.SetMasterWidth, operand: view.getFloat32(ptr + 0, true) }, ptr + 4];
+        }
+        case Opcode.SetPattern: {
+            const length = view.getUint32(ptr, true);
+            return [{ opcode: Opcode.SetPattern, operand: new Uint8Array(view.buffer, view.byteOffset + ptr + 4, length).slice() }, ptr + 4 + length];
+        }
+        case Opcode.SetDrumPattern: {
+            const length = view.getUint32(ptr, true);
+            return [{ opcode: Opcode.SetDrumPattern, operand: new Uint8Array(view.buffer, view.byteOffset + ptr + 4, length).slice() }, ptr + 4 + length];
+        }
+        case Opcode.RestoreSnapshot: {
+            const length = view.getUint32(ptr, true);
+            return [{ opcode: Opcode.RestoreSnapshot, operand: new Uint8Array(view.buffer, view.byteOffset + ptr + 4, length).slice() }, ptr + 4 + length];
+        }
+        case Opcode.MidiInput: {
+            const length = view.getUint32(ptr, true);
+            return [{ opcode: Opcode.MidiInput, operand: new Uint8Array(view.buffer, view.byteOffset + ptr + 4, length).slice() }, ptr + 4 + length];
+        }
+        case Opcode.SetSong: {
+            const length = view.getUint32(ptr, true);
+            return [{ opcode: Opcode.SetSong, operand: new Uint8Array(view.buffer, view.byteOffset + ptr + 4, length).slice() }, ptr + 4 + length];
+        }
+        case Opcode.SetGroove: {
+            const length = view.getUint32(ptr, true);
+            return [{ opcode: Opcode.SetGroove, operand: new Uint8Array(view.buffer, view.byteOffset + ptr + 4, length).slice() }, ptr + 4 + length];
+        }
+        case Opcode.ParameterInfo: {
+            const length = view.getUint32(ptr, true);
+            return [{ opcode: Opcode.ParameterInfo, operand: new Uint8Array(view.buffer, view.byteOffset + ptr + 4, length).slice() }, ptr + 4 + length];
+        }
+        case Opcode.Snapshot: {
+            const length = view.getUint32(ptr, true);
+            return [{ opcode: Opcode.Snapshot, operand: new Uint8Array(view.buffer, view.byteOffset + ptr + 4, length).slice() }, ptr + 4 + length];
+        }
+        case Opcode.MidiOutput: {
+            const length = view.getUint32(ptr, true);
+            return [{ opcode: Opcode.MidiOutput, operand: new Uint8Array(view.buffer, view.byteOffset + ptr + 4, length).slice() }, ptr + 4 + length];
+        }
+        case Opcode.RampParameter: {
+            return [{ opcode: Opcode.RampParameter, operands: [view.getUint8(ptr + 0), view.getUint8(ptr + 1), view.getFloat32(ptr + 2, true), view.getFloat32(ptr + 6, true)] }, ptr + 10];
+        }
+        case Opcode.SetParameter: {
+            return [{ opcode: Opcode.SetParameter, operands: [view.getUint8(ptr + 0), view.getFloat32(ptr + 1, true)] }, ptr + 5];
+        }
+        case Opcode.ParameterValue: {
+            return [{ opcode: Opcode.ParameterValue, operands: [view.getUint8(ptr + 0), view.getFloat32(ptr + 1, true)] }, ptr + 5];
+        }
+        case Opcode.SetMidiMapping: {
+            return [{ opcode: Opcode.SetMidiMapping, operands: [view.getUint8(ptr + 0), view.getUint8(ptr + 1)] }, ptr + 2];
+        }
+        case Opcode.SetPatternLength: {
+            return [{ opcode: Opcode.SetPatternLength, operands: [view.getUint8(ptr + 0), view.getUint8(ptr + 1)] }, ptr + 2];
+        }
+        case Opcode.SetPatternResolution: {
+            return [{ opcode: Opcode.SetPatternResolution, operands: [view.getUint8(ptr + 0), view.getUint8(ptr + 1)] }, ptr + 2];
+        }
+        case Opcode.SetHostTransport: {
+            return [{ opcode: Opcode.SetHostTransport, operands: [view.getFloat32(ptr + 0, true), view.getFloat32(ptr + 4, true)] }, ptr + 8];
+        }
+        default:
+            return [undefined, view.byteLength];
+    }
+}
@@ -0,0 +1,29 @@
+const ParameterId = {
+    Tempo: 0,
+    Cutoff: 1,
+    Resonance: 2,
+    EnvMod: 3,
+    Decay: 4,
+    Accent: 5,
+    DistortionThreshold: 6,
+    DistortionShape: 7,
+    ChannelGain: 8,
+    Pan: 9,
+    DelaySend: 10,
+    DelayFeedback: 11,
+    DelayPingPong: 12,
+    StereoWidth: 13,
+    MasterGain: 14,
+    MasterLowShelf: 15,
+    MasterHighShelf: 16,
+    DrumVolume: 17,
+    KickTune: 18,
+    KickDecay: 19,
+    SnareSnappy: 20,
+    HatDecay: 21,
+    Swing: 22,
+    ChannelPan: 23,
+    MasterWidth: 24
+} as const;
+
+export default ParameterId;
@@ -0,0 +1,6 @@
+const ParameterTaper = {
+    Linear: 0,
+    Logarithmic: 1
+} as const;
+
+export default ParameterTaper;
@@ -0,0 +1,9 @@
+const ParameterUnit = {
+    Unitless: 0,
+    Hertz: 1,
+    Milliseconds: 2,
+    Decibels: 3,
+    BeatsPerMinute: 4
+} as const;
+
+export default ParameterUnit;
@@ -58,6 +58,13 @@ function StepColumn({
         }));
     }, [index, step, dispatch]);
 
+    const handleTieToggle = useCallback(() => {
+        dispatch(setCurrentPatternData(index, {
+            ...step,
+            hasTie: !step.hasTie
+        }));
+    }, [index, step, dispatch]);
+
     const handleAccentToggle = useCallback(() => {
         dispatch(setCurrentPatternData(index, {
             ...step,
@@ -91,6 +98,7 @@ function StepColumn({
                 <div className="flex-column flex-1">
                     <div className={classNames("modifier", step.hasSlide && "active")} onClick={handleSlideToggle}>slide</div>
                     <div className={classNames("modifier", step.hasAccent && "active")} onClick={handleAccentToggle}>accent</div>
+                    <div className={classNames("modifier", step.hasTie && "active")} onClick={handleTieToggle}>tie</div>
                 </div>
 
                 <div className="flex-column flex-1">
@@ -0,0 +1,10 @@
+const StepResolution = {
+    Eighth: 0,
+    Sixteenth: 1,
+    ThirtySecond: 2,
+    EighthTriplet: 3,
+    SixteenthTriplet: 4,
+    ThirtySecondTriplet: 5
+} as const;
+
+export default StepResolution;
@@ -7,29 +7,89 @@ import {
 
 import {
     Pattern,
+    SongEntry,
     Step
 } from "../types";
 
+import GateLength from "../GateLength";
 import Opcode from "../Opcode";
 
+// Time over which the kernel ramps parameter changes to avoid zipper noise, in ms
+const smoothingTime = 20;
+
+function encodeStepFlags(step: Step): number {
+    return (+step.hasNote << 0) +
+        (+step.hasAccent << 1) +
+        (+step.hasSlide << 2) +
+        (+step.octaveUp << 3) +
+        (+step.octaveDown << 4) +
+        (+step.hasTie << 5) +
+        (step.gateLength << 6);
+}
+
+// Uploads a whole pattern at once: pattern index, step count and then pitch and flags per step
+function makeSetPatternInstruction(patternIndex: number, pattern: Pattern): Instruction {
+    const operand = new Uint8Array(2 + 2 * pattern.steps.length);
+
+    operand[0] = patternIndex;
+    operand[1] = pattern.steps.length;
+
+    pattern.steps.forEach((step, stepIndex) => {
+        operand[2 + 2 * stepIndex] = step.pitch;
+        operand[3 + 2 * stepIndex] = encodeStepFlags(step);
+    });
+
+    return {
+        opcode: Opcode.SetPattern,
+        operand
+    };
+}
+
+// The song starts at its first entry after the current pattern, an empty song turns song mode off
+function makeSetSongInstruction(song: SongEntry[]): Instruction {
+    const noEntry = 255;
+    const operand = new Uint8Array(4 + 3 * song.length);
+
+    operand.set([noEntry, 0, 0, 0]);
+
+    song.forEach((entry, entryIndex) => {
+        operand[4 + 3 * entryIndex] = entry.patternIndex;
+        operand[5 + 3 * entryIndex] = entry.repeatCount;
+        operand[6 + 3 * entryIndex] = entry.transpose & 0xff;
+    });
+
+    return {
+        opcode: Opcode.SetSong,
+        operand
+    };
+}
+
 function makeSetPatternDataInstruction(patternIndex: number, stepIndex: number, step: Step): Instruction {
     return {
         opcode: Opcode.SetPatternData,
-        operand: (patternIndex << 0) +
-            (stepIndex << 8) +
-            (step.pitch << 16) +
-            (+step.octaveDown << 28) +
-            (+step.octaveUp << 27) +
-            (+step.hasSlide << 26) +
-            (+step.hasAccent << 25) +
-            (+step.hasNote << 24)
+        operands: [patternIndex, stepIndex, step.pitch, encodeStepFlags(step)]
     };
 }
 
+// Reported periodically by the kernel, the voice values are for the first channel
+interface Telemetry {
+    peakLeft: number;
+    peakRight: number;
+    rmsLeft: number;
+    rmsRight: number;
+
+    effectiveCutoff: number;
+    amplitudeEnvelope: number;
+    filterEnvelope: number;
+}
+
 interface State {
     isInitialized: boolean;
     isRunning: boolean;
 
+    // Transport state reported by the kernel
+    isPlaying: boolean;
+
     waveformIndex: number;
     tuning: number;
     cutoff: number;
@@ -47,13 +107,21 @@ interface State {
 
     sequencerStep: number;
 
+    telemetry: Telemetry;
+
     patterns: Pattern[];
     currentPatternIndex: number;
+
+    song: SongEntry[];
+
+    // Reported by the kernel when a pattern starts, the song position is -1 outside of song mode
+    activePatternIndex: number;
+    songPosition: number;
 }
 
 function makeDemoPattern(): Pattern {
     function step(pitch: number, octaveUp: boolean, octaveDown: boolean, hasNote: boolean, hasSlide: boolean, hasAccent: boolean): Step {
-        return { pitch, octaveUp, octaveDown, hasNote, hasSlide, hasAccent };
+        return { pitch, octaveUp, octaveDown, hasNote, hasSlide, hasAccent, hasTie: false, gateLength: GateLength.Half };
     }
 
     return {
@@ -82,6 +150,8 @@ const initialState: State = {
     isInitialized: false,
     isRunning: false,
 
+    isPlaying: true,
+
     waveformIndex: 0,
     tuning: 0,
     cutoff: 2000.0,
@@ -99,10 +169,26 @@ const initialState: State = {
 
     sequencerStep: 0,
 
+    telemetry: {
+        peakLeft: 0,
+        peakRight: 0,
+        rmsLeft: 0,
+        rmsRight: 0,
+
+        effectiveCutoff: 0,
+        amplitudeEnvelope: 0,
+        filterEnvelope: 0
+    },
+
     patterns: [
         makeDemoPattern()
     ],
-    currentPatternIndex: 0
+    currentPatternIndex: 0,
+
+    song: [],
+
+    activePatternIndex: 0,
+    songPosition: -1
 };
 
 const slice = createSlice({
@@ -173,25 +259,94 @@ const slice = createSlice({
             state.delayLength = action.payload;
         },
 
+        setIsPlaying(state, action: PayloadAction<boolean>) {
+            state.isPlaying = action.payload;
+        },
+
         setSequencerStep(state, action: PayloadAction<number>) {
             state.sequencerStep = action.payload;
+        },
+
+        setTelemetry(state, action: PayloadAction<Partial<Telemetry>>) {
+            Object.assign(state.telemetry, action.payload);
+        },
+
+        setSong(state, action: PayloadAction<SongEntry[]>) {
+            state.song = action.payload;
+        },
+
+        setActivePattern(state, action: PayloadAction<{ patternIndex: number, songPosition: number }>) {
+            state.activePatternIndex = action.payload.patternIndex;
+            state.songPosition = action.payload.songPosition;
         }
     }
 });
 
-const handleInstruction = (dispatch: Parameters<Thunk>[0]) => (instruction: Instruction): void => {
-    switch (instruction.opcode) {
-        case Opcode.Nop:
-            break;
-        case Opcode.BootstrapFinished:
-            break;
-        case Opcode.SetSequencerStep:
-            dispatch(setSequencerStep(instruction.operand));
-            break;
-        case Opcode.SetCutoff:
-            break;
-    }
-}
+const handleInstruction = (dispatch: Parameters<Thunk>[0]) => {
+    // Voice telemetry is preceded by a SetChannel instruction
+    let channel = 0;
+
+    return (instruction: Instruction): void => {
+        switch (instruction.opcode) {
+            case Opcode.Nop:
+                break;
+            case Opcode.BootstrapFinished:
+                break;
+            case Opcode.SetSequencerStep:
+                // Note: the second byte of the operand contains the channel index
+                if ((instruction.operand >> 8) === 0) {
+                    dispatch(setSequencerStep(instruction.operand & 0xff));
+                }
+                break;
+            case Opcode.SetTransportState:
+                dispatch(slice.actions.setIsPlaying(instruction.operand !== 0));
+                break;
+            case Opcode.SetActivePattern:
+                // Pattern, channel, song entry and repeat, one byte each
+                if (((instruction.operand >> 8) & 0xff) === 0) {
+                    const songEntry = (instruction.operand >> 16) & 0xff;
+
+                    dispatch(slice.actions.setActivePattern({
+                        patternIndex: instruction.operand & 0xff,
+                        songPosition: songEntry === 255 ? -1 : songEntry
+                    }));
+                }
+                break;
+            case Opcode.SetCutoff:
+                break;
+            case Opcode.SetChannel:
+                channel = instruction.operand;
+                break;
+            case Opcode.SetPeakLeft:
+                dispatch(setTelemetry({ peakLeft: instruction.operand }));
+                break;
+            case Opcode.SetPeakRight:
+                dispatch(setTelemetry({ peakRight: instruction.operand }));
+                break;
+            case Opcode.SetRmsLeft:
+                dispatch(setTelemetry({ rmsLeft: instruction.operand }));
+                break;
+            case Opcode.SetRmsRight:
+                dispatch(setTelemetry({ rmsRight: instruction.operand }));
+                break;
+            case Opcode.SetEffectiveCutoff:
+                if (channel === 0) {
+                    dispatch(setTelemetry({ effectiveCutoff: instruction.operand }));
+                }
+                break;
+            case Opcode.SetAmplitudeEnvelope:
+                if (channel === 0) {
+                    dispatch(setTelemetry({ amplitudeEnvelope: instruction.operand }));
+                }
+                break;
+            case Opcode.SetFilterEnvelope:
+                if (channel === 0) {
+                    dispatch(setTelemetry({ filterEnvelope: instruction.operand }));
+                }
+                break;
+        }
+    };
+};
 
 export const bootstrap = (): Thunk => async (dispatch, getState, engine) => {
     try {
@@ -205,10 +360,8 @@ export const bootstrap = (): Thunk => async (dispatch, getState, engine) => {
     // Emit initial state instructions
     const r303 = getState().r303;
 
-    const instructions: Instruction[] = r303.patterns.flatMap(
-        (pattern, patternIndex) => pattern.steps.map(
-            (step, stepIndex) => makeSetPatternDataInstruction(patternIndex, stepIndex, step)
-        )
+    const instructions: Instruction[] = r303.patterns.map(
+        (pattern, patternIndex) => makeSetPatternInstruction(patternIndex, pattern)
     );
 
     instructions.push({ opcode: Opcode.SetWaveformIndex, operand: r303.waveformIndex });
@@ -224,8 +377,14 @@ export const bootstrap = (): Thunk => async (dispatch, getState, engine) => {
     instructions.push({ opcode: Opcode.SetDelayFeedback, operand: r303.delayFeedback });
     instructions.push({ opcode: Opcode.SetDelayLength, operand: r303.delayLength });
 
+    // The initial state is applied right away, later changes from the dials are smoothed
+    instructions.push({ opcode: Opcode.SetSmoothingTime, operand: smoothingTime });
+
     engine.sendInstructions(instructions);
 
+    // MIDI input is optional, the sequencer works without it
+    engine.enableMidiInput();
+
     dispatch(slice.actions.setIsInitialized(true));
 };
 
@@ -234,6 +393,23 @@ export const start = (): Thunk => (dispatch, getState, engine) => {
     dispatch(setIsRunning(true));
 };
 
+// Transport control, the kernel reports the new state back
+export const play = (): Thunk => (dispatch, getState, engine) => {
+    engine.sendInstruction({ opcode: Opcode.StartTransport });
+};
+
+export const stop = (): Thunk => (dispatch, getState, engine) => {
+    engine.sendInstruction({ opcode: Opcode.StopTransport });
+};
+
+export const continuePlaying = (): Thunk => (dispatch, getState, engine) => {
+    engine.sendInstruction({ opcode: Opcode.ContinueTransport });
+};
+
+export const resetToStep = (stepIndex: number): Thunk => (dispatch, getState, engine) => {
+    engine.sendInstruction({ opcode: Opcode.ResetTransport, operand: stepIndex });
+};
+
 export const setCurrentPatternData = (stepIndex: number, step: Step): Thunk => (dispatch, getState, engine) => {
     const patternIndex = getState().r303.currentPatternIndex;
     engine.sendInstruction(makeSetPatternDataInstruction(patternIndex, stepIndex, step));
@@ -247,6 +423,20 @@ export const setPatternData = (patternIndex: number, stepIndex: number, step: St
     dispatch(slice.actions.setPatternData({ patternIndex, stepIndex, step }));
 }
 
+export const setSong = (song: SongEntry[]): Thunk => (dispatch, getState, engine) => {
+    engine.sendInstruction(makeSetSongInstruction(song));
+
+    dispatch(slice.actions.setSong(song));
+};
+
+// Jumps to a song entry at the end of the current pattern
+export const queueSongEntry = (entryIndex: number): Thunk => (dispatch, getState, engine) => {
+    engine.sendInstruction({
+        opcode: Opcode.QueueSongEntry,
+        operand: entryIndex
+    });
+};
+
 export const setWaveformIndex = (index: number): Thunk => (dispatch, getState, engine) => {
     engine.sendInstruction({
         opcode: Opcode.SetWaveformIndex,
@@ -359,7 +549,8 @@ export const {
     setIsInitialized,
     setIsRunning,
 
-    setSequencerStep
+    setSequencerStep,
+    setTelemetry
 } = slice.actions;
 
 export default slice.reducer;
@@ -1,3 +1,5 @@
+import GateLength from "./GateLength";
+
 export interface Step {
     pitch: number;
     octaveUp: boolean;
@@ -5,8 +7,19 @@ export interface Step {
     hasNote: boolean;
     hasSlide: boolean;
     hasAccent: boolean;
+
+    // Holds the note of the step before, a tie after a rest is a rest
+    hasTie: boolean;
+    gateLength: typeof GateLength[keyof typeof GateLength];
 }
 
 export interface Pattern {
     steps: Step[];
 }
+
+// A pattern in the song chain, played repeatCount times and transposed by a number of semitones
+export interface SongEntry {
+    patternIndex: number;
+    repeatCount: number;
+    transpose: number;
+}
@@ -1,4 +1,4 @@
-import Opcode from "./Opcode";
+import Opcode, { decodeInstruction } from "./Opcode";
 import RingBuffer from "./RingBuffer";
 
 // TypeScript doesn't understand audio worklets, so we'll have to provide type
@@ -15,8 +15,18 @@ declare const AudioWorkletProcessor: {
     new (options?: AudioWorkletNodeOptions): AudioWorkletProcessor;
 };
 
+declare const sampleRate: number;
+declare const currentFrame: number;
+
 declare function registerProcessor(name: string, processorCtor: (new (options?: AudioWorkletNodeOptions) => AudioWorkletProcessor) & { parameterDescriptors?: AudioParamDescriptor[]; }): undefined;
 
+// Note: Web Audio API nodes use a fixed 128-sample buffer size per channel
+const bufferSize = 128;
+
+// Sizes of the VM program buffers
+const programBufferSize = 32768;
+const outboundBufferSize = 16384;
+
 class Bridge {
     private wasm: WebAssembly.Instance;
     private buffer: Uint8Array;
@@ -46,12 +56,16 @@ class Bridge {
 //interface Kernel extends Omit<WebAssembly.Instance, "exports"> {
 interface Kernel extends WebAssembly.Instance {
     exports: {
-        initialize(): void;
-        process(programSize: number): number;
+        initialize(sampleRate: number): void;
+        process(programSize: number, frameCount: number): number;
+        set_max_block_size(maxBlockSize: number): void;
+        set_voice_count(voiceCount: number): void;
 
         get_left_pointer(): number;
         get_right_pointer(): number;
         get_program_pointer(): number;
+        get_outbound_pointer(): number;
+        get_dropped_message_count(): number;
 
         handle_message(): void;
 
@@ -70,6 +84,7 @@ class Processor extends AudioWorkletProcessor {
     private rightBuffer: Float32Array;
 
     private programBuffer: Uint8Array;
+    private outboundBuffer: Uint8Array;
 
     constructor(options?: AudioWorkletNodeOptions) {
         super(options);
@@ -94,21 +109,20 @@ class Processor extends AudioWorkletProcessor {
 
                 //this._wasm.exports.memory.grow(250)
 
-                this.wasm.exports.initialize();
-
-                const bufferSize = 128; // Note: Web Audio API nodes use a fixed 128-sample buffer size per channel
+                this.wasm.exports.initialize(sampleRate);
+                this.wasm.exports.set_max_block_size(bufferSize);
 
                 this.leftBuffer = new Float32Array(this.wasm.exports.memory.buffer, this.wasm.exports.get_left_pointer(), bufferSize);
                 this.rightBuffer = new Float32Array(this.wasm.exports.memory.buffer, this.wasm.exports.get_right_pointer(), bufferSize);
 
-                this.programBuffer = new Uint8Array(this.wasm.exports.memory.buffer, this.wasm.exports.get_program_pointer(), 32768);
+                this.programBuffer = new Uint8Array(this.wasm.exports.memory.buffer, this.wasm.exports.get_program_pointer(), programBufferSize);
+                this.outboundBuffer = new Uint8Array(this.wasm.exports.memory.buffer, this.wasm.exports.get_outbound_pointer(), outboundBufferSize);
 
                 this.sendBuffer = new RingBuffer(event.data.sendStorage);
                 this.receiveBuffer = new RingBuffer(event.data.receiveStorage);
 
                 // Send initialization completed message
-                this.programBuffer[0] = Opcode.BootstrapFinished;
-                this.sendBuffer.write(this.programBuffer, 1);
+                this.sendBuffer.write(new Uint8Array([Opcode.BootstrapFinished]), 1);
             });
         } else {
             // TODO: Pass message to wasm
@@ -117,22 +131,47 @@ class Processor extends AudioWorkletProcessor {
         }
     }
 
+    // The frontend precedes its instructions with the frame they were sent at. They take effect one
+    // block later at the same point in the block, so that their timing does not depend on when
+    // the worklet picks them up. Instructions that are late take effect at the start of the block.
+    private stampFrameOffsets(programSize: number): void {
+        const view = new DataView(this.programBuffer.buffer, this.programBuffer.byteOffset, programSize);
+        let ptr = 0;
+
+        while (ptr < programSize) {
+            const [instruction, nextPtr] = decodeInstruction(view, ptr);
+
+            // The kernel reports unknown opcodes, the rest of the program cannot be decoded
+            if (instruction === undefined) {
+                break;
+            }
+
+            if (instruction.opcode === Opcode.SetFrameOffset) {
+                const offset = (instruction.operand + bufferSize - currentFrame) | 0;
+                view.setUint32(ptr + 1, Math.min(Math.max(offset, 0), bufferSize), true);
+            }
+
+            ptr = nextPtr;
+        }
+    }
+
     process(inputs: Float32Array[][], outputs: Float32Array[][], parameters: Record<string, Float32Array>): boolean {
         if (!this.wasm) {
             return true;
         }
 
         // Send ringbuffer data to wasm
-        let programSize = this.receiveBuffer.read(this.programBuffer, 32768);
+        const programSize = this.receiveBuffer.read(this.programBuffer, programBufferSize);
+        this.stampFrameOffsets(programSize);
 
         // Process inside wasm
-        programSize = this.wasm.exports.process(programSize);
+        const outboundSize = this.wasm.exports.process(programSize, bufferSize);
         outputs[0][0].set(this.leftBuffer);
         outputs[0][1].set(this.rightBuffer);
 
-        // Send processed program buffer back to frontend
-        if (programSize > 0) {
-            this.sendBuffer.write(this.programBuffer, programSize);
+        // Send outbound messages to frontend
+        if (outboundSize > 0) {
+            this.sendBuffer.write(this.outboundBuffer, outboundSize);
         }
 
         return true;